@@ -0,0 +1,46 @@
+//! Compares the route trie with a linear scan over route patterns.
+//!
+//! Run with `cargo bench --bench router`.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use http_server_starter_rust::{pattern::RoutePattern, trie::RouteTrie};
+
+/// Generates route patterns shaped like a generated REST API.
+fn patterns(resources: usize) -> Vec<RoutePattern> {
+    (0..resources)
+        .flat_map(|i| {
+            [
+                format!("/api/v1/resource{}", i),
+                format!("/api/v1/resource{}/:id", i),
+                format!("/api/v1/resource{}/:id/items/:item", i),
+                format!("/api/v1/resource{}/:id/files/*path", i),
+            ]
+        })
+        .map(|pattern| RoutePattern::parse(&pattern).unwrap())
+        .collect()
+}
+
+fn bench_router(c: &mut Criterion) {
+    let mut group = c.benchmark_group("route_matching");
+    for resources in [10, 100, 500] {
+        let patterns = patterns(resources);
+        let mut trie = RouteTrie::new();
+        for (index, pattern) in patterns.iter().enumerate() {
+            trie.insert(pattern, index, |_, _| true).unwrap();
+        }
+        // The last registered resource is the worst case for the linear scan.
+        let path = format!("/api/v1/resource{}/42/items/7", resources - 1);
+        let routes = patterns.len();
+
+        group.bench_with_input(BenchmarkId::new("linear", routes), &path, |b, path| {
+            b.iter(|| patterns.iter().find_map(|pattern| pattern.matches(black_box(path))))
+        });
+        group.bench_with_input(BenchmarkId::new("trie", routes), &path, |b, path| {
+            b.iter(|| trie.lookup(black_box(path)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_router);
+criterion_main!(benches);
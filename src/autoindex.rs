@@ -0,0 +1,328 @@
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use eyre::{Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use tokio::task::spawn_blocking;
+
+use crate::{
+    extract::{FromRequest, Query},
+    files::{resolve_path, SymlinkPolicy},
+    http::{
+        HttpRequest, HttpResponse, ResponseHeaders, StatusCode, ACCEPT, CONTENT_TYPE,
+        CT_APPLICATION_JSON,
+    },
+    middleware::prefers_json,
+};
+
+const CT_TEXT_HTML_UTF8: &str = "text/html; charset=utf-8";
+
+/// The directories of the public directory that get listings, shared with handlers as router
+/// state.
+///
+/// Listings are off unless a directory, or one of its parents, is enabled.
+#[derive(Debug, Clone, Default)]
+pub struct AutoIndex {
+    dirs: Vec<PathBuf>,
+}
+
+impl AutoIndex {
+    /// Creates an `AutoIndex` with listings off everywhere.
+    pub fn new() -> Self { Self::default() }
+
+    /// Enables listings for a directory and its subdirectories.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The directory relative to the public directory, or `""` for all of it.
+    pub fn enable(&mut self, dir: &str) -> &mut Self {
+        self.dirs.push(normalize(Path::new(dir)));
+        self
+    }
+
+    /// Checks whether listings are enabled for a directory relative to the public directory.
+    pub fn is_enabled(&self, dir: &Path) -> bool {
+        let dir = normalize(dir);
+        self.dirs.iter().any(|enabled| dir.starts_with(enabled))
+    }
+}
+
+/// The column a listing is sorted by.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortKey {
+    #[default]
+    Name,
+    Size,
+    Modified,
+}
+
+/// The direction a listing is sorted in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// The query string of a listing, e.g. `?sort=size&order=desc`.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct ListingQuery {
+    #[serde(default)]
+    pub sort:  SortKey,
+    #[serde(default)]
+    pub order: SortOrder,
+}
+
+/// An entry of a directory listing.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DirEntry {
+    pub name:     String,
+    pub is_dir:   bool,
+    /// The size in bytes, `None` for directories.
+    pub size:     Option<u64>,
+    /// The modification time in seconds since the Unix epoch.
+    pub modified: Option<u64>,
+}
+
+/// Reads the entries of a directory for a listing.
+///
+/// Hidden entries, whose name starts with a dot, are left out, as are entries that
+/// [`resolve_path`] refuses under `symlinks`, e.g. links out of the public directory.
+///
+/// # Arguments
+///
+/// * `root` - The public directory.
+/// * `dir` - The directory to list, relative to `root`.
+/// * `symlinks` - The policy for symbolic links.
+pub fn read_entries(root: &Path, dir: &Path, symlinks: SymlinkPolicy) -> Result<Vec<DirEntry>> {
+    let path = root.join(dir);
+    let mut entries = Vec::new();
+    for entry in
+        fs::read_dir(&path).wrap_err_with(|| format!("Failed to list {}", path.display()))?
+    {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        let relative = dir.join(&name);
+        let Ok(resolved) = resolve_path(root, &relative.to_string_lossy(), symlinks) else {
+            continue;
+        };
+        let Ok(metadata) = fs::metadata(resolved) else {
+            continue;
+        };
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|modified| modified.as_secs());
+        entries.push(DirEntry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: metadata.is_file().then_some(metadata.len()),
+            modified,
+        });
+    }
+    Ok(entries)
+}
+
+/// Sorts entries by the query's column, keeping directories before files.
+pub fn sort_entries(entries: &mut [DirEntry], query: ListingQuery) {
+    entries.sort_by(|a, b| {
+        let ordering = match query.sort {
+            SortKey::Name => a.name.cmp(&b.name),
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Modified => a.modified.cmp(&b.modified),
+        }
+        .then_with(|| a.name.cmp(&b.name));
+        let ordering = match query.order {
+            SortOrder::Asc => ordering,
+            SortOrder::Desc => ordering.reverse(),
+        };
+        b.is_dir.cmp(&a.is_dir).then(ordering)
+    });
+}
+
+/// Serves the listing of a directory of the public directory.
+///
+/// Listings are answered with 404 unless the directory is enabled in the [`AutoIndex`]
+/// registered as state. A directory requested without a trailing slash is redirected to it,
+/// so the relative links of the listing resolve. The listing is HTML, or JSON if the `Accept`
+/// header prefers it.
+///
+/// # Arguments
+///
+/// * `request` - The HTTP request.
+/// * `root` - The public directory.
+/// * `path` - The request path of the directory, relative to `root`.
+pub async fn serve_listing(request: &HttpRequest, root: &str, path: &str) -> Result<HttpResponse> {
+    let dir = normalize(Path::new(path));
+    let enabled = request.state.get::<AutoIndex>().is_some_and(|index| index.is_enabled(&dir));
+    if !enabled {
+        return Ok(HttpResponse::not_found());
+    }
+    let request_path = request.line.path.split('?').next().unwrap_or_default();
+    if !dir.as_os_str().is_empty() && !request_path.ends_with('/') {
+        let name = dir.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+        let location = format!("{}/", encode_segment(&name));
+        return Ok(HttpResponse::redirect(StatusCode::MOVED_PERMANENTLY, &location));
+    }
+    let Query(query) = Query::<ListingQuery>::from_request(request)?;
+    let symlinks = request.state.get::<SymlinkPolicy>().copied().unwrap_or_default();
+    let (root, listed) = (PathBuf::from(root), dir.clone());
+    let mut entries = spawn_blocking(move || read_entries(&root, &listed, symlinks))
+        .await
+        .wrap_err("Directory listing task failed")??;
+    sort_entries(&mut entries, query);
+
+    let accept = request.headers.get(ACCEPT).map(String::as_str).unwrap_or_default();
+    let mut headers = ResponseHeaders::new();
+    if prefers_json(accept) {
+        headers.insert(CONTENT_TYPE.to_string(), CT_APPLICATION_JSON.to_string());
+        return Ok(HttpResponse::new(StatusCode::OK, &serde_json::to_vec(&entries)?, headers));
+    }
+    let title = format!("/{}", dir.to_string_lossy());
+    let title = if title.ends_with('/') { title } else { format!("{}/", title) };
+    headers.insert(CONTENT_TYPE.to_string(), CT_TEXT_HTML_UTF8.to_string());
+    let html = render_html(&title, &entries, query);
+    Ok(HttpResponse::new(StatusCode::OK, html.as_bytes(), headers))
+}
+
+/// Renders a listing as an HTML table whose column headers sort it.
+fn render_html(title: &str, entries: &[DirEntry], query: ListingQuery) -> String {
+    let title = escape_html(title);
+    let column = |key: SortKey, label: &str| {
+        let order = match (query.sort == key, query.order) {
+            (true, SortOrder::Asc) => "desc",
+            _ => "asc",
+        };
+        let key = match key {
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Modified => "modified",
+        };
+        format!("<th><a href=\"?sort={}&amp;order={}\">{}</a></th>", key, order, label)
+    };
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of \
+         {0}</title></head>\n<body>\n<h1>Index of {0}</h1>\n<table>\n<tr>{1}{2}{3}</tr>\n",
+        title,
+        column(SortKey::Name, "Name"),
+        column(SortKey::Size, "Size"),
+        column(SortKey::Modified, "Modified")
+    );
+    if title != "/" {
+        html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in entries {
+        let suffix = if entry.is_dir { "/" } else { "" };
+        let size = entry.size.map_or("-".to_string(), |size| size.to_string());
+        let modified = entry
+            .modified
+            .map(|secs| httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(secs)))
+            .unwrap_or_default();
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            encode_segment(&entry.name),
+            suffix,
+            escape_html(&entry.name),
+            suffix,
+            size,
+            modified
+        ));
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+/// Keeps only the named components of a relative path, e.g. `docs/api` for `./docs/api/`.
+fn normalize(path: &Path) -> PathBuf {
+    path.components().filter(|component| matches!(component, Component::Normal(_))).collect()
+}
+
+/// Escapes the characters HTML gives a meaning to.
+pub(crate) fn escape_html(text: &str) -> String {
+    text.chars().fold(String::with_capacity(text.len()), |mut escaped, c| {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+        escaped
+    })
+}
+
+/// Percent-encodes a path segment, keeping only unreserved characters.
+pub(crate) fn encode_segment(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' =>
+                (b as char).to_string(),
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(name: &str, size: Option<u64>, modified: u64) -> DirEntry {
+        DirEntry { name: name.to_string(), is_dir: size.is_none(), size, modified: Some(modified) }
+    }
+
+    #[test]
+    fn enabled_directories() {
+        let mut index = AutoIndex::new();
+        index.enable("/docs/").enable("media/photos");
+        assert!(index.is_enabled(Path::new("docs")));
+        assert!(index.is_enabled(Path::new("docs/api/")));
+        assert!(index.is_enabled(Path::new("./media/photos/2024")));
+        assert!(!index.is_enabled(Path::new("media")));
+        assert!(!index.is_enabled(Path::new("docs-old")));
+        assert!(!index.is_enabled(Path::new("")));
+        assert!(AutoIndex::new().enable("").is_enabled(Path::new("any/dir")));
+    }
+
+    #[test]
+    fn sorting() {
+        let mut entries = vec![
+            entry("b.txt", Some(1), 30),
+            entry("z", None, 10),
+            entry("a.txt", Some(5), 20),
+            entry("c.txt", Some(3), 10),
+        ];
+        let names =
+            |entries: &[DirEntry]| entries.iter().map(|e| e.name.clone()).collect::<Vec<_>>();
+        sort_entries(&mut entries, ListingQuery::default());
+        assert_eq!(names(&entries), ["z", "a.txt", "b.txt", "c.txt"]);
+        sort_entries(&mut entries, ListingQuery { sort: SortKey::Size, order: SortOrder::Desc });
+        assert_eq!(names(&entries), ["z", "a.txt", "c.txt", "b.txt"]);
+        sort_entries(&mut entries, ListingQuery {
+            sort:  SortKey::Modified,
+            order: SortOrder::Asc,
+        });
+        assert_eq!(names(&entries), ["z", "c.txt", "a.txt", "b.txt"]);
+    }
+
+    #[test]
+    fn html_escaping() {
+        let entries = [entry("<b>&\"x\" y", Some(1), 0)];
+        let html = render_html("/", &entries, ListingQuery::default());
+        assert!(
+            html.contains("<a href=\"%3Cb%3E%26%22x%22%20y\">&lt;b&gt;&amp;&quot;x&quot; y</a>")
+        );
+        assert!(html.contains("<a href=\"?sort=name&amp;order=desc\">Name</a>"));
+        assert!(html.contains("<a href=\"?sort=size&amp;order=asc\">Size</a>"));
+        assert!(!html.contains("../"));
+    }
+}
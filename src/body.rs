@@ -0,0 +1,186 @@
+use std::fmt;
+
+use bytes::{Bytes, BytesMut};
+use eyre::Result;
+use futures::Stream;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    sync::oneshot,
+};
+
+use crate::error::{HttpError, ServerError};
+
+/// The connection a body is read from.
+pub type BodyReader = Box<dyn AsyncRead + Send + Sync + Unpin>;
+
+/// The most bytes read from the connection at once, which bounds the memory a streamed body
+/// takes.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The largest body buffered into [`HttpRequest::body`](crate::http::HttpRequest::body) for
+/// routes that do not stream it; larger ones are refused with 413 Content Too Large.
+pub const MAX_BUFFERED_BODY: u64 = 16 * 1024 * 1024;
+
+/// A request body still to be read from the connection, in chunks of at most [`CHUNK_SIZE`]
+/// bytes.
+///
+/// A body read to the end hands the connection back to the server for the next request; one
+/// dropped before that makes the server close the connection after the response.
+pub struct BodyStream {
+    prefix:    Option<Bytes>,
+    reader:    Option<BodyReader>,
+    len:       u64,
+    remaining: u64,
+    done:      Option<oneshot::Sender<Option<BodyReader>>>,
+}
+
+impl BodyStream {
+    /// Creates a `BodyStream`.
+    ///
+    /// # Arguments
+    ///
+    /// * `prefix` - The start of the body, already read along with the request head.
+    /// * `reader` - The connection the rest of the body is read from.
+    /// * `len` - The length of the whole body, from `Content-Length`.
+    pub fn new(prefix: Bytes, reader: BodyReader, len: u64) -> Self {
+        let remaining = len.saturating_sub(prefix.len() as u64);
+        let prefix = (!prefix.is_empty()).then_some(prefix);
+        Self { prefix, reader: Some(reader), len, remaining, done: None }
+    }
+
+    /// Sends the connection through `done` once the body is dropped: the reader if the body was
+    /// read to the end, or `None` if bytes of it are left on the connection.
+    pub(crate) fn on_done(mut self, done: oneshot::Sender<Option<BodyReader>>) -> Self {
+        self.done = Some(done);
+        self
+    }
+
+    /// Returns the length of the whole body.
+    pub fn len(&self) -> u64 { self.len }
+
+    /// Returns `true` if the body has no bytes.
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+
+    /// Reads the next chunk of the body.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the chunk, `None` at the end of the body, or an `IncompleteBody`
+    /// error if the connection closes first.
+    pub async fn chunk(&mut self) -> Result<Option<Bytes>> {
+        if let Some(prefix) = self.prefix.take() {
+            return Ok(Some(prefix));
+        }
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        let incomplete = || ServerError::HttpError(HttpError::IncompleteBody);
+        let reader = self.reader.as_mut().ok_or_else(incomplete)?;
+        let mut chunk = BytesMut::with_capacity(CHUNK_SIZE.min(self.remaining as usize));
+        while chunk.len() < chunk.capacity() {
+            if reader.read_buf(&mut chunk).await? == 0 {
+                // The reader cannot be reused once the body is cut short.
+                self.reader = None;
+                return Err(incomplete().into());
+            }
+        }
+        self.remaining -= chunk.len() as u64;
+        Ok(Some(chunk.freeze()))
+    }
+
+    /// Reads the whole body into memory.
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - The largest body accepted.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the body, or a `BodyTooLarge` error if it is longer than `limit`,
+    /// in which case nothing is read.
+    pub async fn read_to_end(mut self, limit: u64) -> Result<Vec<u8>> {
+        if self.len > limit {
+            return Err(ServerError::HttpError(HttpError::BodyTooLarge).into());
+        }
+        let mut body = Vec::with_capacity(self.len as usize);
+        while let Some(chunk) = self.chunk().await? {
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body)
+    }
+
+    /// Turns the body into a [`Stream`] of chunks.
+    pub fn into_stream(self) -> impl Stream<Item = Result<Bytes>> + Send {
+        futures::stream::unfold(self, |mut body| async move {
+            body.chunk().await.transpose().map(|chunk| (chunk, body))
+        })
+    }
+}
+
+impl Drop for BodyStream {
+    fn drop(&mut self) {
+        if let Some(done) = self.done.take() {
+            let finished = self.prefix.is_none() && self.remaining == 0;
+            let _ = done.send(self.reader.take().filter(|_| finished));
+        }
+    }
+}
+
+impl fmt::Debug for BodyStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BodyStream")
+            .field("len", &self.len)
+            .field("remaining", &self.remaining)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use futures::StreamExt;
+
+    use super::*;
+
+    fn body(prefix: &'static [u8], rest: Vec<u8>, len: u64) -> BodyStream {
+        BodyStream::new(Bytes::from_static(prefix), Box::new(Cursor::new(rest)), len)
+    }
+
+    #[tokio::test]
+    async fn chunks_are_bounded() {
+        let rest = vec![7; CHUNK_SIZE * 2 + 10];
+        let mut stream = body(b"head", rest.clone(), 4 + rest.len() as u64);
+        let mut sizes = Vec::new();
+        while let Some(chunk) = stream.chunk().await.unwrap() {
+            sizes.push(chunk.len());
+        }
+        assert_eq!(sizes, [4, CHUNK_SIZE, CHUNK_SIZE, 10]);
+
+        let stream = body(b"ab", b"cdef".to_vec(), 5).into_stream();
+        let chunks = stream.map(|chunk| chunk.unwrap()).collect::<Vec<_>>().await;
+        assert_eq!(chunks, [Bytes::from_static(b"ab"), Bytes::from_static(b"cde")]);
+    }
+
+    #[tokio::test]
+    async fn incomplete_and_oversized_bodies() {
+        let mut stream = body(b"", b"abc".to_vec(), 10);
+        assert!(stream.chunk().await.is_err());
+        assert!(body(b"abc", Vec::new(), 3).read_to_end(2).await.is_err());
+        assert_eq!(body(b"a", b"bc".to_vec(), 3).read_to_end(3).await.unwrap(), b"abc");
+    }
+
+    #[tokio::test]
+    async fn connection_is_handed_back() {
+        let (done, returned) = oneshot::channel();
+        let stream = body(b"a", b"bc".to_vec(), 3).on_done(done);
+        stream.read_to_end(3).await.unwrap();
+        assert!(returned.await.unwrap().is_some());
+
+        let (done, returned) = oneshot::channel();
+        let mut stream = body(b"a", b"bc".to_vec(), 3).on_done(done);
+        stream.chunk().await.unwrap();
+        drop(stream);
+        assert!(returned.await.unwrap().is_none());
+    }
+}
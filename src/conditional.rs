@@ -0,0 +1,293 @@
+use std::{
+    fmt,
+    fs::Metadata,
+    os::unix::fs::MetadataExt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::http::{
+    HttpRequest, HttpResponse, ResponseHeaders, StatusCode, ETAG, IF_MATCH, IF_MODIFIED_SINCE,
+    IF_NONE_MATCH, IF_UNMODIFIED_SINCE, LAST_MODIFIED, METHOD_GET, METHOD_HEAD,
+};
+
+/// An entity tag, the opaque validator sent in `ETag` and compared in `If-Match` and
+/// `If-None-Match`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntityTag {
+    pub weak: bool,
+    pub tag:  String,
+}
+
+impl EntityTag {
+    /// Creates a strong tag, which changes whenever the content's bytes do.
+    pub fn strong(tag: &str) -> Self { Self { weak: false, tag: tag.to_string() } }
+
+    /// Creates a weak tag, which only claims the content is equivalent.
+    pub fn weak(tag: &str) -> Self { Self { weak: true, tag: tag.to_string() } }
+
+    /// Creates a strong tag from a hash of the content.
+    pub fn from_content(content: &[u8]) -> Self {
+        let hash = Sha256::digest(content);
+        let hex = hash[..16].iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+        Self::strong(&hex)
+    }
+
+    /// Creates a strong tag from a file's inode, size and modification time, like the tags
+    /// mainstream servers send.
+    ///
+    /// Uploads replace a file by renaming a new one over it, so every write changes the inode
+    /// as well as the nanosecond modification time.
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        Self::strong(&format!(
+            "{:x}-{:x}-{:x}.{:x}",
+            metadata.ino(),
+            metadata.len(),
+            modified.as_secs(),
+            modified.subsec_nanos()
+        ))
+    }
+
+    /// Parses a tag, e.g. `"abc"` or `W/"abc"`.
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let (weak, quoted) = match value.strip_prefix("W/") {
+            Some(quoted) => (true, quoted),
+            None => (false, value),
+        };
+        let tag = quoted.strip_prefix('"')?.strip_suffix('"')?;
+        (!tag.contains('"')).then(|| Self { weak, tag: tag.to_string() })
+    }
+
+    /// Compares two tags as `If-Match` does: both must be strong and equal.
+    pub fn strong_eq(&self, other: &Self) -> bool {
+        !self.weak && !other.weak && self.tag == other.tag
+    }
+
+    /// Compares two tags as `If-None-Match` does: equal, regardless of weakness.
+    pub fn weak_eq(&self, other: &Self) -> bool { self.tag == other.tag }
+}
+
+impl fmt::Display for EntityTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.weak {
+            true => write!(f, "W/\"{}\"", self.tag),
+            false => write!(f, "\"{}\"", self.tag),
+        }
+    }
+}
+
+/// How entity tags of served files are computed, shared with handlers as router state.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ETagMode {
+    /// Strong tags from the file's inode, size and modification time; no need to read the file.
+    #[default]
+    Metadata,
+    /// Strong tags from a hash of the file's content.
+    ContentHash,
+}
+
+/// The validators of the current representation of a resource.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Validators {
+    pub etag:          EntityTag,
+    pub last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    /// Returns the validators of a file.
+    ///
+    /// # Arguments
+    ///
+    /// * `metadata` - The file's metadata.
+    /// * `content` - The file's content, to compute the tag from; without it the tag comes from the
+    ///   metadata.
+    pub fn for_file(metadata: &Metadata, content: Option<&[u8]>) -> Self {
+        Self {
+            etag:          match content {
+                Some(content) => EntityTag::from_content(content),
+                None => EntityTag::from_metadata(metadata),
+            },
+            last_modified: metadata.modified().ok(),
+        }
+    }
+
+    /// Adds the `ETag` and `Last-Modified` headers to a response's headers.
+    pub fn insert_headers(&self, headers: &mut ResponseHeaders) {
+        headers.insert(ETAG.to_string(), self.etag.to_string());
+        if let Some(last_modified) = self.last_modified {
+            headers.insert(LAST_MODIFIED.to_string(), httpdate::fmt_http_date(last_modified));
+        }
+    }
+
+    /// Creates a 304 Not Modified response carrying the validators.
+    pub fn not_modified(&self) -> HttpResponse {
+        let mut headers = ResponseHeaders::new();
+        self.insert_headers(&mut headers);
+        HttpResponse::new(StatusCode::NOT_MODIFIED, b"", headers)
+    }
+}
+
+/// The outcome of evaluating a request's preconditions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Precondition {
+    /// The request goes ahead.
+    Proceed,
+    /// The client's cached copy is current; answer 304 Not Modified.
+    NotModified,
+    /// A precondition failed; answer 412 Precondition Failed.
+    Failed,
+}
+
+/// Evaluates `If-Match`, `If-Unmodified-Since`, `If-None-Match` and `If-Modified-Since` in the
+/// order of RFC 9110, section 13.2.2.
+///
+/// Malformed dates are ignored, as are the date conditions when a tag condition of the same
+/// kind is present.
+///
+/// # Arguments
+///
+/// * `request` - The HTTP request.
+/// * `current` - The validators of the resource, or `None` if it does not exist.
+///
+/// # Returns
+///
+/// `NotModified` only for `GET` and `HEAD`; other methods fail where those would not be
+/// modified.
+pub fn evaluate(request: &HttpRequest, current: Option<&Validators>) -> Precondition {
+    let header = |name| request.headers.get(name).map(String::as_str);
+    let date = |name| header(name).and_then(|value| httpdate::parse_http_date(value).ok());
+    let modified = current.and_then(|current| current.last_modified).map(truncate_to_secs);
+
+    if let Some(if_match) = header(IF_MATCH) {
+        if !matches_any(if_match, current, EntityTag::strong_eq) {
+            return Precondition::Failed;
+        }
+    } else if let (Some(since), Some(modified)) = (date(IF_UNMODIFIED_SINCE), modified) {
+        if modified > since {
+            return Precondition::Failed;
+        }
+    }
+
+    let safe = matches!(request.line.method.as_str(), METHOD_GET | METHOD_HEAD);
+    if let Some(if_none_match) = header(IF_NONE_MATCH) {
+        if matches_any(if_none_match, current, EntityTag::weak_eq) {
+            return match safe {
+                true => Precondition::NotModified,
+                false => Precondition::Failed,
+            };
+        }
+    } else if let (true, Some(since), Some(modified)) = (safe, date(IF_MODIFIED_SINCE), modified) {
+        if modified <= since {
+            return Precondition::NotModified;
+        }
+    }
+    Precondition::Proceed
+}
+
+/// Checks a tag list header against the current tag; `*` matches any existing resource.
+fn matches_any(
+    list: &str,
+    current: Option<&Validators>,
+    eq: fn(&EntityTag, &EntityTag) -> bool,
+) -> bool {
+    let Some(current) = current else {
+        return false;
+    };
+    if list.trim() == "*" {
+        return true;
+    }
+    list.split(',').filter_map(EntityTag::parse).any(|tag| eq(&tag, &current.etag))
+}
+
+/// Drops the sub-second part of a time, which HTTP dates cannot carry.
+fn truncate_to_secs(time: SystemTime) -> SystemTime {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    UNIX_EPOCH + Duration::from_secs(since_epoch.as_secs())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(method: &str, headers: &[(&str, &str)]) -> HttpRequest {
+        let headers = headers
+            .iter()
+            .map(|(key, value)| format!("{}: {}\r\n", key, value))
+            .collect::<String>();
+        HttpRequest::from_string(&format!("{} / HTTP/1.1\r\n{}\r\n", method, headers)).unwrap()
+    }
+
+    fn validators(etag: EntityTag) -> Validators {
+        Validators { etag, last_modified: Some(UNIX_EPOCH + Duration::from_millis(1_000_500)) }
+    }
+
+    #[test]
+    fn parse_and_compare() {
+        let strong = EntityTag::parse("\"abc\"").unwrap();
+        let weak = EntityTag::parse(" W/\"abc\" ").unwrap();
+        assert_eq!(weak, EntityTag::weak("abc"));
+        assert_eq!(weak.to_string(), "W/\"abc\"");
+        assert!(strong.strong_eq(&EntityTag::strong("abc")));
+        assert!(!strong.strong_eq(&weak));
+        assert!(strong.weak_eq(&weak));
+        assert!(EntityTag::parse("abc").is_none());
+        assert!(EntityTag::parse("\"a\"b\"").is_none());
+        assert_eq!(EntityTag::from_content(b"abc"), EntityTag::from_content(b"abc"));
+        assert_ne!(EntityTag::from_content(b"abc"), EntityTag::from_content(b"abd"));
+    }
+
+    #[test]
+    fn if_none_match() {
+        let current = validators(EntityTag::strong("v1"));
+        let check =
+            |method, value| evaluate(&request(method, &[(IF_NONE_MATCH, value)]), Some(&current));
+        assert_eq!(check("GET", "\"v0\", W/\"v1\""), Precondition::NotModified);
+        assert_eq!(check("GET", "*"), Precondition::NotModified);
+        assert_eq!(check("GET", "\"v0\""), Precondition::Proceed);
+        assert_eq!(check("POST", "*"), Precondition::Failed);
+        let missing = evaluate(&request("POST", &[(IF_NONE_MATCH, "*")]), None);
+        assert_eq!(missing, Precondition::Proceed);
+    }
+
+    #[test]
+    fn if_match() {
+        let current = validators(EntityTag::strong("v1"));
+        let check = |value| evaluate(&request("POST", &[(IF_MATCH, value)]), Some(&current));
+        assert_eq!(check("\"v1\""), Precondition::Proceed);
+        assert_eq!(check("*"), Precondition::Proceed);
+        assert_eq!(check("W/\"v1\""), Precondition::Failed);
+        assert_eq!(check("\"v2\""), Precondition::Failed);
+        assert_eq!(evaluate(&request("POST", &[(IF_MATCH, "*")]), None), Precondition::Failed);
+        let weak = validators(EntityTag::weak("v1"));
+        assert_eq!(
+            evaluate(&request("POST", &[(IF_MATCH, "W/\"v1\"")]), Some(&weak)),
+            Precondition::Failed
+        );
+    }
+
+    #[test]
+    fn dates() {
+        let current = validators(EntityTag::strong("v1"));
+        let at = |secs| httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(secs));
+        let check =
+            |method, name, secs| evaluate(&request(method, &[(name, &at(secs))]), Some(&current));
+        assert_eq!(check("GET", IF_MODIFIED_SINCE, 1000), Precondition::NotModified);
+        assert_eq!(check("GET", IF_MODIFIED_SINCE, 999), Precondition::Proceed);
+        assert_eq!(check("POST", IF_MODIFIED_SINCE, 1000), Precondition::Proceed);
+        assert_eq!(check("POST", IF_UNMODIFIED_SINCE, 1000), Precondition::Proceed);
+        assert_eq!(check("POST", IF_UNMODIFIED_SINCE, 999), Precondition::Failed);
+
+        let since = at(1000);
+        let both = request("GET", &[(IF_NONE_MATCH, "\"v0\""), (IF_MODIFIED_SINCE, &since)]);
+        assert_eq!(evaluate(&both, Some(&current)), Precondition::Proceed);
+        let malformed = request("GET", &[(IF_MODIFIED_SINCE, "yesterday")]);
+        assert_eq!(evaluate(&malformed, Some(&current)), Precondition::Proceed);
+    }
+}
@@ -0,0 +1,265 @@
+use std::{collections::HashMap, fmt, time::SystemTime};
+
+use eyre::Result;
+
+use crate::error::{HttpError, ServerError};
+
+pub type RequestCookies = HashMap<String, String>;
+
+/// Parses the value of a `Cookie` request header into name-value pairs.
+///
+/// Malformed pairs are skipped rather than rejected, and surrounding double quotes are stripped
+/// from values. If a name appears more than once, the first occurrence wins.
+///
+/// # Arguments
+///
+/// * `header` - The raw `Cookie` header value, e.g. `a=1; b=2`.
+///
+/// # Returns
+///
+/// The parsed cookies keyed by name.
+pub fn parse_cookie_header(header: &str) -> RequestCookies {
+    let mut cookies = RequestCookies::new();
+    for pair in header.split(';') {
+        let Some((name, value)) = pair.split_once('=') else {
+            continue;
+        };
+        let name = name.trim();
+        if !is_valid_name(name) {
+            continue;
+        }
+        let value = value.trim();
+        let value =
+            value.strip_prefix('"').and_then(|value| value.strip_suffix('"')).unwrap_or(value);
+        cookies.entry(name.to_string()).or_insert_with(|| value.to_string());
+    }
+    cookies
+}
+
+/// The `SameSite` attribute of a `Set-Cookie` header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl fmt::Display for SameSite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SameSite::Strict => write!(f, "Strict"),
+            SameSite::Lax => write!(f, "Lax"),
+            SameSite::None => write!(f, "None"),
+        }
+    }
+}
+
+/// A typed builder for the `Set-Cookie` response header.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SetCookie {
+    pub name:        String,
+    pub value:       String,
+    pub path:        Option<String>,
+    pub domain:      Option<String>,
+    pub expires:     Option<SystemTime>,
+    pub max_age:     Option<i64>,
+    pub secure:      bool,
+    pub http_only:   bool,
+    pub same_site:   Option<SameSite>,
+    pub partitioned: bool,
+}
+
+impl SetCookie {
+    /// Creates a new `SetCookie` with no attributes.
+    pub fn new(name: &str, value: &str) -> Self {
+        Self {
+            name:        name.to_string(),
+            value:       value.to_string(),
+            path:        None,
+            domain:      None,
+            expires:     None,
+            max_age:     None,
+            secure:      false,
+            http_only:   false,
+            same_site:   None,
+            partitioned: false,
+        }
+    }
+
+    /// Creates a `SetCookie` that instructs the client to delete the cookie.
+    pub fn removal(name: &str) -> Self {
+        Self::new(name, "").max_age(0).expires(SystemTime::UNIX_EPOCH)
+    }
+
+    /// Sets the `Path` attribute.
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    /// Sets the `Domain` attribute.
+    pub fn domain(mut self, domain: &str) -> Self {
+        self.domain = Some(domain.to_string());
+        self
+    }
+
+    /// Sets the `Expires` attribute.
+    pub fn expires(mut self, expires: SystemTime) -> Self {
+        self.expires = Some(expires);
+        self
+    }
+
+    /// Sets the `Max-Age` attribute, in seconds.
+    pub fn max_age(mut self, seconds: i64) -> Self {
+        self.max_age = Some(seconds);
+        self
+    }
+
+    /// Sets the `Secure` attribute.
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// Sets the `HttpOnly` attribute.
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    /// Sets the `SameSite` attribute.
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    /// Sets the `Partitioned` attribute.
+    pub fn partitioned(mut self, partitioned: bool) -> Self {
+        self.partitioned = partitioned;
+        self
+    }
+
+    /// Serializes the cookie into a `Set-Cookie` header value.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the header value, or an error if the name, value or an attribute
+    /// contains characters that are not allowed, or if `SameSite=None` or `Partitioned` is used
+    /// without `Secure`.
+    pub fn to_header_value(&self) -> Result<String> {
+        if !is_valid_name(&self.name) {
+            return Err(ServerError::HttpError(HttpError::InvalidCookieName).into());
+        }
+        if !self.value.bytes().all(is_cookie_octet) {
+            return Err(ServerError::HttpError(HttpError::InvalidCookieValue).into());
+        }
+        let needs_secure = self.partitioned || self.same_site == Some(SameSite::None);
+        if needs_secure && !self.secure {
+            return Err(ServerError::HttpError(HttpError::CookieRequiresSecure).into());
+        }
+
+        let mut header = format!("{}={}", self.name, self.value);
+        if let Some(path) = &self.path {
+            header.push_str(&format!("; Path={}", validate_attribute(path)?));
+        }
+        if let Some(domain) = &self.domain {
+            header.push_str(&format!("; Domain={}", validate_attribute(domain)?));
+        }
+        if let Some(expires) = self.expires {
+            header.push_str(&format!("; Expires={}", httpdate::fmt_http_date(expires)));
+        }
+        if let Some(max_age) = self.max_age {
+            header.push_str(&format!("; Max-Age={}", max_age));
+        }
+        if self.secure {
+            header.push_str("; Secure");
+        }
+        if self.http_only {
+            header.push_str("; HttpOnly");
+        }
+        if let Some(same_site) = self.same_site {
+            header.push_str(&format!("; SameSite={}", same_site));
+        }
+        if self.partitioned {
+            header.push_str("; Partitioned");
+        }
+        Ok(header)
+    }
+}
+
+/// Checks that a cookie name is a non-empty RFC 9110 token.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Checks that a byte is a `cookie-octet` as defined by RFC 6265.
+fn is_cookie_octet(b: u8) -> bool {
+    matches!(b, 0x21 | 0x23..=0x2B | 0x2D..=0x3A | 0x3C..=0x5B | 0x5D..=0x7E)
+}
+
+/// Rejects attribute values that would break out of the header.
+fn validate_attribute(value: &str) -> Result<&str> {
+    if value.bytes().any(|b| b == b';' || b.is_ascii_control()) {
+        return Err(ServerError::HttpError(HttpError::InvalidCookieAttribute).into());
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn parse_cookie_header_pairs() {
+        let cookies = parse_cookie_header("session=abc123; theme=\"dark\";  lang=en ;broken; =x");
+        let mut expected = RequestCookies::new();
+        expected.insert("session".to_string(), "abc123".to_string());
+        expected.insert("theme".to_string(), "dark".to_string());
+        expected.insert("lang".to_string(), "en".to_string());
+        assert_eq!(cookies, expected);
+    }
+
+    #[test]
+    fn parse_cookie_header_first_wins() {
+        let cookies = parse_cookie_header("id=1; id=2");
+        assert_eq!(cookies.get("id").map(String::as_str), Some("1"));
+    }
+
+    #[test]
+    fn set_cookie_all_attributes() {
+        let cookie = SetCookie::new("session", "abc123")
+            .path("/")
+            .domain("example.com")
+            .expires(SystemTime::UNIX_EPOCH + Duration::from_secs(784111777))
+            .max_age(3600)
+            .secure(true)
+            .http_only(true)
+            .same_site(SameSite::None)
+            .partitioned(true);
+        assert_eq!(
+            cookie.to_header_value().unwrap(),
+            "session=abc123; Path=/; Domain=example.com; Expires=Sun, 06 Nov 1994 08:49:37 GMT; \
+             Max-Age=3600; Secure; HttpOnly; SameSite=None; Partitioned"
+        );
+    }
+
+    #[test]
+    fn set_cookie_removal() {
+        assert_eq!(
+            SetCookie::removal("session").to_header_value().unwrap(),
+            "session=; Expires=Thu, 01 Jan 1970 00:00:00 GMT; Max-Age=0"
+        );
+    }
+
+    #[test]
+    fn set_cookie_rejects_invalid() {
+        assert!(SetCookie::new("bad name", "v").to_header_value().is_err());
+        assert!(SetCookie::new("name", "a;b").to_header_value().is_err());
+        assert!(SetCookie::new("name", "v").path("/;evil").to_header_value().is_err());
+        assert!(SetCookie::new("name", "v").same_site(SameSite::None).to_header_value().is_err());
+        assert!(SetCookie::new("name", "v").partitioned(true).to_header_value().is_err());
+    }
+}
@@ -10,6 +10,8 @@ pub enum ServerError {
     AddrParse(#[from] AddrParseError),
     #[error("HTTP error: {0}")]
     HttpError(#[from] HttpError),
+    #[error("Router error: {0}")]
+    RouterError(#[from] RouterError),
 }
 
 #[derive(Error, Debug)]
@@ -26,10 +28,72 @@ pub enum HttpError {
     MissingHeaderValue,
     #[error("Invalid content length")]
     InvalidContentLength,
+    #[error("Connection closed before the end of the body")]
+    IncompleteBody,
+    #[error("Request body too large")]
+    BodyTooLarge,
+    #[error("Request head too large")]
+    HeadTooLarge,
+    #[error("Body does not match its digest")]
+    DigestMismatch,
     #[error("Missing request line")]
     MissingRequestLine,
     #[error("Empty request line")]
     EmptyRequestLine,
     #[error("Unsupported version")]
     UnsupportedVersion,
+    #[error("Invalid cookie name")]
+    InvalidCookieName,
+    #[error("Invalid cookie value")]
+    InvalidCookieValue,
+    #[error("Invalid cookie attribute")]
+    InvalidCookieAttribute,
+    #[error("Cookie requires the Secure attribute")]
+    CookieRequiresSecure,
+    #[error("Unsupported media type")]
+    UnsupportedMediaType,
+    #[error("Missing multipart boundary")]
+    MissingBoundary,
+    #[error("Malformed multipart body")]
+    MalformedMultipart,
+    #[error("Multipart part too large")]
+    MultipartPartTooLarge,
+    #[error("Multipart part headers too large")]
+    MultipartHeadersTooLarge,
+    #[error("Too many multipart parts")]
+    MultipartTooManyParts,
+    #[error("Invalid JSON: {0}")]
+    InvalidJson(String),
+    #[error("Invalid path")]
+    InvalidPath,
+    #[error("Forbidden path")]
+    ForbiddenPath,
+    #[error("Parent directory does not exist")]
+    MissingParentDirectory,
+    #[error("Resource is locked")]
+    Locked,
+    #[error("Precondition failed")]
+    PreconditionFailed,
+    #[error("Invalid path parameters: {0}")]
+    InvalidPathParams(String),
+    #[error("Invalid query string: {0}")]
+    InvalidQuery(String),
+}
+
+#[derive(Error, Debug)]
+pub enum RouterError {
+    #[error("Invalid route pattern: {0}")]
+    InvalidPattern(String),
+    #[error("Route conflicts with an existing route: {0}")]
+    RouteConflict(String),
+    #[error("Invalid host pattern: {0}")]
+    InvalidHostPattern(String),
+    #[error("Invalid rewrite rule: {0}")]
+    InvalidRewriteRule(String),
+    #[error("Rewrite rules loop on {0}")]
+    RewriteLoop(String),
+    #[error("Invalid MIME types configuration: {0}")]
+    InvalidMimeTypes(String),
+    #[error("No state of type {0} registered on the router")]
+    MissingState(&'static str),
 }
@@ -0,0 +1,265 @@
+use std::{
+    any::{type_name, Any, TypeId},
+    collections::HashMap,
+    future::{ready, Future},
+    sync::Arc,
+};
+
+use eyre::Result;
+use serde::de::DeserializeOwned;
+
+use crate::{
+    error::{HttpError, RouterError, ServerError},
+    http::{HttpRequest, RequestHeaders},
+    router::{HandlerOutput, RouteFuture},
+};
+
+/// Typed application state shared by every request of a router, keyed by type.
+#[derive(Debug, Clone, Default)]
+pub struct AppState {
+    values: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+}
+
+impl AppState {
+    /// Creates an empty `AppState`.
+    pub fn new() -> Self { Self::default() }
+
+    /// Stores a value, replacing any previous value of the same type.
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) {
+        self.values.insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    /// Copies every value of `other` into this state, replacing values of the same type.
+    pub fn extend(&mut self, other: &AppState) {
+        self.values.extend(other.values.iter().map(|(k, v)| (*k, v.clone())));
+    }
+
+    /// Returns `true` if no state is stored.
+    pub fn is_empty(&self) -> bool { self.values.is_empty() }
+
+    /// Returns the value of a type, if one was stored.
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.values.get(&TypeId::of::<T>()).and_then(|value| value.downcast_ref())
+    }
+}
+
+/// A type that can be extracted from a request and declared as a handler argument.
+pub trait FromRequest: Sized + Send + 'static {
+    /// Extracts the value from a request.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the value, or an error that the router's error handler turns into
+    /// a response. Malformed input maps to 400.
+    fn from_request(request: &HttpRequest) -> Result<Self>;
+}
+
+/// Deserializes the path parameters, e.g. `Path<UserPath>` for `/users/:id`.
+#[derive(Debug)]
+pub struct Path<T>(pub T);
+
+impl<T: DeserializeOwned + Send + 'static> FromRequest for Path<T> {
+    fn from_request(request: &HttpRequest) -> Result<Self> {
+        let invalid = |e: serde_urlencoded::de::Error| {
+            ServerError::HttpError(HttpError::InvalidPathParams(e.to_string()))
+        };
+        // The parameters are already decoded, so re-encode them for the form deserializer,
+        // which also parses numbers and booleans from strings.
+        let encoded = serde_urlencoded::to_string(&request.params).expect("strings encode");
+        Ok(Self(serde_urlencoded::from_str(&encoded).map_err(invalid)?))
+    }
+}
+
+/// Deserializes the query string, e.g. `Query<Pagination>` for `?page=2`.
+#[derive(Debug)]
+pub struct Query<T>(pub T);
+
+impl<T: DeserializeOwned + Send + 'static> FromRequest for Query<T> {
+    fn from_request(request: &HttpRequest) -> Result<Self> {
+        let query = request.line.path.split_once('?').map(|(_, query)| query).unwrap_or_default();
+        serde_urlencoded::from_str(query)
+            .map(Self)
+            .map_err(|e| ServerError::HttpError(HttpError::InvalidQuery(e.to_string())).into())
+    }
+}
+
+/// Deserializes a JSON body, see [`HttpRequest::json`].
+#[derive(Debug)]
+pub struct Json<T>(pub T);
+
+impl<T: DeserializeOwned + Send + 'static> FromRequest for Json<T> {
+    fn from_request(request: &HttpRequest) -> Result<Self> { request.json().map(Self) }
+}
+
+/// The request headers.
+#[derive(Debug)]
+pub struct Headers(pub RequestHeaders);
+
+impl FromRequest for Headers {
+    fn from_request(request: &HttpRequest) -> Result<Self> { Ok(Self(request.headers.clone())) }
+}
+
+/// A clone of state registered with [`crate::router::Router::state`].
+///
+/// Extracting state that was never registered is a programming error and maps to 500.
+#[derive(Debug)]
+pub struct State<T>(pub T);
+
+impl<T: Clone + Send + Sync + 'static> FromRequest for State<T> {
+    fn from_request(request: &HttpRequest) -> Result<Self> {
+        let missing = || ServerError::RouterError(RouterError::MissingState(type_name::<T>()));
+        Ok(Self(request.state.get::<T>().ok_or_else(missing)?.clone()))
+    }
+}
+
+/// A handler function whose arguments are all extractors.
+pub trait Handler<Args>: Send + Sync + 'static {
+    /// Extracts the arguments from a request and calls the handler.
+    fn call(&self, request: &HttpRequest) -> RouteFuture;
+}
+
+macro_rules! impl_handler {
+    ($($arg:ident),*) => {
+        impl<F, Fut, $($arg),*> Handler<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> Fut + Send + Sync + 'static,
+            Fut: Future + Send + 'static,
+            Fut::Output: HandlerOutput,
+            $($arg: FromRequest,)*
+        {
+            #[allow(non_snake_case, unused_variables)]
+            fn call(&self, request: &HttpRequest) -> RouteFuture {
+                $(
+                    let $arg = match $arg::from_request(request) {
+                        Ok(value) => value,
+                        Err(error) => return Box::pin(ready(Err(error))),
+                    };
+                )*
+                let response = self($($arg),*);
+                Box::pin(async move { response.await.into_result() })
+            }
+        }
+    };
+}
+
+impl_handler!();
+impl_handler!(A);
+impl_handler!(A, B);
+impl_handler!(A, B, C);
+impl_handler!(A, B, C, D);
+impl_handler!(A, B, C, D, E);
+impl_handler!(A, B, C, D, E, G);
+
+/// Adapts a handler that takes extractors as arguments into a route handler.
+///
+/// Every argument is extracted before the handler runs; if any extraction fails, the error is
+/// passed to the router's error handler instead.
+///
+/// # Arguments
+///
+/// * `handler` - An async function taking up to six [`FromRequest`] arguments.
+pub fn extract<H, Args>(handler: H) -> impl Fn(HttpRequest) -> RouteFuture + Send + Sync + 'static
+where H: Handler<Args> {
+    move |request| handler.call(&request)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::{
+        http::{HttpResponse, ResponseHeaders, StatusCode},
+        router::Router,
+    };
+
+    #[derive(Deserialize)]
+    struct UserPath {
+        id: u32,
+    }
+
+    #[derive(Deserialize)]
+    struct Pagination {
+        page:  u32,
+        #[serde(default)]
+        limit: Option<u32>,
+    }
+
+    #[derive(Clone)]
+    struct Counter(Arc<AtomicUsize>);
+
+    async fn resolve(router: &Router, request: &str) -> HttpResponse {
+        router.resolve(HttpRequest::from_string(request).unwrap()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn extracts_arguments() {
+        let mut router = Router::new();
+        router.state(Counter(Arc::new(AtomicUsize::new(0))));
+        router
+            .get(
+                "/users/:id",
+                extract(
+                    |Path(path): Path<UserPath>,
+                     Query(page): Query<Pagination>,
+                     State(counter): State<Counter>| async move {
+                        let hits = counter.0.fetch_add(1, Ordering::SeqCst) + 1;
+                        let body = format!("{} {} {:?} {}", path.id, page.page, page.limit, hits);
+                        HttpResponse::ok(body.as_bytes(), ResponseHeaders::new())
+                    },
+                ),
+            )
+            .unwrap();
+
+        let response = resolve(&router, "GET /users/7?page=2 HTTP/1.1\r\n\r\n").await;
+        assert_eq!(response.body, b"7 2 None 1");
+        let response = resolve(&router, "GET /users/7?page=3&limit=5 HTTP/1.1\r\n\r\n").await;
+        assert_eq!(response.body, b"7 3 Some(5) 2");
+    }
+
+    #[tokio::test]
+    async fn failed_extraction_is_bad_request() {
+        let mut router = Router::new();
+        router
+            .get(
+                "/users/:id",
+                extract(|Path(path): Path<UserPath>, Headers(headers): Headers| async move {
+                    let body = format!("{} {}", path.id, headers.len());
+                    HttpResponse::ok(body.as_bytes(), ResponseHeaders::new())
+                }),
+            )
+            .unwrap();
+        router
+            .post(
+                "/users",
+                extract(|Json(value): Json<serde_json::Value>| async move {
+                    HttpResponse::json(StatusCode::CREATED, &value)
+                }),
+            )
+            .unwrap();
+        router
+            .get(
+                "/count",
+                extract(|State(counter): State<Counter>| async move {
+                    let hits = counter.0.load(Ordering::SeqCst).to_string();
+                    HttpResponse::ok(hits.as_bytes(), ResponseHeaders::new())
+                }),
+            )
+            .unwrap();
+
+        let response = resolve(&router, "GET /users/abc HTTP/1.1\r\n\r\n").await;
+        assert_eq!(response.status_code, StatusCode::BAD_REQUEST);
+        let response = resolve(&router, "GET /users/1 HTTP/1.1\r\nHost: a\r\n\r\n").await;
+        assert_eq!(response.body, b"1 1");
+        let response = resolve(
+            &router,
+            "POST /users HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: 2\r\n\r\n{]",
+        )
+        .await;
+        assert_eq!(response.status_code, StatusCode::BAD_REQUEST);
+        let response = resolve(&router, "GET /count HTTP/1.1\r\n\r\n").await;
+        assert_eq!(response.status_code, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
@@ -0,0 +1,1687 @@
+use std::{
+    collections::{hash_map::RandomState, BTreeSet, HashMap},
+    fs::{self, File},
+    hash::{BuildHasher, Hasher},
+    io::{self, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError, Weak},
+};
+
+use bytes::Bytes;
+use eyre::{Result, WrapErr};
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    runtime::Handle,
+    sync::{Mutex as AsyncMutex, OwnedMutexGuard},
+    task::spawn_blocking,
+};
+
+use crate::{
+    autoindex,
+    body::BodyStream,
+    conditional::{self, ETagMode, Precondition, Validators},
+    error::{HttpError, ServerError},
+    extract::{FromRequest, State},
+    form::{
+        multipart_boundary, parse_content_type, percent_decode, sanitize_filename, MultipartEvent,
+        MultipartLimits, MultipartParser, CT_FORM_URLENCODED, CT_MULTIPART_FORM_DATA,
+    },
+    http::{
+        HttpRequest, HttpResponse, ResponseHeaders, StatusCode, ACCEPT_RANGES, ALLOW,
+        CONTENT_DIGEST, CONTENT_RANGE, CONTENT_TYPE, CT_APPLICATION_OCTET_STREAM, METHOD_GET,
+        REPR_DIGEST,
+    },
+    mime::{self, MimeTypes, SNIFF_LEN},
+    range::{self, RangeRequest, BYTES},
+    router::Router,
+    webdav,
+};
+
+/// The directory served under `/files`, shared with handlers as router state.
+#[derive(Debug, Clone)]
+pub struct PublicDir(pub String);
+
+/// Static-site mode, shared with handlers as router state: the public directory is served at
+/// `/` by [`serve_site`].
+#[derive(Debug, Clone)]
+pub struct StaticSite {
+    index:    String,
+    fallback: Option<String>,
+}
+
+impl Default for StaticSite {
+    fn default() -> Self { Self { index: "index.html".to_string(), fallback: None } }
+}
+
+impl StaticSite {
+    /// Creates a `StaticSite` that serves `index.html` for directories and has no fallback.
+    pub fn new() -> Self { Self::default() }
+
+    /// Sets the file served for a directory.
+    pub fn index(&mut self, name: &str) -> &mut Self {
+        self.index = name.to_string();
+        self
+    }
+
+    /// Sets the file served for paths that match no file, e.g. a single-page app's
+    /// `index.html` so that client-side routes load the app.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The file, relative to the public directory.
+    pub fn fallback(&mut self, path: &str) -> &mut Self {
+        self.fallback = Some(path.to_string());
+        self
+    }
+}
+
+/// Options for files written under `/files`, shared with handlers as router state.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UploadOptions {
+    create_parents: bool,
+}
+
+impl UploadOptions {
+    /// Creates `UploadOptions` that refuse writes into missing directories.
+    pub fn new() -> Self { Self::default() }
+
+    /// Sets whether missing parent directories of an uploaded file are created, instead of
+    /// answering 409 Conflict.
+    pub fn create_parents(&mut self, create_parents: bool) -> &mut Self {
+        self.create_parents = create_parents;
+        self
+    }
+}
+
+/// The suffix of the temporary files uploads are written to before they are renamed into place.
+pub const TEMP_SUFFIX: &str = ".upload-tmp";
+
+/// Serialises writers of the same file, shared with handlers as router state.
+///
+/// A writer holds the lock of its target from checking the request's preconditions until the
+/// file is replaced or removed, so concurrent writes to one path happen one after the other and
+/// each sees the file the one before left. Writers of several files take their locks in path
+/// order.
+#[derive(Debug, Default)]
+pub struct WriteLocks {
+    locks: Mutex<HashMap<PathBuf, Weak<AsyncMutex<()>>>>,
+}
+
+impl WriteLocks {
+    /// Creates an empty `WriteLocks`.
+    pub fn new() -> Self { Self::default() }
+
+    /// Returns the lock of a file, shared by every writer of the file at the moment.
+    ///
+    /// Locks no writer holds any more are dropped, so the table only grows with the number of
+    /// files being written concurrently.
+    pub fn lock_for(&self, file: &Path) -> Arc<AsyncMutex<()>> {
+        let mut locks = self.locks.lock().unwrap_or_else(PoisonError::into_inner);
+        locks.retain(|_, lock| lock.strong_count() > 0);
+        if let Some(lock) = locks.get(file).and_then(Weak::upgrade) {
+            return lock;
+        }
+        let lock = Arc::new(AsyncMutex::new(()));
+        locks.insert(file.to_path_buf(), Arc::downgrade(&lock));
+        lock
+    }
+}
+
+/// How symbolic links inside the public directory are treated, shared with handlers as router
+/// state.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SymlinkPolicy {
+    /// Follows every link, even out of the public directory.
+    Follow,
+    /// Follows links whose target stays inside the public directory.
+    #[default]
+    WithinRoot,
+    /// Refuses every path that passes through a link.
+    Deny,
+}
+
+/// Resolves a request path to a file inside a root directory.
+///
+/// The path must be relative and free of `..` segments, backslashes and NUL bytes; it is
+/// checked after percent-decoding, so encoded forms such as `%2e%2e` are rejected as well.
+/// Symbolic links met along the way are checked against `symlinks`. The server's own files,
+/// the sidecar files holding content types and the temporary files of uploads, are out of
+/// reach, so clients can neither read nor forge them.
+///
+/// # Arguments
+///
+/// * `root` - The directory the path must stay in.
+/// * `path` - The decoded path relative to `root`.
+/// * `symlinks` - The policy for symbolic links.
+///
+/// # Returns
+///
+/// A `Result` containing the path under `root`, an `InvalidPath` error if the path is
+/// malformed or tries to leave `root`, or a `ForbiddenPath` error if a symbolic link or one of
+/// the server's own files is refused.
+pub fn resolve_path(root: &Path, path: &str, symlinks: SymlinkPolicy) -> Result<PathBuf> {
+    let invalid = || ServerError::HttpError(HttpError::InvalidPath);
+    let forbidden = || ServerError::HttpError(HttpError::ForbiddenPath);
+    if path.contains(['\0', '\\']) {
+        return Err(invalid().into());
+    }
+    let mut resolved = root.to_path_buf();
+    let mut canonical_root = None;
+    for component in Path::new(path).components() {
+        let part = match component {
+            Component::Normal(part) => part,
+            Component::CurDir => continue,
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) =>
+                return Err(invalid().into()),
+        };
+        let name = part.to_string_lossy();
+        if is_temp_file(&name) || mime::sidecar_owner(&name).is_some() {
+            return Err(forbidden().into());
+        }
+        resolved.push(part);
+        if symlinks == SymlinkPolicy::Follow
+            || !fs::symlink_metadata(&resolved).is_ok_and(|meta| meta.file_type().is_symlink())
+        {
+            continue;
+        }
+        if symlinks == SymlinkPolicy::Deny {
+            return Err(forbidden().into());
+        }
+        if canonical_root.is_none() {
+            canonical_root = Some(fs::canonicalize(root)?);
+        }
+        // Dangling links cannot be checked, so they are refused too.
+        let target = fs::canonicalize(&resolved).map_err(|_| forbidden())?;
+        if !canonical_root.as_ref().is_some_and(|root| target.starts_with(root)) {
+            return Err(forbidden().into());
+        }
+    }
+    Ok(resolved)
+}
+
+/// Takes the write lock of a file from the [`WriteLocks`] registered as state, waiting for the
+/// current writer if there is one.
+///
+/// # Returns
+///
+/// The guard that holds the lock until it is dropped, or `None` if there are no `WriteLocks`.
+pub(crate) async fn lock_file(request: &HttpRequest, file: &Path) -> Option<OwnedMutexGuard<()>> {
+    match request.state.get::<WriteLocks>() {
+        Some(locks) => Some(locks.lock_for(file).lock_owned().await),
+        None => None,
+    }
+}
+
+/// Resolves a request path on a blocking thread, using the request's [`SymlinkPolicy`].
+pub(crate) async fn resolve_request_path(
+    request: &HttpRequest,
+    dir: &str,
+    path: &str,
+) -> Result<PathBuf> {
+    let symlinks = request.state.get::<SymlinkPolicy>().copied().unwrap_or_default();
+    let (root, path) = (PathBuf::from(dir), path.to_string());
+    spawn_blocking(move || resolve_path(&root, &path, symlinks))
+        .await
+        .wrap_err("Path resolution task failed")?
+}
+
+/// Returns a file's validators under the request's [`ETagMode`] and its length, with the file's
+/// content if it was read to compute them, or `None` if there is no such file.
+pub(crate) async fn file_validators(
+    request: &HttpRequest,
+    file: &Path,
+) -> Option<(Validators, u64, Option<Vec<u8>>)> {
+    let metadata = tokio::fs::metadata(file).await.ok().filter(|metadata| metadata.is_file())?;
+    let content = match request.state.get::<ETagMode>().copied().unwrap_or_default() {
+        ETagMode::ContentHash => Some(tokio::fs::read(file).await.ok()?),
+        ETagMode::Metadata => None,
+    };
+    let len = content.as_ref().map_or(metadata.len(), |content| content.len() as u64);
+    Some((Validators::for_file(&metadata, content.as_deref()), len, content))
+}
+
+/// Returns the `Content-Type` of a file under the [`MimeTypes`] registered as state.
+///
+/// The file's first bytes are taken from `content`, or read from disk only if sniffing needs
+/// them.
+pub(crate) async fn file_content_type(
+    request: &HttpRequest,
+    dir: &str,
+    file: &Path,
+    content: Option<&[u8]>,
+) -> Result<String> {
+    let types = request.state.get::<MimeTypes>().cloned().unwrap_or_default();
+    let head = match content {
+        Some(content) => content[..content.len().min(SNIFF_LEN)].to_vec(),
+        None if types.is_sniffing() => {
+            let mut head = Vec::with_capacity(SNIFF_LEN);
+            let file = tokio::fs::File::open(file).await?;
+            file.take(SNIFF_LEN as u64).read_to_end(&mut head).await?;
+            head
+        },
+        None => Vec::new(),
+    };
+    let relative = file.strip_prefix(dir).unwrap_or(file).to_string_lossy().into_owned();
+    let file = file.to_path_buf();
+    let stored = spawn_blocking(move || mime::stored_content_type(&file))
+        .await
+        .wrap_err("Content type lookup task failed")?;
+    Ok(types.content_type(&relative, stored.as_deref(), &head))
+}
+
+/// Serves a file of the public directory, answering conditional and range requests.
+///
+/// # Arguments
+///
+/// * `request` - The HTTP request.
+/// * `dir` - The public directory.
+/// * `file` - The resolved path of the file.
+///
+/// # Returns
+///
+/// A `Result` containing the response, which is a 404 if `file` is not a file.
+pub(crate) async fn send_file(
+    request: &HttpRequest,
+    dir: &str,
+    file: &Path,
+) -> Result<HttpResponse> {
+    let Some((validators, len, content)) = file_validators(request, file).await else {
+        return Ok(HttpResponse::not_found());
+    };
+    match conditional::evaluate(request, Some(&validators)) {
+        Precondition::NotModified => return Ok(validators.not_modified()),
+        Precondition::Failed => return Ok(HttpResponse::precondition_failed()),
+        Precondition::Proceed => {},
+    }
+    let ranges = match range::requested_ranges(request, &validators, len) {
+        RangeRequest::Unsatisfiable => return Ok(range::range_not_satisfiable(len)),
+        RangeRequest::Partial(ranges) => Some(ranges),
+        RangeRequest::Full => None,
+    };
+    let content_type = file_content_type(request, dir, file, content.as_deref()).await?;
+    let mut headers = ResponseHeaders::new();
+    headers.insert(ACCEPT_RANGES.to_string(), BYTES.to_string());
+    validators.insert_headers(&mut headers);
+    if let Some(ranges) = ranges {
+        let parts = match content {
+            Some(content) => ranges
+                .iter()
+                .map(|range| content[range.start as usize..=range.end as usize].to_vec())
+                .collect(),
+            None => range::read_ranges(file, &ranges).await?,
+        };
+        return Ok(range::partial_content(&ranges, parts, len, &content_type, headers));
+    }
+    let body = match content {
+        Some(content) => content,
+        None => match tokio::fs::read(file).await {
+            Ok(body) => body,
+            Err(_) => return Ok(HttpResponse::not_found()),
+        },
+    };
+    headers.insert(CONTENT_TYPE.to_string(), content_type);
+    Ok(HttpResponse::new(StatusCode::OK, &body, headers))
+}
+
+/// Creates the router for `/files`, which reads and writes files in the [`PublicDir`].
+///
+/// Paths are resolved with [`resolve_path`] under the [`SymlinkPolicy`] registered as state,
+/// or [`SymlinkPolicy::WithinRoot`] if there is none. Served files get their `Content-Type`
+/// from the [`MimeTypes`] registered as state, or the defaults if there are none, and uploads
+/// keep the `Content-Type` they were sent with.
+///
+/// Responses carry `ETag` and `Last-Modified` computed under the [`ETagMode`] registered as
+/// state, and conditional requests are answered with 304 Not Modified or 412 Precondition
+/// Failed. `Range` requests are served with 206 Partial Content, reading only the requested
+/// bytes. Directories are listed where the [`AutoIndex`](autoindex::AutoIndex) registered as
+/// state enables it.
+///
+/// `POST` and `PUT` answer 201 Created for a new file and 204 No Content for a replaced one,
+/// and `DELETE` 204 No Content or 404 Not Found. `PATCH` updates part of an existing file: it
+/// writes the body at the bytes a `Content-Range` such as `bytes 10-19/*` names, or appends it
+/// without one, and answers 204 No Content, or 416 Range Not Satisfiable for a range that starts
+/// past the end of the file. Writes to a directory answer 409 Conflict. All of them honour the
+/// same preconditions, so `If-None-Match: *` makes an upload create-only. Writes replace files
+/// atomically, one writer at a time, and writes into missing directories follow the
+/// [`UploadOptions`] registered as state.
+pub fn file_router() -> Result<Router> {
+    let mut router = Router::new();
+
+    router.get("/*path", |request: HttpRequest| async move {
+        let State(PublicDir(dir)) = State::from_request(&request)?;
+        let path = request.param("path").unwrap_or_default();
+        let file = resolve_request_path(&request, &dir, path).await?;
+        if tokio::fs::metadata(&file).await.is_ok_and(|metadata| metadata.is_dir()) {
+            return autoindex::serve_listing(&request, &dir, path).await;
+        }
+        send_file(&request, &dir, &file).await
+    })?;
+
+    router
+        .post("/*path", |mut request: HttpRequest| async move {
+            let State(PublicDir(dir)) = State::from_request(&request)?;
+            if is_multipart(&request) {
+                let limits = MultipartLimits::default();
+                let symlinks = request.state.get::<SymlinkPolicy>().copied().unwrap_or_default();
+                let runtime = Handle::current();
+                let saved = spawn_blocking(move || {
+                    save_multipart_files(&mut request, &dir, symlinks, limits, &runtime)
+                })
+                .await
+                .wrap_err("Multipart upload task failed")??;
+                return Ok(match saved {
+                    0 => HttpResponse::bad_request(),
+                    _ => HttpResponse::created(),
+                });
+            }
+            let path = request.param("path").unwrap_or_default();
+            let file = resolve_request_path(&request, &dir, path).await?;
+            if tokio::fs::metadata(&file).await.is_ok_and(|metadata| metadata.is_dir()) {
+                return Ok(HttpResponse::conflict());
+            }
+            let _guard = lock_file(&request, &file).await;
+            let current = file_validators(&request, &file).await.map(|(validators, ..)| validators);
+            if conditional::evaluate(&request, current.as_ref()) != Precondition::Proceed {
+                return Ok(HttpResponse::precondition_failed());
+            }
+            write_upload(&mut request, &file).await?;
+            Ok(match current {
+                Some(_) => HttpResponse::no_content(),
+                None => HttpResponse::created(),
+            })
+        })?
+        .stream_body();
+
+    router
+        .put("/*path", |mut request: HttpRequest| async move {
+            let State(PublicDir(dir)) = State::from_request(&request)?;
+            let path = request.param("path").unwrap_or_default();
+            let file = resolve_request_path(&request, &dir, path).await?;
+            if tokio::fs::metadata(&file).await.is_ok_and(|metadata| metadata.is_dir()) {
+                return Ok(HttpResponse::conflict());
+            }
+            let _guard = lock_file(&request, &file).await;
+            let current = file_validators(&request, &file).await.map(|(validators, ..)| validators);
+            if conditional::evaluate(&request, current.as_ref()) != Precondition::Proceed {
+                return Ok(HttpResponse::precondition_failed());
+            }
+            write_upload(&mut request, &file).await?;
+            Ok(match current {
+                Some(_) => HttpResponse::no_content(),
+                None => HttpResponse::created(),
+            })
+        })?
+        .stream_body();
+
+    router.patch("/*path", |mut request: HttpRequest| async move {
+        let State(PublicDir(dir)) = State::from_request(&request)?;
+        let path = request.param("path").unwrap_or_default();
+        let file = resolve_request_path(&request, &dir, path).await?;
+        if tokio::fs::metadata(&file).await.is_ok_and(|metadata| metadata.is_dir()) {
+            return Ok(HttpResponse::conflict());
+        }
+        let _guard = lock_file(&request, &file).await;
+        let Some((validators, len, _)) = file_validators(&request, &file).await else {
+            return Ok(HttpResponse::not_found());
+        };
+        if conditional::evaluate(&request, Some(&validators)) != Precondition::Proceed {
+            return Ok(HttpResponse::precondition_failed());
+        }
+        if !webdav::write_permitted(&request, &file, false) {
+            return Ok(HttpResponse::locked());
+        }
+        let offset = match request.headers.get(CONTENT_RANGE) {
+            None => len,
+            Some(header) => match patch_range(header) {
+                Some((start, size)) if size == request.body.len() as u64 => start,
+                _ => return Ok(HttpResponse::bad_request()),
+            },
+        };
+        if offset > len {
+            return Ok(range::range_not_satisfiable(len));
+        }
+        let body = std::mem::take(&mut request.body);
+        spawn_blocking(move || patch_file(&file, offset, &body))
+            .await
+            .wrap_err("Patch task failed")??;
+        Ok(HttpResponse::no_content())
+    })?;
+
+    router.delete("/*path", |request: HttpRequest| async move {
+        let State(PublicDir(dir)) = State::from_request(&request)?;
+        let path = request.param("path").unwrap_or_default();
+        let file = resolve_request_path(&request, &dir, path).await?;
+        let _guard = lock_file(&request, &file).await;
+        let Some((validators, ..)) = file_validators(&request, &file).await else {
+            return Ok(HttpResponse::not_found());
+        };
+        if conditional::evaluate(&request, Some(&validators)) != Precondition::Proceed {
+            return Ok(HttpResponse::precondition_failed());
+        }
+        if !webdav::write_permitted(&request, &file, false) {
+            return Ok(HttpResponse::locked());
+        }
+        Ok(match tokio::fs::remove_file(&file).await {
+            Ok(()) => {
+                spawn_blocking(move || mime::remove_content_type(&file))
+                    .await
+                    .wrap_err("Delete task failed")??;
+                HttpResponse::no_content()
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HttpResponse::not_found(),
+            Err(e) =>
+                return Err(e).wrap_err_with(|| format!("Failed to delete {}", file.display())),
+        })
+    })?;
+
+    Ok(router)
+}
+
+/// Serves a request from the public directory in static-site mode.
+///
+/// A file is served as under `/files`. A directory requested without a trailing slash is
+/// redirected to it; with one, its index file is served, or its listing if the
+/// [`AutoIndex`](autoindex::AutoIndex) enables it. Paths that match nothing get the fallback
+/// file if there is one. Without a [`StaticSite`] registered as state every path is a 404, and
+/// methods other than `GET` get a 405.
+///
+/// # Arguments
+///
+/// * `request` - The HTTP request.
+///
+/// # Returns
+///
+/// A `Result` containing the response, or an error if the path is invalid or forbidden.
+pub async fn serve_site(request: HttpRequest) -> Result<HttpResponse> {
+    let Some(site) = request.state.get::<StaticSite>().cloned() else {
+        return Ok(HttpResponse::not_found());
+    };
+    if request.line.method != METHOD_GET {
+        let mut response = HttpResponse::method_not_allowed();
+        response.headers.insert(ALLOW.to_string(), METHOD_GET.to_string());
+        return Ok(response);
+    }
+    let State(PublicDir(dir)) = State::from_request(&request)?;
+    let (request_path, query) = match request.line.path.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (request.line.path.as_str(), None),
+    };
+    let path = percent_decode(request_path.trim_start_matches('/'), false);
+    let file = resolve_request_path(&request, &dir, &path).await?;
+    let metadata = tokio::fs::metadata(&file).await.ok();
+    if metadata.as_ref().is_some_and(|metadata| metadata.is_dir()) {
+        if !request_path.ends_with('/') {
+            let name = file.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+            let mut location = format!("{}/", autoindex::encode_segment(&name));
+            if let Some(query) = query {
+                location = format!("{}?{}", location, query);
+            }
+            return Ok(HttpResponse::redirect(StatusCode::MOVED_PERMANENTLY, &location));
+        }
+        let index = Path::new(&path).join(&site.index);
+        let index = resolve_request_path(&request, &dir, &index.to_string_lossy()).await?;
+        if tokio::fs::metadata(&index).await.is_ok_and(|metadata| metadata.is_file()) {
+            return send_file(&request, &dir, &index).await;
+        }
+        let listing = autoindex::serve_listing(&request, &dir, &path).await?;
+        if listing.status_code != StatusCode::NOT_FOUND || site.fallback.is_none() {
+            return Ok(listing);
+        }
+    } else if metadata.is_some_and(|metadata| metadata.is_file()) {
+        return send_file(&request, &dir, &file).await;
+    }
+    match &site.fallback {
+        Some(fallback) => {
+            let file = resolve_request_path(&request, &dir, fallback).await?;
+            send_file(&request, &dir, &file).await
+        },
+        None => Ok(HttpResponse::not_found()),
+    }
+}
+
+/// Writes a request body to a file and stores the content type it was sent with.
+///
+/// A streamed body is written chunk by chunk as it arrives. The body goes to a temporary file
+/// next to `file`, which is synced and then renamed over `file`, so readers and a crash see
+/// either the old or the new content, never part of it. The caller holds the file's lock from
+/// [`lock_file`] since checking the request's preconditions. A SHA-256 digest the request
+/// announces in `Content-Digest` or `Repr-Digest` is checked against the body as it is
+/// written, and `file` is left alone if they differ. A file under a WebDAV lock is only written
+/// by requests that submit its token.
+///
+/// # Arguments
+///
+/// * `request` - The HTTP request, whose body stream is consumed.
+/// * `file` - The resolved path of the file.
+///
+/// # Returns
+///
+/// A `Result` that is a `Locked` error if the file is locked, a `MissingParentDirectory` error
+/// if the file's directory does not exist and the [`UploadOptions`] registered as state do not
+/// allow creating it, or a `DigestMismatch` error if the body does not match its digest.
+pub(crate) async fn write_upload(request: &mut HttpRequest, file: &Path) -> Result<()> {
+    if !webdav::write_permitted(request, file, false) {
+        return Err(ServerError::HttpError(HttpError::Locked).into());
+    }
+    let options = request.state.get::<UploadOptions>().copied().unwrap_or_default();
+    if let Some(parent) = file.parent() {
+        if !tokio::fs::metadata(parent).await.is_ok_and(|metadata| metadata.is_dir()) {
+            if !options.create_parents {
+                return Err(ServerError::HttpError(HttpError::MissingParentDirectory).into());
+            }
+            tokio::fs::create_dir_all(parent)
+                .await
+                .wrap_err_with(|| format!("Failed to create {}", parent.display()))?;
+        }
+    }
+    let temp = temp_path(file);
+    let upload = Upload {
+        content_type: upload_content_type(request.headers.get(CONTENT_TYPE)).map(str::to_string),
+        digest:       announced_digest(request),
+        stream:       request.body_stream.take(),
+    };
+    let result = write_temp_file(&temp, file, upload, &request.body).await;
+    if result.is_err() {
+        let _ = spawn_blocking(move || discard_temp_file(&temp)).await;
+    }
+    result.wrap_err_with(|| format!("Failed to write {}", file.display()))
+}
+
+/// What [`write_temp_file`] needs of a request besides its buffered body.
+struct Upload {
+    content_type: Option<String>,
+    digest:       Option<String>,
+    stream:       Option<BodyStream>,
+}
+
+/// Writes a body, streamed or buffered, to a temporary file and renames it over `file`.
+async fn write_temp_file(temp: &Path, file: &Path, upload: Upload, body: &[u8]) -> Result<()> {
+    let mut out = tokio::fs::OpenOptions::new().write(true).create_new(true).open(temp).await?;
+    let mut hasher = upload.digest.is_some().then(Sha256::new);
+    match upload.stream {
+        Some(mut stream) =>
+            while let Some(chunk) = stream.chunk().await? {
+                if let Some(hasher) = hasher.as_mut() {
+                    hasher.update(&chunk);
+                }
+                out.write_all(&chunk).await?;
+            },
+        None => {
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(body);
+            }
+            out.write_all(body).await?;
+        },
+    }
+    if let (Some(expected), Some(hasher)) = (upload.digest, hasher) {
+        if base64_encode(&hasher.finalize()) != expected {
+            return Err(ServerError::HttpError(HttpError::DigestMismatch).into());
+        }
+    }
+    out.sync_all().await?;
+    drop(out);
+    let (temp, file, content_type) = (temp.to_path_buf(), file.to_path_buf(), upload.content_type);
+    spawn_blocking(move || {
+        if let Some(content_type) = content_type {
+            mime::store_content_type(&temp, &content_type);
+        }
+        replace_file(&temp, &file)
+    })
+    .await
+    .wrap_err("Upload task failed")??;
+    Ok(())
+}
+
+/// Parses the `Content-Range` of a `PATCH`, e.g. `bytes 10-19/*` or `bytes 10-19/20`.
+///
+/// # Returns
+///
+/// The offset the body is written at and the length it must have, or `None` if the header is
+/// malformed.
+fn patch_range(header: &str) -> Option<(u64, u64)> {
+    let (unit, range) = header.trim().split_once(' ')?;
+    let (range, _complete_length) = range.trim().split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = (start.parse::<u64>().ok()?, end.parse::<u64>().ok()?);
+    (unit.eq_ignore_ascii_case(BYTES) && start <= end).then(|| (start, end - start + 1))
+}
+
+/// Writes `body` at `offset` into a copy of `file` and renames the copy over it, keeping the
+/// file's stored content type, so readers see either the old or the updated content.
+fn patch_file(file: &Path, offset: u64, body: &[u8]) -> Result<()> {
+    let temp = temp_path(file);
+    let result = write_patched_file(&temp, file, offset, body);
+    if result.is_err() {
+        discard_temp_file(&temp);
+    }
+    result.wrap_err_with(|| format!("Failed to patch {}", file.display()))
+}
+
+/// Copies `file` to a temporary file, writes `body` into it at `offset` and renames it over
+/// `file`.
+fn write_patched_file(temp: &Path, file: &Path, offset: u64, body: &[u8]) -> io::Result<()> {
+    let mut out = File::options().write(true).create_new(true).open(temp)?;
+    io::copy(&mut File::open(file)?, &mut out)?;
+    out.seek(SeekFrom::Start(offset))?;
+    out.write_all(body)?;
+    out.sync_all()?;
+    drop(out);
+    if let Some(content_type) = mime::stored_content_type(file) {
+        mime::store_content_type(temp, &content_type);
+    }
+    replace_file(temp, file)
+}
+
+/// Returns the base64-encoded SHA-256 digest a request announces for its body, from a
+/// `Content-Digest` or `Repr-Digest` header such as `sha-256=:X48E9q...=:`.
+fn announced_digest(request: &HttpRequest) -> Option<String> {
+    [CONTENT_DIGEST, REPR_DIGEST]
+        .iter()
+        .filter_map(|name| request.headers.get(*name))
+        .flat_map(|value| value.split(','))
+        .find_map(|member| {
+            let (algorithm, digest) = member.split_once('=')?;
+            algorithm
+                .trim()
+                .eq_ignore_ascii_case("sha-256")
+                .then(|| digest.trim().trim_start_matches(':').trim_end_matches(':').to_string())
+        })
+}
+
+/// Encodes bytes as standard, padded base64.
+fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for group in bytes.chunks(3) {
+        let bits =
+            group.iter().fold(0u32, |bits, &b| bits << 8 | b as u32) << (8 * (3 - group.len()));
+        for i in 0..4 {
+            match i <= group.len() {
+                true => encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char),
+                false => encoded.push('='),
+            }
+        }
+    }
+    encoded
+}
+
+/// Returns a path for the temporary file of an upload to `file`, in the same directory so it
+/// can be renamed over `file`, and hidden so listings leave it out.
+fn temp_path(file: &Path) -> PathBuf {
+    let random = RandomState::new().build_hasher().finish();
+    file.with_file_name(format!(".{:016x}{}", random, TEMP_SUFFIX))
+}
+
+/// Renames a synced temporary file over `file`, keeping the permissions of a replaced file,
+/// and syncs the directory so the rename survives a crash.
+fn replace_file(temp: &Path, file: &Path) -> io::Result<()> {
+    if let Ok(metadata) = fs::metadata(file) {
+        fs::set_permissions(temp, metadata.permissions())?;
+    }
+    fs::rename(temp, file)?;
+    mime::rename_content_type(temp, file)?;
+    match file.parent() {
+        Some(parent) => File::open(parent)?.sync_all(),
+        None => Ok(()),
+    }
+}
+
+/// Deletes a temporary file that will not be renamed into place, with its stored content type.
+fn discard_temp_file(temp: &Path) {
+    let _ = fs::remove_file(temp);
+    let _ = mime::remove_content_type(temp);
+}
+
+/// Deletes the temporary files of uploads that never completed, e.g. because the server
+/// crashed while writing them, along with the sidecar files holding their content types.
+///
+/// It must run before the directory is served, since it cannot tell an abandoned temporary
+/// file from one being written. Symbolic links are not followed.
+///
+/// # Arguments
+///
+/// * `dir` - The public directory; a missing one has nothing to clean up.
+///
+/// # Returns
+///
+/// A `Result` containing the number of files removed.
+pub fn remove_stale_uploads(dir: &Path) -> Result<usize> {
+    if !dir.is_dir() {
+        return Ok(0);
+    }
+    let mut removed = 0;
+    for entry in fs::read_dir(dir).wrap_err_with(|| format!("Failed to list {}", dir.display()))? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let stale = is_temp_file(&name) || mime::sidecar_owner(&name).is_some_and(is_temp_file);
+        if file_type.is_dir() {
+            removed += remove_stale_uploads(&entry.path())?;
+        } else if file_type.is_file() && stale {
+            fs::remove_file(entry.path())
+                .wrap_err_with(|| format!("Failed to remove {}", entry.path().display()))?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Checks whether a file name is one [`temp_path`] produces.
+fn is_temp_file(name: &str) -> bool {
+    name.strip_prefix('.')
+        .and_then(|name| name.strip_suffix(TEMP_SUFFIX))
+        .is_some_and(|random| random.len() == 16 && random.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+/// Checks whether the request carries a `multipart/form-data` body.
+fn is_multipart(request: &HttpRequest) -> bool {
+    let content_type = request.headers.get(CONTENT_TYPE).map(String::as_str).unwrap_or_default();
+    parse_content_type(content_type).0 == CT_MULTIPART_FORM_DATA
+}
+
+/// Returns the content type worth storing with an upload.
+///
+/// `application/octet-stream` and `application/x-www-form-urlencoded` are what clients send when
+/// they know nothing better, so files uploaded with them are served by their extension instead.
+fn upload_content_type(content_type: Option<&String>) -> Option<&str> {
+    let content_type = content_type?.trim();
+    let media_type = parse_content_type(content_type).0;
+    match media_type.as_str() {
+        "" | CT_APPLICATION_OCTET_STREAM | CT_FORM_URLENCODED => None,
+        _ => Some(content_type),
+    }
+}
+
+/// The file parts of a multipart upload, each written to a temporary file that is only renamed
+/// over its target once the whole body has been parsed.
+#[derive(Default)]
+struct StagedFiles {
+    /// The temporary file of the part being written, the last one in `files`.
+    current: Option<File>,
+    /// The temporary files written so far, with their targets.
+    files:   Vec<(PathBuf, PathBuf)>,
+}
+
+impl StagedFiles {
+    /// Renames every staged file over its target, in the order the parts arrived, so the last
+    /// of several parts with the same file name wins.
+    ///
+    /// The locks of all targets are taken, in path order, and the request's preconditions are
+    /// checked again for each target before anything is renamed. A replaced target is kept as a
+    /// hard link until every rename succeeded, so a rename that fails puts back the targets
+    /// replaced before it and removes the ones it created.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The HTTP request, with the [`WriteLocks`] registered as state.
+    /// * `runtime` - The runtime the preconditions are checked on.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the number of files saved, or a `PreconditionFailed` error if a
+    /// target no longer meets the preconditions, in which case nothing is saved.
+    fn commit(self, request: &HttpRequest, runtime: &Handle) -> Result<usize> {
+        let targets =
+            self.files.iter().map(|(_, target)| target.as_path()).collect::<BTreeSet<_>>();
+        let _guards = request.state.get::<WriteLocks>().map(|locks| {
+            targets
+                .iter()
+                .map(|target| locks.lock_for(target).blocking_lock_owned())
+                .collect::<Vec<_>>()
+        });
+        if !targets.iter().all(|target| runtime.block_on(preconditions_pass(request, target))) {
+            self.discard();
+            return Err(ServerError::HttpError(HttpError::PreconditionFailed).into());
+        }
+        let saved = self.files.len();
+        let mut replaced = Vec::new();
+        let mut files = self.files.into_iter();
+        while let Some((temp, target)) = files.next() {
+            match replace_keeping_backup(&temp, &target) {
+                Ok(backup) => replaced.push((backup, target)),
+                Err(e) => {
+                    for (temp, _) in std::iter::once((temp, target.clone())).chain(files) {
+                        discard_temp_file(&temp);
+                    }
+                    for (backup, target) in replaced.into_iter().rev() {
+                        restore_file(backup.as_deref(), &target);
+                    }
+                    return Err(e).wrap_err_with(|| format!("Failed to save {}", target.display()));
+                },
+            }
+        }
+        for backup in replaced.into_iter().filter_map(|(backup, _)| backup) {
+            discard_temp_file(&backup);
+        }
+        Ok(saved)
+    }
+
+    /// Deletes every staged file, leaving the targets untouched.
+    fn discard(self) {
+        drop(self.current);
+        for (temp, _) in self.files {
+            discard_temp_file(&temp);
+        }
+    }
+}
+
+/// Renames a temporary file over `file` like [`replace_file`], keeping a hard link to the file
+/// it replaces, with its stored content type, so that it can be put back.
+///
+/// # Returns
+///
+/// A `Result` containing the path of the link, or `None` if there was no file to replace.
+fn replace_keeping_backup(temp: &Path, file: &Path) -> io::Result<Option<PathBuf>> {
+    let backup = temp_path(file);
+    let backup = match fs::hard_link(file, &backup) {
+        Ok(()) => {
+            if let Some(content_type) = mime::stored_content_type(file) {
+                mime::store_content_type(&backup, &content_type);
+            }
+            Some(backup)
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    if let Err(e) = replace_file(temp, file) {
+        if let Some(backup) = &backup {
+            discard_temp_file(backup);
+        }
+        return Err(e);
+    }
+    Ok(backup)
+}
+
+/// Puts back a file replaced by [`replace_keeping_backup`], or removes it if it had no backup,
+/// i.e. did not exist before.
+fn restore_file(backup: Option<&Path>, file: &Path) {
+    match backup {
+        Some(backup) =>
+            if replace_file(backup, file).is_err() {
+                discard_temp_file(backup);
+            },
+        None => {
+            let _ = fs::remove_file(file);
+            let _ = mime::remove_content_type(file);
+        },
+    }
+}
+
+/// Checks a request's preconditions against a file, e.g. each target of a multipart upload.
+async fn preconditions_pass(request: &HttpRequest, file: &Path) -> bool {
+    let current = file_validators(request, file).await.map(|(validators, ..)| validators);
+    conditional::evaluate(request, current.as_ref()) == Precondition::Proceed
+}
+
+/// Streams every file part of a multipart request into the public directory.
+///
+/// Each file is stored under its sanitized file name; parts without a file name (plain form
+/// fields) and parts whose name sanitizes to nothing are skipped. Every file is staged in a
+/// temporary file, and the targets are only replaced once the whole body has been parsed, so a
+/// request that fails midway, e.g. on a part over the size limit, leaves every target untouched.
+/// The request's preconditions apply to each target, so `If-None-Match: *` refuses the whole
+/// upload with a `PreconditionFailed` error if any of its files exists.
+///
+/// # Arguments
+///
+/// * `request` - The HTTP request.
+/// * `pub_dir` - The directory to save files to.
+/// * `symlinks` - The policy for symbolic links in `pub_dir`.
+/// * `limits` - The limits enforced on the multipart body.
+/// * `runtime` - The runtime a streamed body is read on, chunk by chunk.
+///
+/// # Returns
+///
+/// A `Result` containing the number of files saved or an error.
+fn save_multipart_files(
+    request: &mut HttpRequest,
+    pub_dir: &str,
+    symlinks: SymlinkPolicy,
+    limits: MultipartLimits,
+    runtime: &Handle,
+) -> Result<usize> {
+    let content_type = request.headers.get(CONTENT_TYPE).map(String::as_str).unwrap_or_default();
+    let boundary = multipart_boundary(content_type)?;
+    let mut parser = MultipartParser::new(&boundary, limits);
+    let mut stream = request.body_stream.take();
+    let mut body = Some(Bytes::from(std::mem::take(&mut request.body)));
+    let mut staged = StagedFiles::default();
+    let result = loop {
+        let chunk = match stream.as_mut() {
+            Some(stream) => runtime.block_on(stream.chunk()),
+            None => Ok(body.take()),
+        };
+        let chunk = match chunk {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break parser.finish(),
+            Err(e) => break Err(e),
+        };
+        if let Err(e) = stage_multipart_files(
+            request,
+            &mut parser,
+            &chunk,
+            pub_dir,
+            symlinks,
+            runtime,
+            &mut staged,
+        ) {
+            break Err(e);
+        }
+    };
+    match result {
+        Ok(()) => staged.commit(request, runtime),
+        Err(e) => {
+            staged.discard();
+            Err(e)
+        },
+    }
+}
+
+/// Feeds a chunk of a body to the multipart parser and writes file parts to their temporary
+/// files as they are produced.
+fn stage_multipart_files(
+    request: &HttpRequest,
+    parser: &mut MultipartParser,
+    chunk: &[u8],
+    pub_dir: &str,
+    symlinks: SymlinkPolicy,
+    runtime: &Handle,
+    staged: &mut StagedFiles,
+) -> Result<()> {
+    for event in parser.push(chunk)? {
+        match event {
+            MultipartEvent::PartStart(headers) => {
+                if let Some(filename) = headers.filename.as_deref().and_then(sanitize_filename) {
+                    let target = resolve_path(Path::new(pub_dir), &filename, symlinks)?;
+                    if !webdav::write_permitted(request, &target, false) {
+                        return Err(ServerError::HttpError(HttpError::Locked).into());
+                    }
+                    if !runtime.block_on(preconditions_pass(request, &target)) {
+                        return Err(ServerError::HttpError(HttpError::PreconditionFailed).into());
+                    }
+                    let temp = temp_path(&target);
+                    let file = File::options().write(true).create_new(true).open(&temp)?;
+                    staged.files.push((temp.clone(), target));
+                    staged.current = Some(file);
+                    if let Some(content_type) = upload_content_type(headers.content_type.as_ref()) {
+                        mime::store_content_type(&temp, content_type);
+                    }
+                }
+            },
+            MultipartEvent::PartData(data) =>
+                if let Some(file) = staged.current.as_mut() {
+                    file.write_all(&data)?;
+                },
+            MultipartEvent::PartEnd =>
+                if let Some(file) = staged.current.take() {
+                    file.sync_all()?;
+                },
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::{
+        autoindex::AutoIndex,
+        body::CHUNK_SIZE,
+        conditional::EntityTag,
+        http::{CONTENT_ENCODING, CONTENT_RANGE, ETAG, LAST_MODIFIED, LOCATION, VARY},
+        router::make_router,
+    };
+
+    const SECRET: &[u8] = b"top secret";
+
+    /// Paths under `/files` that try to reach `secret.txt` or another file outside `public/`.
+    const TRAVERSAL_PAYLOADS: [&str; 15] = [
+        "/files/../secret.txt",
+        "/files/sub/../../secret.txt",
+        "/files/..%2fsecret.txt",
+        "/files/%2e%2e/secret.txt",
+        "/files/%2e%2e%2fsecret.txt",
+        "/files/%2E%2E%2Fsecret.txt",
+        "/files/sub/%2e%2e/%2e%2e/secret.txt",
+        "/files/..%5csecret.txt",
+        "/files/%2fetc%2fpasswd",
+        "/files/%2f..%2fsecret.txt",
+        "/files/ok.txt%00.png",
+        "/files/escape",
+        "/files/escape-dir/secret.txt",
+        "/files/dangling",
+        "/files/%252e%252e/secret.txt",
+    ];
+
+    /// Creates `public/` with a file, a subdirectory and links in and out of it, next to a
+    /// secret file that must never be served.
+    fn fixture() -> (TempDir, PathBuf) {
+        let base = TempDir::new("traversal").unwrap();
+        let public = base.path().join("public");
+        fs::create_dir_all(public.join("sub")).unwrap();
+        fs::write(public.join("ok.txt"), b"ok").unwrap();
+        fs::write(public.join("sub/inner.txt"), b"inner").unwrap();
+        fs::write(base.path().join("secret.txt"), SECRET).unwrap();
+        symlink(public.join("sub/inner.txt"), public.join("inside")).unwrap();
+        symlink(base.path().join("secret.txt"), public.join("escape")).unwrap();
+        symlink(base.path(), public.join("escape-dir")).unwrap();
+        symlink(base.path().join("missing"), public.join("dangling")).unwrap();
+        (base, public)
+    }
+
+    async fn get(router: &Router, target: &str) -> HttpResponse {
+        let request = HttpRequest::from_string(&format!("GET {} HTTP/1.1\r\n\r\n", target));
+        router.resolve(request.unwrap()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn traversal_payloads_are_rejected() {
+        let (_base, public) = fixture();
+        let router = make_router(public.to_str().unwrap()).unwrap();
+        for payload in TRAVERSAL_PAYLOADS {
+            let response = get(&router, payload).await;
+            assert!(
+                matches!(response.status_code.as_u16(), 400 | 403 | 404),
+                "{} answered {:?}",
+                payload,
+                response.status_code
+            );
+            assert_ne!(response.body, SECRET, "{} leaked the secret", payload);
+        }
+        assert_eq!(get(&router, "/files/ok.txt").await.body, b"ok");
+        assert_eq!(get(&router, "/files/./sub/inner.txt").await.body, b"inner");
+        assert_eq!(get(&router, "/files/inside").await.body, b"inner");
+    }
+
+    #[tokio::test]
+    async fn uploads_cannot_escape() {
+        let (base, public) = fixture();
+        let router = make_router(public.to_str().unwrap()).unwrap();
+        // Captures are percent-decoded, so every write method must reject encoded traversal too.
+        for (method, target) in ["POST", "PUT", "DELETE"]
+            .into_iter()
+            .flat_map(|method| TRAVERSAL_PAYLOADS.map(|target| (method, target)))
+        {
+            let request = HttpRequest::from_string(&format!(
+                "{} {} HTTP/1.1\r\nContent-Length: 5\r\n\r\npwned",
+                method, target
+            ))
+            .unwrap();
+            let response = router.resolve(request).await.unwrap();
+            assert!(
+                response.status_code.as_u16() >= 400,
+                "{} {} answered {:?}",
+                method,
+                target,
+                response.status_code
+            );
+        }
+        assert_eq!(fs::read(base.path().join("secret.txt")).unwrap(), SECRET);
+    }
+
+    #[tokio::test]
+    async fn content_types() {
+        let (_base, public) = fixture();
+        let mut router = make_router(public.to_str().unwrap()).unwrap();
+        let content_type = |response: HttpResponse| response.headers[CONTENT_TYPE].clone();
+        assert_eq!(content_type(get(&router, "/files/ok.txt").await), "text/plain; charset=utf-8");
+
+        let uploads = [
+            ("photo", "image/png"),
+            ("notes.txt", CT_APPLICATION_OCTET_STREAM),
+            ("page.html", CT_FORM_URLENCODED),
+        ];
+        for (name, content_type) in uploads {
+            let request = HttpRequest::from_string(&format!(
+                "POST /files/{} HTTP/1.1\r\nContent-Type: {}\r\nContent-Length: 2\r\n\r\nhi",
+                name, content_type
+            ))
+            .unwrap();
+            assert_eq!(router.resolve(request).await.unwrap().status_code, StatusCode::CREATED);
+        }
+        let body = "--b\r\nContent-Disposition: form-data; name=\"f\"; \
+                    filename=\"rows\"\r\nContent-Type: text/csv\r\n\r\na,b\r\n--b--\r\n";
+        let request = HttpRequest::from_string(&format!(
+            "POST /files/ HTTP/1.1\r\nContent-Type: multipart/form-data; \
+             boundary=b\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        ))
+        .unwrap();
+        assert_eq!(router.resolve(request).await.unwrap().status_code, StatusCode::CREATED);
+        assert_eq!(content_type(get(&router, "/files/photo").await), "image/png");
+        assert_eq!(content_type(get(&router, "/files/rows").await), "text/csv; charset=utf-8");
+        let request =
+            HttpRequest::from_string("PATCH /files/photo HTTP/1.1\r\nContent-Length: 1\r\n\r\n!");
+        let response = router.resolve(request.unwrap()).await.unwrap();
+        assert_eq!(response.status_code, StatusCode::NO_CONTENT);
+        assert_eq!(content_type(get(&router, "/files/photo").await), "image/png");
+
+        // Sidecar files cannot be written, read or deleted, so they cannot retype another file.
+        fs::write(public.join("plain.txt"), b"<p>hi</p>").unwrap();
+        for method in ["PUT", "POST", "PATCH", "DELETE", "GET"] {
+            for target in ["/files/.plain.txt.content-type", "/files/.photo.content-type"] {
+                let request = HttpRequest::from_string(&format!(
+                    "{} {} HTTP/1.1\r\nContent-Length: 9\r\n\r\ntext/html",
+                    method, target
+                ));
+                let response = router.resolve(request.unwrap()).await.unwrap();
+                assert_eq!(response.status_code, StatusCode::FORBIDDEN, "{} {}", method, target);
+            }
+        }
+        assert!(!public.join(".plain.txt.content-type").exists());
+        assert_eq!(
+            content_type(get(&router, "/files/plain.txt").await),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(content_type(get(&router, "/files/photo").await), "image/png");
+        let temp = "/files/.0123456789abcdef.upload-tmp";
+        assert_eq!(get(&router, temp).await.status_code, StatusCode::FORBIDDEN);
+        assert_eq!(
+            content_type(get(&router, "/files/notes.txt").await),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(
+            content_type(get(&router, "/files/page.html").await),
+            "text/html; charset=utf-8"
+        );
+
+        let mut types = MimeTypes::new();
+        types.override_type("sub", "txt", "text/markdown");
+        router.state(types);
+        assert_eq!(
+            content_type(get(&router, "/files/sub/inner.txt").await),
+            "text/markdown; charset=utf-8"
+        );
+        assert_eq!(content_type(get(&router, "/files/ok.txt").await), "text/plain; charset=utf-8");
+    }
+
+    #[tokio::test]
+    async fn conditional_requests() {
+        let (_base, public) = fixture();
+        let mut router = make_router(public.to_str().unwrap()).unwrap();
+        let send = |request: String| {
+            let request = HttpRequest::from_string(&request).unwrap();
+            router.resolve(request)
+        };
+        let response = send("GET /files/ok.txt HTTP/1.1\r\n\r\n".into()).await.unwrap();
+        let etag = response.headers[ETAG].clone();
+        let last_modified = response.headers[LAST_MODIFIED].clone();
+        assert!(etag.starts_with('"'));
+
+        let response =
+            send(format!("GET /files/ok.txt HTTP/1.1\r\nIf-None-Match: {}\r\n\r\n", etag));
+        let response = response.await.unwrap();
+        assert_eq!(response.status_code, StatusCode::NOT_MODIFIED);
+        assert_eq!(response.body, b"");
+        assert_eq!(response.headers[ETAG], etag);
+        let response = send(format!(
+            "GET /files/ok.txt HTTP/1.1\r\nIf-Modified-Since: {}\r\n\r\n",
+            last_modified
+        ));
+        assert_eq!(response.await.unwrap().status_code, StatusCode::NOT_MODIFIED);
+
+        // The default tags are strong, so they guard writes; each write changes the tag.
+        let update = |condition: &str| {
+            format!("POST /files/ok.txt HTTP/1.1\r\n{}\r\nContent-Length: 3\r\n\r\nnew", condition)
+        };
+        let response = send(update("If-None-Match: *")).await.unwrap();
+        assert_eq!(response.status_code, StatusCode::PRECONDITION_FAILED);
+        let response = send(update("If-Unmodified-Since: Thu, 01 Jan 1970 00:00:00 GMT"));
+        assert_eq!(response.await.unwrap().status_code, StatusCode::PRECONDITION_FAILED);
+        assert_eq!(fs::read(public.join("ok.txt")).unwrap(), b"ok");
+        let response = send(update(&format!("If-Match: {}", etag))).await.unwrap();
+        assert_eq!(response.status_code, StatusCode::NO_CONTENT);
+        assert_eq!(fs::read(public.join("ok.txt")).unwrap(), b"new");
+        let response = send(update(&format!("If-Match: {}", etag))).await.unwrap();
+        assert_eq!(response.status_code, StatusCode::PRECONDITION_FAILED);
+        fs::write(public.join("ok.txt"), b"ok").unwrap();
+
+        router.state(ETagMode::ContentHash);
+        let send = |request: String| {
+            let request = HttpRequest::from_string(&request).unwrap();
+            router.resolve(request)
+        };
+        let strong =
+            send("GET /files/ok.txt HTTP/1.1\r\n\r\n".into()).await.unwrap().headers[ETAG].clone();
+        assert_eq!(strong, EntityTag::from_content(b"ok").to_string());
+        let response = send(update(&format!("If-Match: {}", strong))).await.unwrap();
+        assert_eq!(response.status_code, StatusCode::NO_CONTENT);
+        let response = send(update(&format!("If-Match: {}", strong))).await.unwrap();
+        assert_eq!(response.status_code, StatusCode::PRECONDITION_FAILED);
+        let response =
+            send(format!("GET /files/ok.txt HTTP/1.1\r\nIf-None-Match: {}\r\n\r\n", strong));
+        assert_eq!(response.await.unwrap().body, b"new");
+        let response = send(
+            "POST /files/fresh.txt HTTP/1.1\r\nIf-None-Match: *\r\nContent-Length: 1\r\n\r\nx"
+                .into(),
+        );
+        assert_eq!(response.await.unwrap().status_code, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn range_requests() {
+        let (_base, public) = fixture();
+        fs::write(public.join("digits.txt"), b"0123456789").unwrap();
+        let mut router = make_router(public.to_str().unwrap()).unwrap();
+        let get_range = |headers: &str| {
+            let request = format!("GET /files/digits.txt HTTP/1.1\r\n{}\r\n", headers);
+            router.resolve(HttpRequest::from_string(&request).unwrap())
+        };
+
+        let response = get_range("").await.unwrap();
+        assert_eq!(response.status_code, StatusCode::OK);
+        assert_eq!(response.headers[ACCEPT_RANGES], "bytes");
+        // Whole files are still compressed, without ranges, which count uncompressed bytes.
+        let response = get_range("Accept-Encoding: gzip\r\n").await.unwrap();
+        assert_eq!(response.headers[CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers[VARY], "Accept-Encoding");
+        assert!(!response.headers.contains_key(ACCEPT_RANGES));
+        let response = get_range("Accept-Encoding: gzip\r\nRange: bytes=2-4\r\n").await.unwrap();
+        assert_eq!(response.status_code, StatusCode::PARTIAL_CONTENT);
+        assert!(!response.headers.contains_key(CONTENT_ENCODING));
+        assert_eq!(response.body, b"234");
+        let response = get_range("Range: bytes=2-4\r\n").await.unwrap();
+        assert_eq!(response.status_code, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.body, b"234");
+        assert_eq!(response.headers[CONTENT_RANGE], "bytes 2-4/10");
+        assert_eq!(response.headers[CONTENT_TYPE], "text/plain; charset=utf-8");
+        let response = get_range("Range: bytes=-2, 0-0\r\n").await.unwrap();
+        let body = String::from_utf8(response.body).unwrap();
+        assert!(response.headers[CONTENT_TYPE].starts_with("multipart/byteranges; boundary="));
+        assert!(body.contains("Content-Range: bytes 0-0/10\r\n\r\n0\r\n"));
+        assert!(body.contains("Content-Range: bytes 8-9/10\r\n\r\n89\r\n"));
+        let response = get_range("Range: bytes=10-\r\n").await.unwrap();
+        assert_eq!(response.status_code, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers[CONTENT_RANGE], "bytes */10");
+
+        // Both the default tag and a matching date satisfy If-Range, a weak tag never does.
+        let response = get_range("").await.unwrap();
+        let (etag, last_modified) = (&response.headers[ETAG], &response.headers[LAST_MODIFIED]);
+        let response = get_range(&format!("Range: bytes=0-0\r\nIf-Range: {}\r\n", etag));
+        assert_eq!(response.await.unwrap().body, b"0");
+        let weak = format!("W/{}", etag);
+        let response = get_range(&format!("Range: bytes=0-0\r\nIf-Range: {}\r\n", weak));
+        assert_eq!(response.await.unwrap().body, b"0123456789");
+        let response = get_range(&format!("Range: bytes=0-0\r\nIf-Range: {}\r\n", last_modified));
+        assert_eq!(response.await.unwrap().body, b"0");
+
+        router.state(ETagMode::ContentHash);
+        let get_range = |headers: &str| {
+            let request = format!("GET /files/digits.txt HTTP/1.1\r\n{}\r\n", headers);
+            router.resolve(HttpRequest::from_string(&request).unwrap())
+        };
+        let strong = EntityTag::from_content(b"0123456789");
+        let response = get_range(&format!("Range: bytes=9-\r\nIf-Range: {}\r\n", strong));
+        assert_eq!(response.await.unwrap().body, b"9");
+        let stale = EntityTag::from_content(b"old");
+        let response = get_range(&format!("Range: bytes=9-\r\nIf-Range: {}\r\n", stale));
+        assert_eq!(response.await.unwrap().status_code, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn directory_listings() {
+        let (_base, public) = fixture();
+        fs::write(public.join(".hidden"), b"").unwrap();
+        let mut router = make_router(public.to_str().unwrap()).unwrap();
+        assert_eq!(get(&router, "/files/sub/").await.status_code, StatusCode::NOT_FOUND);
+
+        router.state(AutoIndex::new().enable("sub").clone());
+        assert_eq!(get(&router, "/files/").await.status_code, StatusCode::NOT_FOUND);
+        let response = get(&router, "/files/sub").await;
+        assert_eq!(response.status_code, StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(response.headers[LOCATION], "sub/");
+        let response = get(&router, "/files/sub/").await;
+        assert_eq!(response.status_code, StatusCode::OK);
+        let html = String::from_utf8(response.body).unwrap();
+        assert!(html.contains("<title>Index of /sub/</title>"));
+        assert!(html.contains("<a href=\"inner.txt\">inner.txt</a></td><td>5</td>"));
+
+        router.state(AutoIndex::new().enable("").clone());
+        let request = HttpRequest::from_string(
+            "GET /files/?sort=size&order=desc HTTP/1.1\r\nAccept: application/json\r\n\r\n",
+        )
+        .unwrap();
+        let response = router.resolve(request).await.unwrap();
+        assert_eq!(response.headers[CONTENT_TYPE], "application/json");
+        let entries: Vec<serde_json::Value> = serde_json::from_slice(&response.body).unwrap();
+        let names = entries.iter().map(|entry| entry["name"].as_str().unwrap()).collect::<Vec<_>>();
+        // Hidden files and links out of the public directory are not listed.
+        assert_eq!(names, ["sub", "inside", "ok.txt"]);
+        assert_eq!(entries[1]["size"], 5);
+        let request = HttpRequest::from_string("GET /files/?sort=color HTTP/1.1\r\n\r\n").unwrap();
+        let response = router.resolve(request).await.unwrap();
+        assert_eq!(response.status_code, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn static_site() {
+        let (_base, public) = fixture();
+        fs::write(public.join("index.html"), b"<h1>home</h1>").unwrap();
+        fs::create_dir_all(public.join("docs/empty")).unwrap();
+        fs::write(public.join("docs/index.html"), b"docs").unwrap();
+        let mut router = make_router(public.to_str().unwrap()).unwrap();
+        assert_eq!(get(&router, "/").await.body, b"");
+        assert_eq!(get(&router, "/ok.txt").await.status_code, StatusCode::NOT_FOUND);
+
+        router.state(StaticSite::new());
+        let response = get(&router, "/").await;
+        assert_eq!(response.body, b"<h1>home</h1>");
+        assert_eq!(response.headers[CONTENT_TYPE], "text/html; charset=utf-8");
+        assert_eq!(get(&router, "/ok.txt").await.body, b"ok");
+        assert_eq!(get(&router, "/docs/").await.body, b"docs");
+        let response = get(&router, "/docs?lang=en").await;
+        assert_eq!(response.status_code, StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(response.headers[LOCATION], "docs/?lang=en");
+        assert_eq!(get(&router, "/docs/empty/").await.status_code, StatusCode::NOT_FOUND);
+        assert_eq!(get(&router, "/app/settings").await.status_code, StatusCode::NOT_FOUND);
+        assert_eq!(get(&router, "/%2e%2e/secret.txt").await.status_code, StatusCode::BAD_REQUEST);
+        assert_eq!(get(&router, "/echo/hi").await.body, b"hi");
+
+        router.state(StaticSite::new().fallback("index.html").clone());
+        assert_eq!(get(&router, "/app/settings").await.body, b"<h1>home</h1>");
+        assert_eq!(get(&router, "/docs/empty/").await.body, b"<h1>home</h1>");
+        let request = HttpRequest::from_string("DELETE /app HTTP/1.1\r\n\r\n").unwrap();
+        let response = router.resolve(request).await.unwrap();
+        assert_eq!(response.status_code, StatusCode::NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn put_and_delete() {
+        let (_base, public) = fixture();
+        let mut router = make_router(public.to_str().unwrap()).unwrap();
+        let send = |method: &str, target: &str, headers: &str, body: &str| {
+            let request = HttpRequest::from_string(&format!(
+                "{} {} HTTP/1.1\r\n{}Content-Length: {}\r\n\r\n{}",
+                method,
+                target,
+                headers,
+                body.len(),
+                body
+            ))
+            .unwrap();
+            router.resolve(request)
+        };
+        let status = |response: Result<HttpResponse>| response.unwrap().status_code;
+
+        assert_eq!(status(send("PUT", "/files/new.txt", "", "one").await), StatusCode::CREATED);
+        assert_eq!(status(send("PUT", "/files/new.txt", "", "two").await), StatusCode::NO_CONTENT);
+        assert_eq!(fs::read(public.join("new.txt")).unwrap(), b"two");
+        let create_only = "If-None-Match: *\r\n";
+        let response = send("PUT", "/files/new.txt", create_only, "three").await;
+        assert_eq!(status(response), StatusCode::PRECONDITION_FAILED);
+        let response = send("PUT", "/files/other.txt", create_only, "three").await;
+        assert_eq!(status(response), StatusCode::CREATED);
+        assert_eq!(status(send("PUT", "/files/sub", "", "x").await), StatusCode::CONFLICT);
+        // A directory, even the public one, is not written over, nor is anything put next to it.
+        assert_eq!(status(send("POST", "/files/sub", "", "x").await), StatusCode::CONFLICT);
+        assert_eq!(status(send("POST", "/files/", "", "x").await), StatusCode::CONFLICT);
+        assert!(public.join("sub/inner.txt").exists());
+        for dir in [&public, &public.join(".."), &public.join("sub")] {
+            let names = fs::read_dir(dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect::<Vec<_>>();
+            assert!(!names.iter().any(|name| is_temp_file(name)), "{:?}", names);
+        }
+
+        assert_eq!(status(send("PUT", "/files/a/b/c.txt", "", "deep").await), StatusCode::CONFLICT);
+        assert_eq!(
+            status(send("POST", "/files/a/b/c.txt", "", "deep").await),
+            StatusCode::CONFLICT
+        );
+        router.state(*UploadOptions::new().create_parents(true));
+        let send = |method: &str, target: &str, body: &str| {
+            let request = format!(
+                "{} {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                method,
+                target,
+                body.len(),
+                body
+            );
+            router.resolve(HttpRequest::from_string(&request).unwrap())
+        };
+        assert_eq!(status(send("PUT", "/files/a/b/c.txt", "deep").await), StatusCode::CREATED);
+        assert_eq!(fs::read(public.join("a/b/c.txt")).unwrap(), b"deep");
+
+        assert_eq!(status(send("DELETE", "/files/new.txt", "").await), StatusCode::NO_CONTENT);
+        assert!(!public.join("new.txt").exists());
+        assert_eq!(status(send("DELETE", "/files/new.txt", "").await), StatusCode::NOT_FOUND);
+        assert_eq!(status(send("DELETE", "/files/sub", "").await), StatusCode::NOT_FOUND);
+        assert_eq!(status(send("DELETE", "/files/escape", "").await), StatusCode::FORBIDDEN);
+        assert!(public.join("sub/inner.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn patch() {
+        let (_base, public) = fixture();
+        fs::write(public.join("digits.txt"), b"0123456789").unwrap();
+        let router = make_router(public.to_str().unwrap()).unwrap();
+        let send = |target: &str, headers: &str, body: &str| {
+            let request = HttpRequest::from_string(&format!(
+                "PATCH {} HTTP/1.1\r\n{}Content-Length: {}\r\n\r\n{}",
+                target,
+                headers,
+                body.len(),
+                body
+            ))
+            .unwrap();
+            router.resolve(request)
+        };
+        let status = |response: Result<HttpResponse>| response.unwrap().status_code;
+        let digits = || fs::read(public.join("digits.txt")).unwrap();
+
+        let response = send("/files/digits.txt", "Content-Range: bytes 2-4/*\r\n", "abc").await;
+        assert_eq!(status(response), StatusCode::NO_CONTENT);
+        assert_eq!(digits(), b"01abc56789");
+        let response = send("/files/digits.txt", "Content-Range: bytes 8-11/12\r\n", "wxyz").await;
+        assert_eq!(status(response), StatusCode::NO_CONTENT);
+        assert_eq!(digits(), b"01abc567wxyz");
+        assert_eq!(status(send("/files/digits.txt", "", "!").await), StatusCode::NO_CONTENT);
+        assert_eq!(digits(), b"01abc567wxyz!");
+
+        // The body must fill the range, which may not leave a hole after the file.
+        let response = send("/files/digits.txt", "Content-Range: bytes 0-4/*\r\n", "ab").await;
+        assert_eq!(status(response), StatusCode::BAD_REQUEST);
+        let response = send("/files/digits.txt", "Content-Range: items 0-1/*\r\n", "ab").await;
+        assert_eq!(status(response), StatusCode::BAD_REQUEST);
+        let response = send("/files/digits.txt", "Content-Range: bytes 20-21/*\r\n", "ab").await;
+        let response = response.unwrap();
+        assert_eq!(response.status_code, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers[CONTENT_RANGE], "bytes */13");
+        let response = send("/files/digits.txt", "If-Match: \"stale\"\r\n", "?").await;
+        assert_eq!(status(response), StatusCode::PRECONDITION_FAILED);
+        assert_eq!(digits(), b"01abc567wxyz!");
+        assert_eq!(status(send("/files/missing.txt", "", "x").await), StatusCode::NOT_FOUND);
+        assert_eq!(status(send("/files/sub", "", "x").await), StatusCode::CONFLICT);
+        assert_eq!(status(send("/files/", "", "x").await), StatusCode::CONFLICT);
+        assert!(!public.join("missing.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn concurrent_writes() {
+        let (_base, public) = fixture();
+        let router = make_router(public.to_str().unwrap()).unwrap();
+        let puts = (0..8).map(|i| {
+            let request = format!(
+                "PUT /files/race.txt HTTP/1.1\r\nIf-None-Match: *\r\nContent-Length: 1\r\n\r\n{}",
+                i
+            );
+            router.resolve(HttpRequest::from_string(&request).unwrap())
+        });
+        let statuses = futures::future::join_all(puts).await;
+        let statuses = statuses.into_iter().map(|response| response.unwrap().status_code);
+        let statuses = statuses.collect::<Vec<_>>();
+        // The first writer creates the file, every other one then finds it and is refused.
+        let created = statuses.iter().position(|status| *status == StatusCode::CREATED).unwrap();
+        let refused = statuses.iter().filter(|status| **status == StatusCode::PRECONDITION_FAILED);
+        assert_eq!(refused.count(), statuses.len() - 1);
+        assert_eq!(fs::read(public.join("race.txt")).unwrap(), created.to_string().as_bytes());
+    }
+
+    #[tokio::test]
+    async fn atomic_uploads() {
+        let (_base, public) = fixture();
+        let router = make_router(public.to_str().unwrap()).unwrap();
+        let put = |body: String| {
+            let request = format!(
+                "PUT /files/ok.txt HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            router.resolve(HttpRequest::from_string(&request).unwrap())
+        };
+        fs::set_permissions(public.join("ok.txt"), fs::Permissions::from_mode(0o600)).unwrap();
+        let bodies = (0..8).map(|i| i.to_string().repeat(4096)).collect::<Vec<_>>();
+        for response in futures::future::join_all(bodies.iter().cloned().map(put)).await {
+            assert_eq!(response.unwrap().status_code, StatusCode::NO_CONTENT);
+        }
+        let content = String::from_utf8(fs::read(public.join("ok.txt")).unwrap()).unwrap();
+        assert!(bodies.contains(&content), "interleaved writes");
+        let mode = fs::metadata(public.join("ok.txt")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // The first part is complete, but nothing is saved when a later one fails.
+        let body = "--b\r\nContent-Disposition: form-data; name=\"f\"; \
+                    filename=\"ok.txt\"\r\n\r\nwhole\r\n--b\r\nContent-Disposition: form-data; \
+                    name=\"f\"; filename=\"next.txt\"\r\n\r\ntrunc";
+        let request = HttpRequest::from_string(&format!(
+            "POST /files/ HTTP/1.1\r\nContent-Type: multipart/form-data; \
+             boundary=b\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        ))
+        .unwrap();
+        assert!(router.resolve(request).await.unwrap().status_code.as_u16() >= 400);
+        assert_eq!(fs::read_to_string(public.join("ok.txt")).unwrap(), content);
+        assert!(!public.join("next.txt").exists());
+
+        let multipart = |headers: &str, files: &[&str]| {
+            let mut body = String::new();
+            for name in files {
+                body.push_str(&format!(
+                    "--b\r\nContent-Disposition: form-data; name=\"f\"; \
+                     filename=\"{}\"\r\n\r\nnew\r\n",
+                    name
+                ));
+            }
+            body.push_str("--b--\r\n");
+            let request = format!(
+                "POST /files/ HTTP/1.1\r\n{}Content-Type: multipart/form-data; \
+                 boundary=b\r\nContent-Length: {}\r\n\r\n{}",
+                headers,
+                body.len(),
+                body
+            );
+            router.resolve(HttpRequest::from_string(&request).unwrap())
+        };
+        // Preconditions apply to every file, so one existing file refuses a create-only upload.
+        let response = multipart("If-None-Match: *\r\n", &["fresh.txt", "ok.txt"]).await;
+        assert_eq!(response.unwrap().status_code, StatusCode::PRECONDITION_FAILED);
+        assert!(!public.join("fresh.txt").exists());
+        assert_eq!(fs::read_to_string(public.join("ok.txt")).unwrap(), content);
+        // A rename that fails, here over a directory, puts back the files renamed before it.
+        let response = multipart("", &["fresh.txt", "ok.txt", "sub"]).await;
+        assert_eq!(response.unwrap().status_code, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!public.join("fresh.txt").exists());
+        assert_eq!(fs::read_to_string(public.join("ok.txt")).unwrap(), content);
+        assert_eq!(
+            fs::metadata(public.join("ok.txt")).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert!(public.join("sub/inner.txt").exists());
+        let response = multipart("", &["fresh.txt", "ok.txt"]).await;
+        assert_eq!(response.unwrap().status_code, StatusCode::CREATED);
+        assert_eq!(fs::read(public.join("ok.txt")).unwrap(), b"new");
+        let names = fs::read_dir(&public)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        assert!(!names.iter().any(|name| is_temp_file(name)), "{:?}", names);
+    }
+
+    #[tokio::test]
+    async fn streamed_uploads() {
+        let (_base, public) = fixture();
+        let router = make_router(public.to_str().unwrap()).unwrap();
+        let content = (0..CHUNK_SIZE * 3 + 5).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let digest = base64_encode(&Sha256::digest(&content));
+        let send = |head: String, body: &[u8]| {
+            let mut request = HttpRequest::from_head(head.as_bytes()).unwrap();
+            let (prefix, rest) = body.split_at(10);
+            let reader = Box::new(io::Cursor::new(rest.to_vec()));
+            let stream = BodyStream::new(Bytes::copy_from_slice(prefix), reader, body.len() as u64);
+            request.body_stream = Some(stream);
+            router.resolve(request)
+        };
+        let put =
+            |digest: &str| format!("PUT /files/big.bin HTTP/1.1\r\nContent-Digest: {}", digest);
+
+        let response = send(put(&format!("sha-256=:{}:", digest)), &content).await.unwrap();
+        assert_eq!(response.status_code, StatusCode::CREATED);
+        assert_eq!(fs::read(public.join("big.bin")).unwrap(), content);
+        let response = send(put("sha-256=:AAAA:, sha-512=:AAAA:"), b"0123456789 other").await;
+        assert_eq!(response.unwrap().status_code, StatusCode::BAD_REQUEST);
+        assert_eq!(fs::read(public.join("big.bin")).unwrap(), content);
+
+        let mut body = b"--b\r\nContent-Disposition: form-data; name=\"f\"; \
+                         filename=\"part.bin\"\r\n\r\n"
+            .to_vec();
+        body.extend_from_slice(&content);
+        body.extend_from_slice(b"\r\n--b--\r\n");
+        let head = "POST /files/ HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=b";
+        let response = send(head.to_string(), &body).await.unwrap();
+        assert_eq!(response.status_code, StatusCode::CREATED);
+        assert_eq!(fs::read(public.join("part.bin")).unwrap(), content);
+
+        assert_eq!(base64_encode(b"abcd"), "YWJjZA==");
+        assert_eq!(base64_encode(b"abcde"), "YWJjZGU=");
+        assert_eq!(base64_encode(b"abcdef"), "YWJjZGVm");
+    }
+
+    #[test]
+    fn stale_uploads_are_removed() {
+        let (_base, public) = fixture();
+        let stale = [
+            public.join(".0123456789abcdef.upload-tmp"),
+            public.join("..0123456789abcdef.upload-tmp.content-type"),
+            temp_path(&public.join("sub/x")),
+        ];
+        let kept = [
+            public.join(".hidden"),
+            public.join("notes.upload-tmp"),
+            public.join(".notes.txt.content-type"),
+        ];
+        for file in stale.iter().chain(&kept) {
+            fs::write(file, b"partial").unwrap();
+        }
+        assert_eq!(remove_stale_uploads(&public).unwrap(), 3);
+        assert!(stale.iter().all(|file| !file.exists()));
+        assert!(kept.iter().all(|file| file.exists()));
+        assert!(public.join("sub/inner.txt").exists());
+        assert_eq!(remove_stale_uploads(&public.join("missing")).unwrap(), 0);
+    }
+
+    #[test]
+    fn symlink_policies() {
+        let (_base, public) = fixture();
+        let resolve = |path, symlinks| resolve_path(&public, path, symlinks);
+        assert!(resolve("inside", SymlinkPolicy::WithinRoot).is_ok());
+        assert!(resolve("escape", SymlinkPolicy::WithinRoot).is_err());
+        assert!(resolve("inside", SymlinkPolicy::Deny).is_err());
+        assert!(resolve("ok.txt", SymlinkPolicy::Deny).is_ok());
+        assert!(resolve("escape", SymlinkPolicy::Follow).is_ok());
+        assert!(resolve(".ok.txt.content-type", SymlinkPolicy::Follow).is_err());
+        assert!(resolve("sub/.0123456789abcdef.upload-tmp", SymlinkPolicy::Follow).is_err());
+        assert!(resolve(".content-type", SymlinkPolicy::Follow).is_ok());
+        assert_eq!(
+            resolve("sub/./new.txt", SymlinkPolicy::Deny).unwrap(),
+            public.join("sub/new.txt")
+        );
+    }
+}
@@ -0,0 +1,484 @@
+use std::collections::HashMap;
+
+use eyre::Result;
+
+use crate::error::{HttpError, ServerError};
+
+pub const CT_FORM_URLENCODED: &str = "application/x-www-form-urlencoded";
+pub const CT_MULTIPART_FORM_DATA: &str = "multipart/form-data";
+
+const CONTENT_DISPOSITION: &str = "content-disposition";
+const CONTENT_TYPE: &str = "content-type";
+const HEADERS_END: &[u8] = b"\r\n\r\n";
+
+pub type FormFields = Vec<(String, String)>;
+
+/// Decodes a percent-encoded string.
+///
+/// Invalid escapes are kept verbatim and invalid UTF-8 is replaced lossily.
+///
+/// # Arguments
+///
+/// * `input` - The encoded string.
+/// * `plus_as_space` - Whether `+` should decode to a space, as in form bodies and query strings.
+pub fn percent_decode(input: &str, plus_as_space: bool) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                match (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                    (Some(hi), Some(lo)) => {
+                        decoded.push(hi << 4 | lo);
+                        i += 3;
+                        continue;
+                    },
+                    _ => decoded.push(b'%'),
+                }
+            },
+            b'+' if plus_as_space => decoded.push(b' '),
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+fn hex_value(b: u8) -> Option<u8> { (b as char).to_digit(16).map(|d| d as u8) }
+
+/// Parses an `application/x-www-form-urlencoded` body.
+///
+/// Repeated names are preserved in order. Pairs without `=` are treated as having an empty value.
+pub fn parse_urlencoded(body: &[u8]) -> FormFields {
+    String::from_utf8_lossy(body)
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(name, true), percent_decode(value, true))
+        })
+        .collect()
+}
+
+/// Splits a `Content-Type` value into its lowercased media type and parameters.
+///
+/// Parameter names are lowercased and quoted parameter values are unquoted.
+pub fn parse_content_type(content_type: &str) -> (String, HashMap<String, String>) {
+    let mut parts = content_type.split(';');
+    let media_type = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+    let params = parts
+        .filter_map(|param| param.split_once('='))
+        .map(|(name, value)| {
+            let value = value.trim();
+            let value =
+                value.strip_prefix('"').and_then(|value| value.strip_suffix('"')).unwrap_or(value);
+            (name.trim().to_ascii_lowercase(), value.to_string())
+        })
+        .collect();
+    (media_type, params)
+}
+
+/// Extracts the boundary from a `multipart/form-data` content type.
+pub fn multipart_boundary(content_type: &str) -> Result<String> {
+    let (media_type, params) = parse_content_type(content_type);
+    if media_type != CT_MULTIPART_FORM_DATA {
+        return Err(ServerError::HttpError(HttpError::UnsupportedMediaType).into());
+    }
+    match params.get("boundary") {
+        Some(boundary) if !boundary.is_empty() && boundary.len() <= 70 => Ok(boundary.clone()),
+        _ => Err(ServerError::HttpError(HttpError::MissingBoundary).into()),
+    }
+}
+
+/// Reduces a client-supplied file name to a safe, single path component.
+///
+/// Directory components are dropped, control characters and reserved characters are replaced
+/// with `_`, and names that would refer to the current or parent directory are rejected.
+pub fn sanitize_filename(filename: &str) -> Option<String> {
+    let name = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name = name
+        .chars()
+        .map(|c| if c.is_control() || "<>:\"|?*".contains(c) { '_' } else { c })
+        .collect::<String>();
+    let name = name.trim().trim_start_matches('.');
+    if name.is_empty() {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+/// Limits enforced by the multipart parser.
+#[derive(Debug, Clone, Copy)]
+pub struct MultipartLimits {
+    /// Maximum size of a single part's body, in bytes.
+    pub max_part_size:   usize,
+    /// Maximum size of a single part's header block, in bytes.
+    pub max_header_size: usize,
+    /// Maximum number of parts in a body.
+    pub max_parts:       usize,
+}
+
+impl Default for MultipartLimits {
+    fn default() -> Self {
+        Self { max_part_size: 10 * 1024 * 1024, max_header_size: 8 * 1024, max_parts: 100 }
+    }
+}
+
+/// The headers of a single multipart part.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartHeaders {
+    pub name:         Option<String>,
+    pub filename:     Option<String>,
+    pub content_type: Option<String>,
+}
+
+impl PartHeaders {
+    /// Parses a part's header block.
+    fn parse(block: &[u8]) -> Result<Self> {
+        let block = String::from_utf8_lossy(block);
+        let mut headers = Self { name: None, filename: None, content_type: None };
+        for line in block.split("\r\n").filter(|line| !line.is_empty()) {
+            let (key, value) = line
+                .split_once(':')
+                .ok_or(ServerError::HttpError(HttpError::MalformedMultipart))?;
+            match key.trim().to_ascii_lowercase().as_str() {
+                CONTENT_DISPOSITION => {
+                    let (disposition, params) = parse_content_type(value);
+                    if disposition != "form-data" {
+                        return Err(ServerError::HttpError(HttpError::MalformedMultipart).into());
+                    }
+                    headers.name = params.get("name").cloned();
+                    headers.filename = params.get("filename").cloned();
+                },
+                CONTENT_TYPE => headers.content_type = Some(value.trim().to_string()),
+                _ => {},
+            }
+        }
+        Ok(headers)
+    }
+}
+
+/// An event produced by the streaming multipart parser.
+#[derive(Debug, PartialEq)]
+pub enum MultipartEvent {
+    /// A new part begins.
+    PartStart(PartHeaders),
+    /// A chunk of the current part's body.
+    PartData(Vec<u8>),
+    /// The current part is complete.
+    PartEnd,
+}
+
+#[derive(Debug, PartialEq)]
+enum ParserState {
+    Preamble,
+    AfterBoundary,
+    Headers,
+    Body,
+    Done,
+}
+
+/// A streaming `multipart/form-data` parser.
+///
+/// Input may be pushed in arbitrarily sized chunks; part bodies are emitted as soon as they can
+/// no longer be the start of a boundary, so memory use is bounded by the chunk size plus the
+/// boundary length rather than the size of the part.
+pub struct MultipartParser {
+    delimiter: Vec<u8>,
+    limits:    MultipartLimits,
+    buffer:    Vec<u8>,
+    state:     ParserState,
+    parts:     usize,
+    part_size: usize,
+}
+
+impl MultipartParser {
+    /// Creates a new `MultipartParser`.
+    ///
+    /// # Arguments
+    ///
+    /// * `boundary` - The boundary from the request's `Content-Type`.
+    /// * `limits` - The limits to enforce.
+    pub fn new(boundary: &str, limits: MultipartLimits) -> Self {
+        Self {
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            limits,
+            // The first boundary may not be preceded by a CRLF.
+            buffer: b"\r\n".to_vec(),
+            state: ParserState::Preamble,
+            parts: 0,
+            part_size: 0,
+        }
+    }
+
+    /// Returns whether the closing boundary has been seen.
+    pub fn is_done(&self) -> bool { self.state == ParserState::Done }
+
+    /// Feeds a chunk of the body to the parser.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the events that the chunk completed, or an error if the body is
+    /// malformed or exceeds the limits.
+    pub fn push(&mut self, chunk: &[u8]) -> Result<Vec<MultipartEvent>> {
+        self.buffer.extend_from_slice(chunk);
+        let mut events = Vec::new();
+        loop {
+            match self.state {
+                ParserState::Preamble => match find(&self.buffer, &self.delimiter) {
+                    Some(index) => {
+                        self.buffer.drain(..index + self.delimiter.len());
+                        self.state = ParserState::AfterBoundary;
+                    },
+                    None => {
+                        let keep = self.delimiter.len().min(self.buffer.len());
+                        self.buffer.drain(..self.buffer.len() - keep);
+                        break;
+                    },
+                },
+                ParserState::AfterBoundary => {
+                    if self.buffer.len() < 2 {
+                        break;
+                    }
+                    if self.buffer.starts_with(b"--") {
+                        self.buffer.clear();
+                        self.state = ParserState::Done;
+                    } else if self.buffer.starts_with(b"\r\n") {
+                        self.buffer.drain(..2);
+                        self.state = ParserState::Headers;
+                    } else {
+                        return Err(ServerError::HttpError(HttpError::MalformedMultipart).into());
+                    }
+                },
+                ParserState::Headers => {
+                    // A part with no headers starts directly with the blank line.
+                    let end = if self.buffer.starts_with(b"\r\n") {
+                        Some((0, 2))
+                    } else {
+                        find(&self.buffer, HEADERS_END).map(|index| (index, HEADERS_END.len()))
+                    };
+                    let Some((index, len)) = end else {
+                        if self.buffer.len() > self.limits.max_header_size {
+                            return Err(ServerError::HttpError(
+                                HttpError::MultipartHeadersTooLarge,
+                            )
+                            .into());
+                        }
+                        break;
+                    };
+                    if index > self.limits.max_header_size {
+                        return Err(
+                            ServerError::HttpError(HttpError::MultipartHeadersTooLarge).into()
+                        );
+                    }
+                    self.parts += 1;
+                    if self.parts > self.limits.max_parts {
+                        return Err(ServerError::HttpError(HttpError::MultipartTooManyParts).into());
+                    }
+                    let headers = PartHeaders::parse(&self.buffer[..index])?;
+                    self.buffer.drain(..index + len);
+                    self.part_size = 0;
+                    self.state = ParserState::Body;
+                    events.push(MultipartEvent::PartStart(headers));
+                },
+                ParserState::Body => match find(&self.buffer, &self.delimiter) {
+                    Some(index) => {
+                        let data = self.buffer.drain(..index).collect::<Vec<_>>();
+                        self.buffer.drain(..self.delimiter.len());
+                        self.emit_data(data, &mut events)?;
+                        events.push(MultipartEvent::PartEnd);
+                        self.state = ParserState::AfterBoundary;
+                    },
+                    None => {
+                        let safe = self.buffer.len().saturating_sub(self.delimiter.len() - 1);
+                        let data = self.buffer.drain(..safe).collect::<Vec<_>>();
+                        self.emit_data(data, &mut events)?;
+                        break;
+                    },
+                },
+                ParserState::Done => {
+                    self.buffer.clear();
+                    break;
+                },
+            }
+        }
+        Ok(events)
+    }
+
+    /// Checks that the body was terminated by the closing boundary.
+    pub fn finish(&self) -> Result<()> {
+        if !self.is_done() {
+            return Err(ServerError::HttpError(HttpError::MalformedMultipart).into());
+        }
+        Ok(())
+    }
+
+    fn emit_data(&mut self, data: Vec<u8>, events: &mut Vec<MultipartEvent>) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.part_size += data.len();
+        if self.part_size > self.limits.max_part_size {
+            return Err(ServerError::HttpError(HttpError::MultipartPartTooLarge).into());
+        }
+        events.push(MultipartEvent::PartData(data));
+        Ok(())
+    }
+}
+
+/// A fully buffered multipart part.
+#[derive(Debug, PartialEq)]
+pub struct MultipartPart {
+    pub headers: PartHeaders,
+    pub body:    Vec<u8>,
+}
+
+/// Parses a complete `multipart/form-data` body into its parts.
+///
+/// # Arguments
+///
+/// * `body` - The request body.
+/// * `boundary` - The boundary from the request's `Content-Type`.
+/// * `limits` - The limits to enforce.
+pub fn parse_multipart(
+    body: &[u8],
+    boundary: &str,
+    limits: MultipartLimits,
+) -> Result<Vec<MultipartPart>> {
+    let mut parser = MultipartParser::new(boundary, limits);
+    let mut parts: Vec<MultipartPart> = Vec::new();
+    for event in parser.push(body)? {
+        match event {
+            MultipartEvent::PartStart(headers) =>
+                parts.push(MultipartPart { headers, body: Vec::new() }),
+            MultipartEvent::PartData(data) =>
+                if let Some(part) = parts.last_mut() {
+                    part.body.extend_from_slice(&data);
+                },
+            MultipartEvent::PartEnd => {},
+        }
+    }
+    parser.finish()?;
+    Ok(parts)
+}
+
+/// Returns the index of the first occurrence of `needle` in `haystack`.
+pub(crate) fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BOUNDARY: &str = "XyZ";
+
+    fn multipart_body() -> Vec<u8> {
+        b"preamble\r\n--XyZ\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nHello\r\n\
+          --XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\
+          Content-Type: text/plain\r\n\r\nline 1\r\nline 2\r\n--XyZ--\r\nepilogue"
+            .to_vec()
+    }
+
+    #[test]
+    fn percent_decode_escapes() {
+        assert_eq!(percent_decode("a%20b%2Fc", false), "a b/c");
+        assert_eq!(percent_decode("a+b", true), "a b");
+        assert_eq!(percent_decode("a+b", false), "a+b");
+        assert_eq!(percent_decode("100%", false), "100%");
+        assert_eq!(percent_decode("%zz%4", false), "%zz%4");
+        assert_eq!(percent_decode("%e2%82%ac", false), "€");
+    }
+
+    #[test]
+    fn urlencoded_fields() {
+        let fields = parse_urlencoded(b"name=J%C3%BCrgen+Doe&tag=a&tag=b&flag&&empty=");
+        assert_eq!(fields, vec![
+            ("name".to_string(), "Jürgen Doe".to_string()),
+            ("tag".to_string(), "a".to_string()),
+            ("tag".to_string(), "b".to_string()),
+            ("flag".to_string(), "".to_string()),
+            ("empty".to_string(), "".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn boundary_from_content_type() {
+        assert_eq!(multipart_boundary("multipart/form-data; boundary=abc").unwrap(), "abc");
+        assert_eq!(multipart_boundary("Multipart/Form-Data; Boundary=\"a b\"").unwrap(), "a b");
+        assert!(multipart_boundary("multipart/form-data").is_err());
+        assert!(multipart_boundary("text/plain; boundary=abc").is_err());
+    }
+
+    #[test]
+    fn sanitize_filenames() {
+        assert_eq!(sanitize_filename("report.pdf").as_deref(), Some("report.pdf"));
+        assert_eq!(sanitize_filename("../../etc/passwd").as_deref(), Some("passwd"));
+        assert_eq!(sanitize_filename("C:\\Users\\me\\a?.txt").as_deref(), Some("a_.txt"));
+        assert_eq!(sanitize_filename("..").as_deref(), None);
+        assert_eq!(sanitize_filename(".hidden").as_deref(), Some("hidden"));
+        assert_eq!(sanitize_filename("dir/").as_deref(), None);
+    }
+
+    #[test]
+    fn multipart_parts() {
+        let parts =
+            parse_multipart(&multipart_body(), BOUNDARY, MultipartLimits::default()).unwrap();
+        assert_eq!(parts, vec![
+            MultipartPart {
+                headers: PartHeaders {
+                    name:         Some("title".to_string()),
+                    filename:     None,
+                    content_type: None,
+                },
+                body:    b"Hello".to_vec(),
+            },
+            MultipartPart {
+                headers: PartHeaders {
+                    name:         Some("file".to_string()),
+                    filename:     Some("a.txt".to_string()),
+                    content_type: Some("text/plain".to_string()),
+                },
+                body:    b"line 1\r\nline 2".to_vec(),
+            },
+        ]);
+    }
+
+    #[test]
+    fn multipart_byte_by_byte() {
+        let mut parser = MultipartParser::new(BOUNDARY, MultipartLimits::default());
+        let mut data = Vec::new();
+        let mut starts = 0;
+        for byte in multipart_body() {
+            for event in parser.push(&[byte]).unwrap() {
+                match event {
+                    MultipartEvent::PartStart(_) => starts += 1,
+                    MultipartEvent::PartData(chunk) => data.extend(chunk),
+                    MultipartEvent::PartEnd => data.push(b'|'),
+                }
+            }
+        }
+        parser.finish().unwrap();
+        assert_eq!(starts, 2);
+        assert_eq!(data, b"Hello|line 1\r\nline 2|");
+    }
+
+    #[test]
+    fn multipart_limits() {
+        let limits = MultipartLimits { max_part_size: 5, ..MultipartLimits::default() };
+        assert!(parse_multipart(&multipart_body(), BOUNDARY, limits).is_err());
+
+        let limits = MultipartLimits { max_parts: 1, ..MultipartLimits::default() };
+        assert!(parse_multipart(&multipart_body(), BOUNDARY, limits).is_err());
+
+        let limits = MultipartLimits { max_header_size: 10, ..MultipartLimits::default() };
+        assert!(parse_multipart(&multipart_body(), BOUNDARY, limits).is_err());
+    }
+
+    #[test]
+    fn multipart_unterminated() {
+        let body = b"--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nvalue";
+        assert!(parse_multipart(body, BOUNDARY, MultipartLimits::default()).is_err());
+    }
+}
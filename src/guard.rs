@@ -0,0 +1,169 @@
+use std::{ops::Not, sync::Arc};
+
+use crate::{
+    form::{parse_content_type, parse_urlencoded},
+    http::{HttpRequest, CONTENT_TYPE, HOST},
+};
+
+/// A predicate on a request that decides whether a route applies, checked after the path has
+/// matched.
+///
+/// Guards combine with [`Guard::and`], [`Guard::or`] and `!`.
+#[derive(Clone)]
+pub enum Guard {
+    /// The `Host` header, without its port, equals this name, ignoring case.
+    Host(String),
+    /// A header, looked up ignoring case, equals this value.
+    Header(String, String),
+    /// A query string parameter equals this value.
+    Query(String, String),
+    /// The media type of the `Content-Type` header equals this one, ignoring parameters.
+    ContentType(String),
+    /// Every guard passes.
+    All(Vec<Guard>),
+    /// At least one guard passes.
+    Any(Vec<Guard>),
+    /// The guard fails.
+    Not(Box<Guard>),
+    /// A custom predicate.
+    Custom(Arc<dyn Fn(&HttpRequest) -> bool + Send + Sync>),
+}
+
+impl Guard {
+    /// Creates a guard on the `Host` header.
+    pub fn host(host: &str) -> Self { Self::Host(host.to_ascii_lowercase()) }
+
+    /// Creates a guard on a header value.
+    pub fn header(name: &str, value: &str) -> Self {
+        Self::Header(name.to_string(), value.to_string())
+    }
+
+    /// Creates a guard on a query string parameter.
+    pub fn query(name: &str, value: &str) -> Self {
+        Self::Query(name.to_string(), value.to_string())
+    }
+
+    /// Creates a guard on the request's content type.
+    pub fn content_type(media_type: &str) -> Self {
+        Self::ContentType(media_type.to_ascii_lowercase())
+    }
+
+    /// Creates a guard from a custom predicate.
+    pub fn custom<F>(predicate: F) -> Self
+    where F: Fn(&HttpRequest) -> bool + Send + Sync + 'static {
+        Self::Custom(Arc::new(predicate))
+    }
+
+    /// Combines two guards so that both must pass.
+    pub fn and(self, other: Guard) -> Self {
+        match self {
+            Self::All(mut guards) => {
+                guards.push(other);
+                Self::All(guards)
+            },
+            guard => Self::All(vec![guard, other]),
+        }
+    }
+
+    /// Combines two guards so that either may pass.
+    pub fn or(self, other: Guard) -> Self {
+        match self {
+            Self::Any(mut guards) => {
+                guards.push(other);
+                Self::Any(guards)
+            },
+            guard => Self::Any(vec![guard, other]),
+        }
+    }
+
+    /// Checks the guard against a request.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The HTTP request.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the route applies to the request.
+    pub fn check(&self, request: &HttpRequest) -> bool {
+        match self {
+            Self::Host(host) => request_host(request).is_some_and(|h| h == *host),
+            Self::Header(name, value) => header(request, name).is_some_and(|v| v.trim() == value),
+            Self::Query(name, value) => {
+                let query = request.line.path.split_once('?').map(|(_, query)| query);
+                parse_urlencoded(query.unwrap_or_default().as_bytes())
+                    .iter()
+                    .any(|(n, v)| n == name && v == value)
+            },
+            Self::ContentType(media_type) => header(request, CONTENT_TYPE)
+                .is_some_and(|content_type| parse_content_type(content_type).0 == *media_type),
+            Self::All(guards) => guards.iter().all(|guard| guard.check(request)),
+            Self::Any(guards) => guards.iter().any(|guard| guard.check(request)),
+            Self::Not(guard) => !guard.check(request),
+            Self::Custom(predicate) => predicate(request),
+        }
+    }
+}
+
+impl Not for Guard {
+    type Output = Guard;
+
+    fn not(self) -> Self::Output { Self::Not(Box::new(self)) }
+}
+
+/// Returns the lowercased `Host` of a request, without its port.
+pub fn request_host(request: &HttpRequest) -> Option<String> {
+    let host = header(request, HOST)?.trim();
+    let host = match host.strip_prefix('[') {
+        // IPv6 literals keep their brackets.
+        Some(rest) => &host[..rest.find(']').map_or(host.len(), |end| end + 2)],
+        None => host.split(':').next().unwrap_or_default(),
+    };
+    Some(host.to_ascii_lowercase())
+}
+
+/// Looks up a request header, ignoring the case of its name.
+fn header<'a>(request: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    request
+        .headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(head: &str) -> HttpRequest {
+        HttpRequest::from_string(&format!("{}\r\n\r\n", head)).unwrap()
+    }
+
+    #[test]
+    fn simple_guards() {
+        let req = request(
+            "POST /api?v=2&x HTTP/1.1\r\nhost: Example.COM:8080\r\nX-Api-Version: \
+             2\r\nContent-Type: application/json; charset=utf-8",
+        );
+        assert!(Guard::host("example.com").check(&req));
+        assert!(!Guard::host("other.com").check(&req));
+        assert!(Guard::header("x-api-version", "2").check(&req));
+        assert!(!Guard::header("X-Api-Version", "1").check(&req));
+        assert!(Guard::query("v", "2").check(&req));
+        assert!(!Guard::query("v", "1").check(&req));
+        assert!(Guard::content_type("application/json").check(&req));
+        assert!(Guard::custom(|request| request.line.method == "POST").check(&req));
+        assert_eq!(request_host(&request("GET / HTTP/1.1\r\nHost: [::1]:80")).unwrap(), "[::1]");
+    }
+
+    #[test]
+    fn combined_guards() {
+        let req = request("GET / HTTP/1.1\r\nHost: a.com\r\nX-Api-Version: 2");
+        let v2 = Guard::header("X-Api-Version", "2");
+        assert!(Guard::host("a.com").and(v2.clone()).check(&req));
+        assert!(!Guard::host("b.com").and(v2.clone()).check(&req));
+        assert!(Guard::host("b.com").or(v2.clone()).check(&req));
+        assert!(!(!v2.clone()).check(&req));
+        assert!((!Guard::host("b.com")).and(v2).check(&req));
+    }
+}
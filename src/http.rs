@@ -1,9 +1,20 @@
-use std::{collections::HashMap, io::Write};
+use std::{collections::HashMap, io::Write, sync::Arc};
 
-use eyre::Result;
+use eyre::{Report, Result};
 use flate2::{write::GzEncoder, Compression};
-
-use crate::error::{HttpError, ServerError};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    body::BodyStream,
+    cookie::{parse_cookie_header, RequestCookies, SetCookie},
+    error::{HttpError, ServerError},
+    extract::AppState,
+    form::{
+        find, multipart_boundary, parse_content_type, parse_multipart, parse_urlencoded,
+        FormFields, MultipartLimits, MultipartPart, CT_FORM_URLENCODED,
+    },
+    pattern::PathParams,
+};
 
 const CRLF: &str = "\r\n";
 const HTTP_VERSION_1_1: &str = "HTTP/1.1";
@@ -16,11 +27,37 @@ pub const CONTENT_LENGTH: &str = "Content-Length";
 pub const CONNECTION: &str = "Connection";
 pub const CONTENT_TYPE: &str = "Content-Type";
 pub const CT_TEXT_PLAIN: &str = "text/plain";
+pub const CT_TEXT_HTML: &str = "text/html";
 pub const USER_AGENT: &str = "User-Agent";
+pub const HOST: &str = "Host";
 pub const CT_APPLICATION_OCTET_STREAM: &str = "application/octet-stream";
+pub const CT_APPLICATION_JSON: &str = "application/json";
+pub const COOKIE: &str = "Cookie";
+pub const SET_COOKIE: &str = "Set-Cookie";
+pub const ALLOW: &str = "Allow";
+pub const ACCEPT: &str = "Accept";
+pub const LOCATION: &str = "Location";
+pub const ETAG: &str = "ETag";
+pub const LAST_MODIFIED: &str = "Last-Modified";
+pub const IF_MATCH: &str = "If-Match";
+pub const IF_NONE_MATCH: &str = "If-None-Match";
+pub const IF_MODIFIED_SINCE: &str = "If-Modified-Since";
+pub const IF_UNMODIFIED_SINCE: &str = "If-Unmodified-Since";
+pub const RANGE: &str = "Range";
+pub const IF_RANGE: &str = "If-Range";
+pub const ACCEPT_RANGES: &str = "Accept-Ranges";
+pub const CONTENT_RANGE: &str = "Content-Range";
+pub const CONTENT_DIGEST: &str = "Content-Digest";
+pub const REPR_DIGEST: &str = "Repr-Digest";
+pub const VARY: &str = "Vary";
 
 pub const METHOD_GET: &str = "GET";
+pub const METHOD_HEAD: &str = "HEAD";
 pub const METHOD_POST: &str = "POST";
+pub const METHOD_PUT: &str = "PUT";
+pub const METHOD_PATCH: &str = "PATCH";
+pub const METHOD_DELETE: &str = "DELETE";
+pub const METHOD_OPTIONS: &str = "OPTIONS";
 
 /// Represents the request line of an HTTP request.
 #[derive(Debug, PartialEq)]
@@ -60,31 +97,130 @@ pub type RequestHeaders = HashMap<String, String>;
 /// Represents an HTTP request.
 #[derive(Debug)]
 pub struct HttpRequest {
-    pub line:       RequestLine,
-    pub headers:    RequestHeaders,
-    pub connection: String,
-    pub body:       Vec<u8>,
+    pub line:        RequestLine,
+    pub headers:     RequestHeaders,
+    pub connection:  String,
+    pub cookies:     RequestCookies,
+    pub params:      PathParams,
+    pub state:       Arc<AppState>,
+    pub body:        Vec<u8>,
+    /// The body, when it is still to be read from the connection; `body` is empty then. The
+    /// router reads it into `body` unless the route streams it, see
+    /// [`Route::stream_body`](crate::router::Route::stream_body).
+    pub body_stream: Option<BodyStream>,
 }
 
 impl HttpRequest {
     /// Creates a new `HttpRequest`.
     fn new(line: RequestLine, headers: RequestHeaders, body: Vec<u8>) -> Self {
         let connection = headers.get(CONNECTION).unwrap_or(&KEEP_ALIVE.to_string()).to_owned();
-        Self { line, headers, connection, body }
+        let cookies = headers.get(COOKIE).map(|h| parse_cookie_header(h)).unwrap_or_default();
+        Self {
+            line,
+            headers,
+            connection,
+            cookies,
+            params: PathParams::new(),
+            state: Arc::default(),
+            body,
+            body_stream: None,
+        }
+    }
+
+    /// Returns the value of a path parameter captured by the matched route.
+    pub fn param(&self, name: &str) -> Option<&str> { self.params.get(name).map(String::as_str) }
+
+    /// Returns the value of a request cookie by name.
+    pub fn cookie(&self, name: &str) -> Option<&str> { self.cookies.get(name).map(String::as_str) }
+
+    /// Reads a streamed body into `body`, so it can be used whole.
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - The largest body accepted.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` that is a `BodyTooLarge` error if the body is longer than `limit`, or an
+    /// `IncompleteBody` error if the connection closes before its end.
+    pub async fn buffer_body(&mut self, limit: u64) -> Result<()> {
+        if let Some(stream) = self.body_stream.take() {
+            self.body = stream.read_to_end(limit).await?;
+        }
+        Ok(())
+    }
+
+    /// Parses the body as an `application/x-www-form-urlencoded` form.
+    pub fn form(&self) -> Result<FormFields> {
+        let content_type = self.headers.get(CONTENT_TYPE).map(String::as_str).unwrap_or_default();
+        if parse_content_type(content_type).0 != CT_FORM_URLENCODED {
+            return Err(ServerError::HttpError(HttpError::UnsupportedMediaType).into());
+        }
+        Ok(parse_urlencoded(&self.body))
+    }
+
+    /// Parses the body as a `multipart/form-data` form.
+    pub fn multipart(&self, limits: MultipartLimits) -> Result<Vec<MultipartPart>> {
+        let content_type = self.headers.get(CONTENT_TYPE).map(String::as_str).unwrap_or_default();
+        let boundary = multipart_boundary(content_type)?;
+        parse_multipart(&self.body, &boundary, limits)
+    }
+
+    /// Deserializes the body as JSON.
+    ///
+    /// The `Content-Type` must be `application/json` or a `+json` media type.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the deserialized value, or an `UnsupportedMediaType` error for other
+    /// content types and an `InvalidJson` error describing where parsing failed.
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T> {
+        let content_type = self.headers.get(CONTENT_TYPE).map(String::as_str).unwrap_or_default();
+        let (media_type, _) = parse_content_type(content_type);
+        if media_type != CT_APPLICATION_JSON && !media_type.ends_with("+json") {
+            return Err(ServerError::HttpError(HttpError::UnsupportedMediaType).into());
+        }
+        serde_json::from_slice(&self.body)
+            .map_err(|e| ServerError::HttpError(HttpError::InvalidJson(e.to_string())).into())
     }
 
     /// Parses an HTTP request from bytes.
+    ///
+    /// The head is split into lines, while the body is kept as raw bytes so that binary and
+    /// multi-line bodies survive intact.
     pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
-        let string = String::from_utf8_lossy(bytes);
-        Self::from_string(&string)
+        let head_end = CRLF.repeat(2);
+        let (head, body) = match find(bytes, head_end.as_bytes()) {
+            Some(index) => (&bytes[..index], &bytes[index + head_end.len()..]),
+            None => (bytes, &[][..]),
+        };
+        let mut request = Self::from_head(head)?;
+        request.body = Self::parse_body(&request.headers, body)?;
+        Ok(request)
     }
 
-    /// Parses an HTTP request from a string.
-    pub fn from_string(string: &str) -> Result<Self> {
-        let lines = string.split(CRLF).map(|s| s.to_string()).collect::<Vec<_>>();
-        Self::from_strings(&lines)
+    /// Parses the request line and headers of an HTTP request, leaving the body empty.
+    ///
+    /// # Arguments
+    ///
+    /// * `head` - The head, without the empty line that ends it.
+    pub fn from_head(head: &[u8]) -> Result<Self> {
+        let head = String::from_utf8_lossy(head);
+        let mut lines = head.split(CRLF);
+        let request_line =
+            lines.next().ok_or(ServerError::HttpError(HttpError::MissingRequestLine))?;
+        let header_lines = lines.map(|s| s.to_string()).collect::<Vec<_>>();
+        let line = RequestLine::from_line(request_line)?;
+        let headers = Self::parse_headers(&header_lines)?;
+        Ok(Self::new(line, headers, Vec::new()))
     }
 
+    /// Returns the length of the body from the `Content-Length` header, or 0 if there is none.
+    pub fn content_length(&self) -> Result<u64> { content_length(&self.headers) }
+
+    /// Parses an HTTP request from a string.
+    pub fn from_string(string: &str) -> Result<Self> { Self::from_bytes(string.as_bytes()) }
+
     /// Parses an HTTP request from a list of strings.
     pub fn from_strings(strings: &[String]) -> Result<Self> {
         let request_line =
@@ -103,7 +239,7 @@ impl HttpRequest {
     ) -> Result<Self> {
         let line = RequestLine::from_line(request_line)?;
         let headers = Self::parse_headers(header_lines)?;
-        let body = Self::parse_body(&headers, body_lines)?;
+        let body = Self::parse_body(&headers, body_lines.join(CRLF).as_bytes())?;
         Ok(Self::new(line, headers, body))
     }
 
@@ -128,46 +264,123 @@ impl HttpRequest {
         Ok(headers)
     }
 
-    /// Parses the body from a list of strings.
-    fn parse_body(headers: &RequestHeaders, body_lines: &[String]) -> Result<Vec<u8>> {
-        let content_length = headers
-            .get(CONTENT_LENGTH)
-            .map(|s| s.parse::<usize>())
-            .transpose()
-            .map_err(|_| ServerError::HttpError(HttpError::InvalidContentLength))?
-            .unwrap_or(0);
+    /// Parses the body from raw bytes.
+    fn parse_body(headers: &RequestHeaders, body: &[u8]) -> Result<Vec<u8>> {
+        let content_length = content_length(headers)?;
 
         if content_length != 0 {
-            let body: Vec<u8> =
-                body_lines.iter().flat_map(|line| line.as_bytes()).copied().collect();
-            if body.len() != content_length {
+            if body.len() as u64 != content_length {
                 return Err(ServerError::HttpError(HttpError::InvalidContentLength).into());
             }
-            return Ok(body);
+            return Ok(body.to_vec());
         }
         Ok(Vec::new())
     }
 }
 
+/// Parses the `Content-Length` header, which defaults to 0.
+fn content_length(headers: &RequestHeaders) -> Result<u64> {
+    Ok(headers
+        .get(CONTENT_LENGTH)
+        .map(|s| s.parse::<u64>())
+        .transpose()
+        .map_err(|_| ServerError::HttpError(HttpError::InvalidContentLength))?
+        .unwrap_or(0))
+}
+
 /// Represents an HTTP status code.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct StatusCode(u16);
 
 impl StatusCode {
+    pub const BAD_GATEWAY: Self = Self(502);
+    pub const BAD_REQUEST: Self = Self(400);
+    pub const CONFLICT: Self = Self(409);
     pub const CREATED: Self = Self(201);
+    pub const FORBIDDEN: Self = Self(403);
+    pub const FOUND: Self = Self(302);
     pub const INTERNAL_SERVER_ERROR: Self = Self(500);
+    pub const LOCKED: Self = Self(423);
+    pub const MISDIRECTED_REQUEST: Self = Self(421);
+    pub const MOVED_PERMANENTLY: Self = Self(301);
+    pub const MULTI_STATUS: Self = Self(207);
     pub const NOT_ALLOWED: Self = Self(405);
     pub const NOT_FOUND: Self = Self(404);
+    pub const NOT_MODIFIED: Self = Self(304);
+    pub const NO_CONTENT: Self = Self(204);
     pub const OK: Self = Self(200);
+    pub const PARTIAL_CONTENT: Self = Self(206);
+    pub const PAYLOAD_TOO_LARGE: Self = Self(413);
+    pub const PERMANENT_REDIRECT: Self = Self(308);
+    pub const PRECONDITION_FAILED: Self = Self(412);
+    pub const RANGE_NOT_SATISFIABLE: Self = Self(416);
+    pub const TEMPORARY_REDIRECT: Self = Self(307);
+    pub const UNSUPPORTED_MEDIA_TYPE: Self = Self(415);
+
+    /// Returns the status code for a number, if it is one this server knows.
+    pub fn from_u16(code: u16) -> Option<Self> {
+        let status_code = Self(code);
+        status_code.as_str().starts_with(&format!("{} ", code)).then_some(status_code)
+    }
+
+    /// Returns `true` for 3xx redirection status codes.
+    pub fn is_redirection(&self) -> bool { (300..400).contains(&self.0) }
+
+    /// Returns the numeric status code.
+    pub fn as_u16(&self) -> u16 { self.0 }
+
+    /// Returns the reason phrase, e.g. `Not Found`.
+    pub fn reason(&self) -> &str { self.as_str().split_once(' ').map_or("", |(_, reason)| reason) }
+
+    /// Returns the status code a request error should be answered with.
+    ///
+    /// Request errors map to their 4xx status; any other error is a 500.
+    pub fn for_error(error: &Report) -> Self {
+        match error.downcast_ref::<ServerError>() {
+            Some(ServerError::HttpError(
+                HttpError::MultipartPartTooLarge
+                | HttpError::MultipartHeadersTooLarge
+                | HttpError::MultipartTooManyParts
+                | HttpError::BodyTooLarge,
+            )) => Self::PAYLOAD_TOO_LARGE,
+            Some(ServerError::HttpError(HttpError::UnsupportedMediaType)) =>
+                Self::UNSUPPORTED_MEDIA_TYPE,
+            Some(ServerError::HttpError(HttpError::ForbiddenPath)) => Self::FORBIDDEN,
+            Some(ServerError::HttpError(HttpError::MissingParentDirectory)) => Self::CONFLICT,
+            Some(ServerError::HttpError(HttpError::Locked)) => Self::LOCKED,
+            Some(ServerError::HttpError(HttpError::PreconditionFailed)) =>
+                Self::PRECONDITION_FAILED,
+            Some(ServerError::HttpError(_)) => Self::BAD_REQUEST,
+            _ => Self::INTERNAL_SERVER_ERROR,
+        }
+    }
 
     /// Returns the status code as a string.
     pub fn as_str(&self) -> &str {
         match self.0 {
             200 => "200 OK",
             201 => "201 Created",
+            204 => "204 No Content",
+            206 => "206 Partial Content",
+            207 => "207 Multi-Status",
+            301 => "301 Moved Permanently",
+            302 => "302 Found",
+            304 => "304 Not Modified",
+            307 => "307 Temporary Redirect",
+            308 => "308 Permanent Redirect",
+            400 => "400 Bad Request",
+            403 => "403 Forbidden",
             404 => "404 Not Found",
             405 => "405 Method Not Allowed",
+            409 => "409 Conflict",
+            412 => "412 Precondition Failed",
+            413 => "413 Content Too Large",
+            415 => "415 Unsupported Media Type",
+            416 => "416 Range Not Satisfiable",
+            421 => "421 Misdirected Request",
+            423 => "423 Locked",
             500 => "500 Internal Server Error",
+            502 => "502 Bad Gateway",
             _ => "500 Internal Server Error",
         }
     }
@@ -180,13 +393,20 @@ pub type ResponseHeaders = HashMap<String, String>;
 pub struct HttpResponse {
     pub status_code: StatusCode,
     pub headers:     ResponseHeaders,
+    pub cookies:     Vec<SetCookie>,
     pub body:        Vec<u8>,
 }
 
 impl HttpResponse {
     /// Creates a new `HttpResponse`.
     pub fn new(status_code: StatusCode, body: &[u8], headers: ResponseHeaders) -> Self {
-        Self { status_code, headers, body: body.to_vec() }
+        Self { status_code, headers, cookies: Vec::new(), body: body.to_vec() }
+    }
+
+    /// Adds a `Set-Cookie` header to the response.
+    pub fn with_cookie(mut self, cookie: SetCookie) -> Self {
+        self.cookies.push(cookie);
+        self
     }
 
     /// Creates a 200 OK response.
@@ -194,6 +414,32 @@ impl HttpResponse {
         Self::new(StatusCode::OK, body, headers)
     }
 
+    /// Creates a response with a JSON body.
+    ///
+    /// # Arguments
+    ///
+    /// * `status_code` - The status code of the response.
+    /// * `value` - The value to serialize.
+    pub fn json<T: Serialize>(status_code: StatusCode, value: &T) -> Result<Self> {
+        let body = serde_json::to_vec(value)?;
+        let mut headers = ResponseHeaders::new();
+        headers.insert(CONTENT_TYPE.to_string(), CT_APPLICATION_JSON.to_string());
+        Ok(Self::new(status_code, &body, headers))
+    }
+
+    /// Creates a response describing a request error.
+    ///
+    /// Client errors map to their 4xx status with the error message as a plain-text body; any
+    /// other error becomes a bodiless 500 so that internal details are not leaked.
+    pub fn from_error(error: &Report) -> Self {
+        let Some(ServerError::HttpError(http_error)) = error.downcast_ref::<ServerError>() else {
+            return Self::internal_server_error();
+        };
+        let mut headers = ResponseHeaders::new();
+        headers.insert(CONTENT_TYPE.to_string(), CT_TEXT_PLAIN.to_string());
+        Self::new(StatusCode::for_error(error), http_error.to_string().as_bytes(), headers)
+    }
+
     /// Creates a response from a status code.
     pub fn from_status_code(status_code: StatusCode) -> Self {
         Self::new(status_code, b"", ResponseHeaders::new())
@@ -202,12 +448,45 @@ impl HttpResponse {
     /// Creates a 201 Created response.
     pub fn created() -> Self { Self::from_status_code(StatusCode::CREATED) }
 
+    /// Creates a 204 No Content response.
+    pub fn no_content() -> Self { Self::from_status_code(StatusCode::NO_CONTENT) }
+
+    /// Creates a 400 Bad Request response.
+    pub fn bad_request() -> Self { Self::from_status_code(StatusCode::BAD_REQUEST) }
+
     /// Creates a 404 Not Found response.
     pub fn not_found() -> Self { Self::from_status_code(StatusCode::NOT_FOUND) }
 
     /// Creates a 405 Method Not Allowed response.
     pub fn method_not_allowed() -> Self { Self::from_status_code(StatusCode::NOT_ALLOWED) }
 
+    /// Creates a 409 Conflict response.
+    pub fn conflict() -> Self { Self::from_status_code(StatusCode::CONFLICT) }
+
+    /// Creates a redirect response to a location.
+    pub fn redirect(status_code: StatusCode, location: &str) -> Self {
+        let mut headers = ResponseHeaders::new();
+        headers.insert(LOCATION.to_string(), location.to_string());
+        Self::new(status_code, b"", headers)
+    }
+
+    /// Creates a 412 Precondition Failed response.
+    pub fn precondition_failed() -> Self { Self::from_status_code(StatusCode::PRECONDITION_FAILED) }
+
+    /// Creates a 421 Misdirected Request response.
+    pub fn misdirected_request() -> Self { Self::from_status_code(StatusCode::MISDIRECTED_REQUEST) }
+
+    /// Creates a 423 Locked response.
+    pub fn locked() -> Self { Self::from_status_code(StatusCode::LOCKED) }
+
+    /// Creates a 413 Content Too Large response.
+    pub fn payload_too_large() -> Self { Self::from_status_code(StatusCode::PAYLOAD_TOO_LARGE) }
+
+    /// Creates a 415 Unsupported Media Type response.
+    pub fn unsupported_media_type() -> Self {
+        Self::from_status_code(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+    }
+
     /// Creates a 500 Internal Server Error response.
     pub fn internal_server_error() -> Self {
         Self::from_status_code(StatusCode::INTERNAL_SERVER_ERROR)
@@ -228,6 +507,12 @@ impl HttpResponse {
             response.extend_from_slice(header.as_bytes());
             response.extend_from_slice(CRLF.as_bytes());
         }
+        for cookie in &self.cookies {
+            response.extend_from_slice(
+                format!("{}: {}", SET_COOKIE, cookie.to_header_value()?).as_bytes(),
+            );
+            response.extend_from_slice(CRLF.as_bytes());
+        }
 
         // Serialize body
         if self.body.is_empty() {
@@ -322,4 +607,114 @@ mod test {
             b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 13\r\n\r\nHello, world!"
         );
     }
+
+    #[test]
+    fn request_cookies() {
+        let request = HttpRequest::from_string(
+            "GET / HTTP/1.1\r\nHost: localhost:4221\r\nCookie: session=abc; theme=dark\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(request.cookie("session"), Some("abc"));
+        assert_eq!(request.cookie("theme"), Some("dark"));
+        assert_eq!(request.cookie("missing"), None);
+    }
+
+    #[test]
+    fn request_binary_body() {
+        let mut bytes = b"POST /files/a HTTP/1.1\r\nContent-Length: 6\r\n\r\n".to_vec();
+        bytes.extend_from_slice(b"\xff\r\n\r\n\x00");
+        let request = HttpRequest::from_bytes(&bytes).unwrap();
+        assert_eq!(request.body, b"\xff\r\n\r\n\x00");
+    }
+
+    #[test]
+    fn request_form() {
+        let request = HttpRequest::from_string(
+            "POST /login HTTP/1.1\r\nContent-Type: \
+             application/x-www-form-urlencoded\r\nContent-Length: 19\r\n\r\nuser=a%40b&pass=x+y",
+        )
+        .unwrap();
+        assert_eq!(request.form().unwrap(), vec![
+            ("user".to_string(), "a@b".to_string()),
+            ("pass".to_string(), "x y".to_string()),
+        ]);
+
+        let request = HttpRequest::from_string("POST /login HTTP/1.1\r\n\r\n").unwrap();
+        assert!(request.form().is_err());
+    }
+
+    #[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+    struct Item {
+        id:   u32,
+        name: String,
+    }
+
+    #[test]
+    fn request_json() {
+        let body = r#"{"id":7,"name":"mango"}"#;
+        let request = HttpRequest::from_string(&format!(
+            "POST /items HTTP/1.1\r\nContent-Type: application/json; \
+             charset=utf-8\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        ))
+        .unwrap();
+        assert_eq!(request.json::<Item>().unwrap(), Item { id: 7, name: "mango".to_string() });
+    }
+
+    #[test]
+    fn request_json_errors() {
+        let request = HttpRequest::from_string(
+            "POST /items HTTP/1.1\r\nContent-Type: text/plain\r\nContent-Length: 2\r\n\r\n{}",
+        )
+        .unwrap();
+        let response = HttpResponse::from_error(&request.json::<Item>().unwrap_err());
+        assert_eq!(response.status_code, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let request = HttpRequest::from_string(
+            "POST /items HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: \
+             8\r\n\r\n{\"id\":1}",
+        )
+        .unwrap();
+        let response = HttpResponse::from_error(&request.json::<Item>().unwrap_err());
+        assert_eq!(response.status_code, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            String::from_utf8(response.body).unwrap(),
+            "Invalid JSON: missing field `name` at line 1 column 8"
+        );
+    }
+
+    #[test]
+    fn response_json() {
+        let response =
+            HttpResponse::json(StatusCode::OK, &Item { id: 1, name: "kiwi".to_string() }).unwrap();
+        assert_eq!(
+            response.to_string().unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: \
+             22\r\n\r\n{\"id\":1,\"name\":\"kiwi\"}"
+        );
+    }
+
+    #[test]
+    fn status_codes_from_numbers() {
+        assert_eq!(StatusCode::from_u16(404), Some(StatusCode::NOT_FOUND));
+        assert_eq!(StatusCode::from_u16(308), Some(StatusCode::PERMANENT_REDIRECT));
+        assert_eq!(StatusCode::from_u16(500), Some(StatusCode::INTERNAL_SERVER_ERROR));
+        for partial in [0, 5, 30, 40, 50] {
+            assert_eq!(StatusCode::from_u16(partial), None, "{}", partial);
+        }
+        assert_eq!(StatusCode::from_u16(599), None);
+        assert_eq!(StatusCode::from_u16(5000), None);
+    }
+
+    #[test]
+    fn response_set_cookies() {
+        let response = HttpResponse::ok(b"", ResponseHeaders::new())
+            .with_cookie(SetCookie::new("a", "1").http_only(true))
+            .with_cookie(SetCookie::new("b", "2").path("/"));
+        assert_eq!(
+            response.to_string().unwrap(),
+            "HTTP/1.1 200 OK\r\nSet-Cookie: a=1; HttpOnly\r\nSet-Cookie: b=2; Path=/\r\n\r\n"
+        );
+    }
 }
@@ -0,0 +1,21 @@
+pub mod autoindex;
+pub mod body;
+pub mod conditional;
+pub mod cookie;
+pub mod error;
+pub mod extract;
+pub mod files;
+pub mod form;
+pub mod guard;
+pub mod http;
+pub mod middleware;
+pub mod mime;
+pub mod pattern;
+pub mod problem;
+pub mod range;
+pub mod rewrite;
+pub mod router;
+pub mod server;
+pub mod trie;
+pub mod vhost;
+pub mod webdav;
@@ -1,32 +1,137 @@
-use eyre::Result;
-
-use crate::{router::make_router, server::Server};
+use std::path::Path;
 
-mod error;
-mod http;
-mod router;
-mod server;
+use eyre::Result;
+use http_server_starter_rust::{
+    autoindex::AutoIndex,
+    conditional::ETagMode,
+    files::{remove_stale_uploads, StaticSite, SymlinkPolicy, UploadOptions},
+    mime::MimeTypes,
+    rewrite::RewriteRules,
+    router::make_router,
+    server::Server,
+    vhost::VirtualHosts,
+    webdav::WebDav,
+};
 
 const DEFAULT_DIRECTORY: &str = "./public";
 
 const DEFAULT_ADDR: &str = "127.0.0.1:4221";
 
+const USAGE: &str = "Usage: http-server [--directory DIRECTORY] [--vhost HOST=DIRECTORY]... \
+                     [--strict-hosts] [--rewrites FILE] [--symlinks follow|within-root|deny] \
+                     [--mime-types FILE] [--etags metadata|content] [--autoindex DIRECTORY]... \
+                     [--static-site] [--site-fallback FILE] [--create-parents] [--webdav]";
+
+/// Command line options.
+struct Args {
+    directory:    String,
+    vhosts:       Vec<(String, String)>,
+    strict_hosts: bool,
+    rewrites:     Option<String>,
+    symlinks:     SymlinkPolicy,
+    mime_types:   Option<String>,
+    etags:        ETagMode,
+    autoindex:    AutoIndex,
+    static_site:  Option<StaticSite>,
+    uploads:      UploadOptions,
+    webdav:       Option<WebDav>,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    let pub_dir = parse_cli_args();
-    let router = make_router(&pub_dir);
+    let args = parse_cli_args();
+    remove_stale_uploads(Path::new(&args.directory))?;
+    for (_, directory) in &args.vhosts {
+        remove_stale_uploads(Path::new(directory))?;
+    }
+    let mut router = match args.vhosts.is_empty() {
+        true => make_router(&args.directory)?,
+        false => {
+            let mut hosts = VirtualHosts::new();
+            for (host, directory) in &args.vhosts {
+                hosts.host(host, make_router(directory)?)?;
+            }
+            hosts.default_host(make_router(&args.directory)?).strict(args.strict_hosts);
+            hosts.into_router()
+        },
+    };
+    router.state(args.symlinks).state(args.etags).state(args.autoindex).state(args.uploads);
+    if let Some(static_site) = args.static_site {
+        router.state(static_site);
+    }
+    if let Some(webdav) = args.webdav {
+        router.state(webdav);
+    }
+    if let Some(rewrites) = &args.rewrites {
+        router.rewrites(RewriteRules::load(rewrites)?);
+    }
+    if let Some(mime_types) = &args.mime_types {
+        router.state(MimeTypes::load(mime_types)?);
+    }
     let server = Server::new(DEFAULT_ADDR, router)?;
     server.listen().await
 }
 
-fn parse_cli_args() -> String {
-    let args = std::env::args().skip(1).collect::<Vec<String>>();
-    if args.len() == 2 && args[0] == "--directory" {
-        args[1].clone()
-    } else if !args.is_empty() {
-        println!("Usage: http-server --directory DIRECTORY");
-        std::process::exit(1);
-    } else {
-        DEFAULT_DIRECTORY.to_string()
+fn parse_cli_args() -> Args {
+    let mut args = std::env::args().skip(1);
+    let mut parsed = Args {
+        directory:    DEFAULT_DIRECTORY.to_string(),
+        vhosts:       Vec::new(),
+        strict_hosts: false,
+        rewrites:     None,
+        symlinks:     SymlinkPolicy::default(),
+        mime_types:   None,
+        etags:        ETagMode::default(),
+        autoindex:    AutoIndex::new(),
+        static_site:  None,
+        uploads:      UploadOptions::new(),
+        webdav:       None,
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--directory" => parsed.directory = args.next().unwrap_or_else(|| usage()),
+            "--vhost" => {
+                let vhost = args.next().unwrap_or_else(|| usage());
+                let (host, directory) = vhost.split_once('=').unwrap_or_else(|| usage());
+                parsed.vhosts.push((host.to_string(), directory.to_string()));
+            },
+            "--strict-hosts" => parsed.strict_hosts = true,
+            "--symlinks" =>
+                parsed.symlinks = match args.next().as_deref() {
+                    Some("follow") => SymlinkPolicy::Follow,
+                    Some("within-root") => SymlinkPolicy::WithinRoot,
+                    Some("deny") => SymlinkPolicy::Deny,
+                    _ => usage(),
+                },
+            "--rewrites" => parsed.rewrites = Some(args.next().unwrap_or_else(|| usage())),
+            "--etags" =>
+                parsed.etags = match args.next().as_deref() {
+                    Some("metadata") => ETagMode::Metadata,
+                    Some("content") => ETagMode::ContentHash,
+                    _ => usage(),
+                },
+            "--autoindex" => {
+                parsed.autoindex.enable(&args.next().unwrap_or_else(|| usage()));
+            },
+            "--static-site" => {
+                parsed.static_site.get_or_insert_with(StaticSite::new);
+            },
+            "--site-fallback" => {
+                let fallback = args.next().unwrap_or_else(|| usage());
+                parsed.static_site.get_or_insert_with(StaticSite::new).fallback(&fallback);
+            },
+            "--create-parents" => {
+                parsed.uploads.create_parents(true);
+            },
+            "--webdav" => parsed.webdav = Some(WebDav),
+            "--mime-types" => parsed.mime_types = Some(args.next().unwrap_or_else(|| usage())),
+            _ => usage(),
+        }
     }
+    parsed
+}
+
+fn usage() -> ! {
+    println!("{}", USAGE);
+    std::process::exit(1);
 }
@@ -0,0 +1,447 @@
+use std::{collections::HashMap, fs, future::Future, path::Path, sync::Arc};
+
+use eyre::{Result, WrapErr};
+
+use crate::{
+    http::{
+        HttpRequest, HttpResponse, ResponseHeaders, StatusCode, ACCEPT, ACCEPT_ENCODING,
+        ACCEPT_RANGES, CONTENT_ENCODING, CONTENT_RANGE, CONTENT_TYPE, CT_APPLICATION_JSON,
+        CT_TEXT_HTML, ENCODING_GZIP, ETAG, RANGE, VARY,
+    },
+    router::{Endpoint, HandlerFuture},
+};
+
+/// Cross-cutting behaviour that wraps request handling.
+///
+/// A middleware receives the request and the rest of the pipeline as `next`. It may inspect or
+/// modify the request, short-circuit by returning a response without calling `next`, or await
+/// `next.run(request)` and transform the response it returns.
+pub trait Middleware: Send + Sync + 'static {
+    /// Handles a request.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The HTTP request.
+    /// * `next` - The remaining middleware and the route handler.
+    fn handle(&self, request: HttpRequest, next: Next) -> HandlerFuture;
+}
+
+impl<F, Fut> Middleware for F
+where
+    F: Fn(HttpRequest, Next) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = HttpResponse> + Send + 'static,
+{
+    fn handle(&self, request: HttpRequest, next: Next) -> HandlerFuture {
+        Box::pin(self(request, next))
+    }
+}
+
+/// The remainder of a middleware pipeline.
+pub struct Next {
+    chain:    Arc<[Arc<dyn Middleware>]>,
+    index:    usize,
+    endpoint: Endpoint,
+}
+
+impl Next {
+    /// Creates a pipeline that runs `chain` in order and then `endpoint`.
+    pub(crate) fn new(chain: Vec<Arc<dyn Middleware>>, endpoint: Endpoint) -> Self {
+        Self { chain: chain.into(), index: 0, endpoint }
+    }
+
+    /// Runs the next middleware, or the route handler once all middleware has run.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The HTTP request.
+    ///
+    /// # Returns
+    ///
+    /// The HTTP response.
+    pub async fn run(mut self, request: HttpRequest) -> HttpResponse {
+        match self.chain.get(self.index).cloned() {
+            Some(middleware) => {
+                self.index += 1;
+                middleware.handle(request, self).await
+            },
+            None => (self.endpoint)(request).await,
+        }
+    }
+}
+
+/// Compresses response bodies with gzip when the client accepts it, i.e. lists `gzip` or `*`
+/// in `Accept-Encoding` with a non-zero q-value.
+///
+/// A strong `ETag` on a compressed response is made weak, as the bytes sent are no longer the
+/// ones it was computed from, and its `Accept-Ranges` is dropped, as ranges count the
+/// uncompressed bytes. Requests for byte ranges and the 206 Partial Content responses to them
+/// are left alone for the same reason. A response that could be compressed carries
+/// `Vary: Accept-Encoding` whenever the request sent that header, so caches keep the compressed
+/// and plain versions apart; a plain response to a request without it suits every client.
+pub struct Compression;
+
+impl Middleware for Compression {
+    fn handle(&self, request: HttpRequest, next: Next) -> HandlerFuture {
+        let negotiated = request.headers.contains_key(ACCEPT_ENCODING);
+        let accepts_gzip = request
+            .headers
+            .get(ACCEPT_ENCODING)
+            .is_some_and(|encodings| accepts_encoding(encodings, ENCODING_GZIP));
+        let ranged = request.headers.contains_key(RANGE);
+        Box::pin(async move {
+            let mut response = next.run(request).await;
+            if !negotiated
+                || ranged
+                || response.body.is_empty()
+                || response.status_code == StatusCode::PARTIAL_CONTENT
+                || response.headers.contains_key(CONTENT_ENCODING)
+                || response.headers.contains_key(CONTENT_RANGE)
+            {
+                return response;
+            }
+            add_vary(&mut response.headers, ACCEPT_ENCODING);
+            if accepts_gzip {
+                response.headers.insert(CONTENT_ENCODING.to_string(), ENCODING_GZIP.to_string());
+                response.headers.remove(ACCEPT_RANGES);
+                if let Some(etag) =
+                    response.headers.get_mut(ETAG).filter(|etag| !etag.starts_with("W/"))
+                {
+                    etag.insert_str(0, "W/");
+                }
+            }
+            response
+        })
+    }
+}
+
+/// Checks whether an `Accept-Encoding` header accepts an encoding, by name or through `*`.
+///
+/// The encoding's own entry wins over `*`, and an entry with `q=0` refuses the encoding.
+fn accepts_encoding(header: &str, encoding: &str) -> bool {
+    let mut wildcard = None;
+    for entry in header.split(',') {
+        let mut params = entry.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if name.eq_ignore_ascii_case(encoding) {
+            return quality > 0.0;
+        }
+        if name == "*" {
+            wildcard = Some(quality > 0.0);
+        }
+    }
+    wildcard.unwrap_or(false)
+}
+
+/// Adds a header name to a response's `Vary`, unless it is already listed.
+fn add_vary(headers: &mut ResponseHeaders, name: &str) {
+    let vary = headers.entry(VARY.to_string()).or_default();
+    if vary
+        .split(',')
+        .any(|listed| listed.trim().eq_ignore_ascii_case(name) || listed.trim() == "*")
+    {
+        return;
+    }
+    if !vary.is_empty() {
+        vary.push_str(", ");
+    }
+    vary.push_str(name);
+}
+
+/// Fills the empty bodies of error responses from per-status templates.
+///
+/// Each status can have an HTML and a JSON template; the JSON one is used when the request's
+/// `Accept` header ranks `application/json` above `text/html`. The placeholders `{{status}}`
+/// and `{{reason}}` are replaced with the status code and reason phrase. Responses that already
+/// have a body, and statuses without a template, are left untouched.
+#[derive(Debug, Clone, Default)]
+pub struct ErrorPages {
+    html: HashMap<u16, String>,
+    json: HashMap<u16, String>,
+}
+
+impl ErrorPages {
+    /// Creates an `ErrorPages` without templates.
+    pub fn new() -> Self { Self::default() }
+
+    /// Loads the templates named after their status, e.g. `404.html` or `500.json`, from a
+    /// directory. A missing directory yields no templates.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The directory to load templates from.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the templates, or an error if the directory or a template cannot
+    /// be read.
+    pub fn load(dir: impl AsRef<Path>) -> Result<Self> {
+        let mut pages = Self::new();
+        let Ok(entries) = fs::read_dir(dir.as_ref()) else {
+            return Ok(pages);
+        };
+        for entry in entries {
+            let path = entry?.path();
+            let (Some(stem), Some(extension)) = (path.file_stem(), path.extension()) else {
+                continue;
+            };
+            let Some(status) = stem.to_str().and_then(|stem| stem.parse::<u16>().ok()) else {
+                continue;
+            };
+            if !(400..600).contains(&status) {
+                continue;
+            }
+            let read = || {
+                fs::read_to_string(&path)
+                    .wrap_err_with(|| format!("Failed to read error page {}", path.display()))
+            };
+            match extension.to_str() {
+                Some("html") => pages.html.insert(status, read()?),
+                Some("json") => pages.json.insert(status, read()?),
+                _ => None,
+            };
+        }
+        Ok(pages)
+    }
+
+    /// Sets the HTML template for a status code.
+    pub fn html(mut self, status: u16, template: &str) -> Self {
+        self.html.insert(status, template.to_string());
+        self
+    }
+
+    /// Sets the JSON template for a status code.
+    pub fn json(mut self, status: u16, template: &str) -> Self {
+        self.json.insert(status, template.to_string());
+        self
+    }
+
+    /// Returns `true` if no template is registered.
+    pub fn is_empty(&self) -> bool { self.html.is_empty() && self.json.is_empty() }
+}
+
+impl Middleware for ErrorPages {
+    fn handle(&self, request: HttpRequest, next: Next) -> HandlerFuture {
+        let accept = request.headers.get(ACCEPT).map(String::as_str).unwrap_or_default();
+        let pages = match prefers_json(accept) {
+            true => (&self.json, CT_APPLICATION_JSON),
+            false => (&self.html, CT_TEXT_HTML),
+        };
+        let (templates, content_type) = (pages.0.clone(), pages.1);
+        Box::pin(async move {
+            let mut response = next.run(request).await;
+            let status = response.status_code.as_u16();
+            if status < 400 || !response.body.is_empty() {
+                return response;
+            }
+            if let Some(template) = templates.get(&status) {
+                response.body = template
+                    .replace("{{status}}", &status.to_string())
+                    .replace("{{reason}}", response.status_code.reason())
+                    .into_bytes();
+                response.headers.insert(CONTENT_TYPE.to_string(), content_type.to_string());
+            }
+            response
+        })
+    }
+}
+
+/// Checks whether an `Accept` header ranks `application/json` above `text/html`.
+pub(crate) fn prefers_json(accept: &str) -> bool {
+    let quality = |media_type: &str| {
+        accept
+            .split(',')
+            .filter_map(|range| {
+                let mut params = range.split(';');
+                let range = params.next()?.trim();
+                let matches = range == media_type
+                    || range == "*/*"
+                    || range.strip_suffix("/*").is_some_and(|ty| media_type.starts_with(ty));
+                let q = params
+                    .filter_map(|param| param.trim().strip_prefix("q="))
+                    .find_map(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                // Exact ranges outrank wildcards of the same quality.
+                matches.then_some((q, range == media_type))
+            })
+            .max_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
+            .unwrap_or((0.0, false))
+    };
+    quality(CT_APPLICATION_JSON) > quality(CT_TEXT_HTML)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::{
+        http::{ResponseHeaders, StatusCode},
+        router::{sync, Router},
+    };
+
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    /// Records when it runs before and after the rest of the pipeline.
+    fn tracer(log: &Log, name: &'static str) -> impl Middleware {
+        let log = log.clone();
+        move |request: HttpRequest, next: Next| {
+            let log = log.clone();
+            async move {
+                log.lock().unwrap().push(format!("{} in", name));
+                let response = next.run(request).await;
+                log.lock().unwrap().push(format!("{} out", name));
+                response
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_in_defined_order() {
+        let log = Log::default();
+        let mut router = Router::new();
+        router.layer(tracer(&log, "global 1")).layer(tracer(&log, "global 2"));
+        let handler_log = log.clone();
+        router
+            .get(
+                "/",
+                sync(move |_| {
+                    handler_log.lock().unwrap().push("handler".to_string());
+                    HttpResponse::ok(b"", ResponseHeaders::new())
+                }),
+            )
+            .unwrap()
+            .layer(tracer(&log, "route"));
+
+        let request = HttpRequest::from_string("GET / HTTP/1.1\r\n\r\n").unwrap();
+        router.resolve(request).await.unwrap();
+        assert_eq!(*log.lock().unwrap(), vec![
+            "global 1 in",
+            "global 2 in",
+            "route in",
+            "handler",
+            "route out",
+            "global 2 out",
+            "global 1 out",
+        ]);
+
+        log.lock().unwrap().clear();
+        let request = HttpRequest::from_string("GET /missing HTTP/1.1\r\n\r\n").unwrap();
+        let response = router.resolve(request).await.unwrap();
+        assert_eq!(response.status_code, StatusCode::NOT_FOUND);
+        assert_eq!(*log.lock().unwrap(), vec![
+            "global 1 in",
+            "global 2 in",
+            "global 2 out",
+            "global 1 out"
+        ]);
+    }
+
+    #[tokio::test]
+    async fn short_circuits() {
+        let mut router = Router::new();
+        router.layer(|request: HttpRequest, next: Next| async move {
+            match request.headers.get("Authorization") {
+                Some(_) => next.run(request).await,
+                None => HttpResponse::from_status_code(StatusCode::NOT_FOUND),
+            }
+        });
+        router
+            .get("/secret", sync(|_| HttpResponse::ok(b"s3cret", ResponseHeaders::new())))
+            .unwrap();
+
+        let request = HttpRequest::from_string("GET /secret HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(router.resolve(request).await.unwrap().body, b"");
+        let request =
+            HttpRequest::from_string("GET /secret HTTP/1.1\r\nAuthorization: yes\r\n\r\n").unwrap();
+        assert_eq!(router.resolve(request).await.unwrap().body, b"s3cret");
+    }
+
+    #[tokio::test]
+    async fn compression() {
+        let mut router = Router::new();
+        router.layer(Compression);
+        router
+            .get(
+                "/",
+                sync(|_| {
+                    let headers = ResponseHeaders::from([(ETAG.to_string(), "\"v1\"".to_string())]);
+                    HttpResponse::ok(b"abc", headers)
+                }),
+            )
+            .unwrap();
+
+        let request = HttpRequest::from_string(
+            "GET / HTTP/1.1\r\nAccept-Encoding: deflate, gzip;q=0.5\r\n\r\n",
+        )
+        .unwrap();
+        let response = router.resolve(request).await.unwrap();
+        assert_eq!(response.headers.get(CONTENT_ENCODING).unwrap(), ENCODING_GZIP);
+        assert_eq!(response.headers[ETAG], "W/\"v1\"");
+
+        assert_eq!(response.headers[VARY], ACCEPT_ENCODING);
+
+        let request = HttpRequest::from_string("GET / HTTP/1.1\r\n\r\n").unwrap();
+        let response = router.resolve(request).await.unwrap();
+        assert!(!response.headers.contains_key(CONTENT_ENCODING));
+        assert!(!response.headers.contains_key(VARY));
+        assert_eq!(response.headers[ETAG], "\"v1\"");
+
+        for (header, gzip) in [
+            ("gzip;q=0", false),
+            ("gzip; q=0.000, *", false),
+            ("*;q=0.1", true),
+            ("br, *;q=0", false),
+            ("GZIP", true),
+        ] {
+            let request = format!("GET / HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n", header);
+            let response =
+                router.resolve(HttpRequest::from_string(&request).unwrap()).await.unwrap();
+            assert_eq!(response.headers.contains_key(CONTENT_ENCODING), gzip, "{}", header);
+            assert_eq!(response.headers[VARY], ACCEPT_ENCODING);
+        }
+
+        let mut headers = ResponseHeaders::new();
+        headers.insert(VARY.to_string(), ACCEPT.to_string());
+        add_vary(&mut headers, ACCEPT_ENCODING);
+        add_vary(&mut headers, "accept-encoding");
+        assert_eq!(headers[VARY], "Accept, Accept-Encoding");
+    }
+
+    #[tokio::test]
+    async fn error_pages() {
+        let dir = tempdir::TempDir::new("pages").unwrap();
+        std::fs::write(dir.path().join("404.html"), "<h1>{{status}} {{reason}}</h1>").unwrap();
+        std::fs::write(dir.path().join("404.json"), r#"{"status":{{status}}}"#).unwrap();
+        std::fs::write(dir.path().join("index.html"), "index").unwrap();
+        let mut router = Router::new();
+        router.layer(ErrorPages::load(dir.path()).unwrap().html(405, "not allowed"));
+        router.get("/teapot", sync(|_| HttpResponse::ok(b"tea", ResponseHeaders::new()))).unwrap();
+        router
+            .get(
+                "/gone",
+                sync(|_| HttpResponse::new(StatusCode::NOT_FOUND, b"own", ResponseHeaders::new())),
+            )
+            .unwrap();
+
+        let resolve = |request: &str| router.resolve(HttpRequest::from_string(request).unwrap());
+        let response =
+            resolve("GET /missing HTTP/1.1\r\nAccept: text/html,*/*\r\n\r\n").await.unwrap();
+        assert_eq!(response.body, b"<h1>404 Not Found</h1>");
+        assert_eq!(response.headers.get(CONTENT_TYPE).unwrap(), CT_TEXT_HTML);
+        let response =
+            resolve("GET /missing HTTP/1.1\r\nAccept: application/json, */*;q=0.8\r\n\r\n")
+                .await
+                .unwrap();
+        assert_eq!(response.body, br#"{"status":404}"#);
+        assert_eq!(response.headers.get(CONTENT_TYPE).unwrap(), CT_APPLICATION_JSON);
+        let response = resolve("POST /teapot HTTP/1.1\r\n\r\n").await.unwrap();
+        assert_eq!(response.body, b"not allowed");
+        let response = resolve("GET /gone HTTP/1.1\r\n\r\n").await.unwrap();
+        assert_eq!(response.body, b"own");
+        assert!(ErrorPages::load(dir.path().join("missing")).unwrap().is_empty());
+    }
+}
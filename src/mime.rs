@@ -0,0 +1,345 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use eyre::{Result, WrapErr};
+use serde::Deserialize;
+
+use crate::{
+    error::{RouterError, ServerError},
+    form::parse_content_type,
+    http::{CT_APPLICATION_JSON, CT_APPLICATION_OCTET_STREAM},
+};
+
+/// The extended attribute an uploaded file's content type is stored in, as used by freedesktop.
+pub const XATTR_MIME_TYPE: &str = "user.mime_type";
+
+/// The suffix of the hidden file next to an upload that holds its content type on file systems
+/// without extended attributes, e.g. `.photo.content-type` for `photo`.
+pub const SIDECAR_SUFFIX: &str = ".content-type";
+
+/// How many leading bytes of a file are inspected when sniffing.
+pub const SNIFF_LEN: usize = 512;
+
+/// Content types that are text without being `text/*`.
+const TEXT_APPLICATION_TYPES: [&str; 4] =
+    [CT_APPLICATION_JSON, "application/javascript", "application/xml", "image/svg+xml"];
+
+/// Magic numbers and the content types they identify.
+const SIGNATURES: [(&[u8], &str); 8] = [
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"\0asm", "application/wasm"),
+];
+
+/// Decides the `Content-Type` of served files.
+///
+/// A file's type comes from, in order: a per-directory override for its extension, the type
+/// stored when it was uploaded, the extension database, sniffing its first bytes if enabled,
+/// and finally `application/octet-stream`. Text types get `charset=utf-8` unless they name a
+/// charset already.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MimeTypes {
+    #[serde(default)]
+    sniff:     bool,
+    /// Extension to content type maps, keyed by directory relative to the public directory.
+    #[serde(default)]
+    overrides: BTreeMap<String, HashMap<String, String>>,
+}
+
+impl MimeTypes {
+    /// Creates a `MimeTypes` that uses the extension database only.
+    pub fn new() -> Self { Self::default() }
+
+    /// Parses the configuration from TOML.
+    ///
+    /// ```toml
+    /// sniff = true
+    ///
+    /// [overrides.""]
+    /// md = "text/markdown"
+    ///
+    /// [overrides.downloads]
+    /// html = "application/octet-stream"
+    /// ```
+    pub fn from_toml(config: &str) -> Result<Self> {
+        let mut types = toml::from_str::<Self>(config)
+            .map_err(|e| ServerError::RouterError(RouterError::InvalidMimeTypes(e.to_string())))?;
+        let overrides = std::mem::take(&mut types.overrides);
+        for (dir, extensions) in overrides {
+            for (extension, content_type) in extensions {
+                types.override_type(&dir, &extension, &content_type);
+            }
+        }
+        Ok(types)
+    }
+
+    /// Loads the configuration from a TOML file, see [`MimeTypes::from_toml`].
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let config = fs::read_to_string(path)
+            .wrap_err_with(|| format!("Failed to read MIME types {}", path.display()))?;
+        Self::from_toml(&config)
+    }
+
+    /// Sets whether files that no other rule identifies are sniffed by their first bytes.
+    pub fn sniff(&mut self, sniff: bool) -> &mut Self {
+        self.sniff = sniff;
+        self
+    }
+
+    /// Returns whether files are sniffed, i.e. whether [`MimeTypes::content_type`] needs their
+    /// first bytes.
+    pub fn is_sniffing(&self) -> bool { self.sniff }
+
+    /// Overrides the content type of an extension in a directory and its subdirectories.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The directory relative to the public directory, or `""` for all of it.
+    /// * `extension` - The extension, without the dot.
+    /// * `content_type` - The content type to send.
+    pub fn override_type(&mut self, dir: &str, extension: &str, content_type: &str) -> &mut Self {
+        let dir = dir.trim_matches('/').trim_start_matches("./");
+        let dir = if dir == "." { "" } else { dir };
+        self.overrides
+            .entry(dir.to_string())
+            .or_default()
+            .insert(extension.to_ascii_lowercase(), content_type.to_string());
+        self
+    }
+
+    /// Returns the `Content-Type` for a file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The file's path relative to the public directory.
+    /// * `stored` - The content type stored with the file when it was uploaded, if any.
+    /// * `head` - The file's first bytes, used when sniffing.
+    pub fn content_type(&self, path: &str, stored: Option<&str>, head: &[u8]) -> String {
+        let path = Path::new(path);
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase)
+            .unwrap_or_default();
+        let content_type = self
+            .override_for(path, &extension)
+            .or(stored)
+            .or_else(|| {
+                mime_guess::from_ext(&extension).first_raw().filter(|_| !extension.is_empty())
+            })
+            .or_else(|| self.sniff.then(|| sniff(head)).flatten())
+            .unwrap_or(CT_APPLICATION_OCTET_STREAM);
+        with_charset(content_type)
+    }
+
+    /// Returns the override of the deepest configured directory containing `path`.
+    fn override_for(&self, path: &Path, extension: &str) -> Option<&str> {
+        let dir = path.parent().unwrap_or(Path::new(""));
+        self.overrides
+            .iter()
+            .filter(|(prefix, _)| prefix.is_empty() || dir.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .and_then(|(_, extensions)| extensions.get(extension))
+            .map(String::as_str)
+    }
+}
+
+/// Adds `charset=utf-8` to text content types that have no charset.
+pub fn with_charset(content_type: &str) -> String {
+    let (media_type, params) = parse_content_type(content_type);
+    let is_text =
+        media_type.starts_with("text/") || TEXT_APPLICATION_TYPES.contains(&media_type.as_str());
+    match is_text && !params.contains_key("charset") {
+        true => format!("{}; charset=utf-8", content_type),
+        false => content_type.to_string(),
+    }
+}
+
+/// Identifies a content type from a file's first bytes.
+///
+/// Known binary signatures are checked first, then HTML and XML prologues; anything else that
+/// is UTF-8 without control characters is plain text.
+pub fn sniff(head: &[u8]) -> Option<&'static str> {
+    if let Some((_, content_type)) = SIGNATURES.iter().find(|(magic, _)| head.starts_with(magic)) {
+        return Some(content_type);
+    }
+    if head.len() >= 12 && head.starts_with(b"RIFF") && &head[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+    let start = head.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(head.len());
+    let text = head[start..].to_ascii_lowercase();
+    if text.starts_with(b"<!doctype html") || text.starts_with(b"<html") {
+        return Some("text/html");
+    }
+    if text.starts_with(b"<?xml") {
+        return Some("application/xml");
+    }
+    let valid = match std::str::from_utf8(head) {
+        Ok(_) => true,
+        // A multi-byte character may be cut off at the end of the sniffed bytes.
+        Err(e) => e.error_len().is_none(),
+    };
+    let binary = head.iter().any(|b| b.is_ascii_control() && !b"\t\n\x0c\r\x1b".contains(b));
+    (valid && !binary && !head.is_empty()).then_some("text/plain")
+}
+
+/// Returns the content type stored with an uploaded file, if any.
+pub fn stored_content_type(path: &Path) -> Option<String> {
+    if let Ok(Some(value)) = xattr::get(path, XATTR_MIME_TYPE) {
+        return String::from_utf8(value).ok();
+    }
+    let stored = fs::read_to_string(sidecar_path(path)?).ok()?;
+    Some(stored.trim().to_string())
+}
+
+/// Stores the content type of an uploaded file with it.
+///
+/// The type goes into an extended attribute, or into a sidecar file where the file system has
+/// none. This is best effort: a file whose type could not be stored is served by its extension.
+pub fn store_content_type(path: &Path, content_type: &str) {
+    if xattr::set(path, XATTR_MIME_TYPE, content_type.as_bytes()).is_err() {
+        if let Some(sidecar) = sidecar_path(path) {
+            let _ = fs::write(sidecar, content_type);
+        }
+    }
+}
+
+/// Moves the stored content type of a file that was renamed, replacing any type stored with
+/// the destination.
+///
+/// Extended attributes move with the file, so only a sidecar file needs moving.
+pub fn rename_content_type(from: &Path, to: &Path) -> io::Result<()> {
+    let (Some(from), Some(to)) = (sidecar_path(from), sidecar_path(to)) else {
+        return Ok(());
+    };
+    match fs::rename(from, &to) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => remove_sidecar(&to),
+        result => result,
+    }
+}
+
+/// Removes the stored content type of a file that was deleted.
+pub fn remove_content_type(path: &Path) -> io::Result<()> {
+    match sidecar_path(path) {
+        Some(sidecar) => remove_sidecar(&sidecar),
+        None => Ok(()),
+    }
+}
+
+/// Checks whether a file name is that of a sidecar file, and returns the name of the file it
+/// belongs to.
+pub fn sidecar_owner(name: &str) -> Option<&str> {
+    name.strip_prefix('.')?.strip_suffix(SIDECAR_SUFFIX).filter(|owner| !owner.is_empty())
+}
+
+/// Returns the sidecar file that holds the content type of `path`.
+fn sidecar_path(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_string_lossy();
+    Some(path.with_file_name(format!(".{}{}", name, SIDECAR_SUFFIX)))
+}
+
+fn remove_sidecar(sidecar: &Path) -> io::Result<()> {
+    match fs::remove_file(sidecar) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn by_extension() {
+        let types = MimeTypes::new();
+        assert_eq!(types.content_type("index.html", None, b""), "text/html; charset=utf-8");
+        assert_eq!(types.content_type("a/style.CSS", None, b""), "text/css; charset=utf-8");
+        assert_eq!(types.content_type("logo.png", None, b""), "image/png");
+        assert_eq!(types.content_type("data.json", None, b""), "application/json; charset=utf-8");
+        assert_eq!(types.content_type("README", None, b"hello"), CT_APPLICATION_OCTET_STREAM);
+        assert_eq!(
+            types.content_type("upload", Some("text/csv; charset=latin1"), b""),
+            "text/csv; charset=latin1"
+        );
+    }
+
+    #[test]
+    fn sniffing() {
+        let mut types = MimeTypes::new();
+        types.sniff(true);
+        assert_eq!(types.content_type("a", None, b"\x89PNG\r\n\x1a\n...."), "image/png");
+        assert_eq!(types.content_type("a", None, b"RIFF\0\0\0\0WEBPVP8 "), "image/webp");
+        assert_eq!(
+            types.content_type("a", None, b"\n  <!DOCTYPE html><html>"),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(
+            types.content_type("a", None, "h\u{e9}llo".as_bytes()),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(
+            types.content_type("a", None, &"\u{e9}".as_bytes()[..1]),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(types.content_type("a", None, b"\0\x01\x02"), CT_APPLICATION_OCTET_STREAM);
+        assert_eq!(types.content_type("a", None, b""), CT_APPLICATION_OCTET_STREAM);
+    }
+
+    #[test]
+    fn sidecar_files() {
+        let dir = tempdir::TempDir::new("mime").unwrap();
+        let (upload, renamed) = (dir.path().join("upload"), dir.path().join("renamed"));
+        fs::write(&upload, b"a,b").unwrap();
+        fs::write(dir.path().join(".upload.content-type"), "text/csv\n").unwrap();
+        assert_eq!(stored_content_type(&upload).as_deref(), Some("text/csv"));
+
+        fs::rename(&upload, &renamed).unwrap();
+        rename_content_type(&upload, &renamed).unwrap();
+        assert_eq!(stored_content_type(&renamed).as_deref(), Some("text/csv"));
+        assert!(!dir.path().join(".upload.content-type").exists());
+        remove_content_type(&renamed).unwrap();
+        assert!(!dir.path().join(".renamed.content-type").exists());
+        remove_content_type(&renamed).unwrap();
+
+        assert_eq!(sidecar_owner(".photo.content-type"), Some("photo"));
+        assert_eq!(sidecar_owner("photo.content-type"), None);
+        assert_eq!(sidecar_owner(".content-type"), None);
+    }
+
+    #[test]
+    fn directory_overrides() {
+        let types = MimeTypes::from_toml(
+            r#"
+            [overrides.""]
+            md = "text/markdown"
+
+            [overrides."downloads/"]
+            html = "application/octet-stream"
+            md = "application/octet-stream"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(types.content_type("notes.md", None, b""), "text/markdown; charset=utf-8");
+        assert_eq!(types.content_type("downloads/a.html", None, b""), CT_APPLICATION_OCTET_STREAM);
+        assert_eq!(types.content_type("downloads/x/a.md", None, b""), CT_APPLICATION_OCTET_STREAM);
+        assert_eq!(
+            types.content_type("downloads-old/a.html", None, b""),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(
+            types.content_type("downloads/a.png", Some("text/plain"), b""),
+            "text/plain; charset=utf-8"
+        );
+        assert!(MimeTypes::from_toml("sniff = 1").is_err());
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+
+use eyre::Result;
+
+use crate::{
+    error::{RouterError, ServerError},
+    form::percent_decode,
+};
+
+pub type PathParams = HashMap<String, String>;
+
+/// A single segment of a route pattern.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+    /// Matches exactly this segment.
+    Static(String),
+    /// Matches any one segment, e.g. `:id`.
+    Param(String),
+    /// Matches zero or one segment, e.g. `:id?`.
+    OptionalParam(String),
+    /// Matches all remaining segments, including none, e.g. `*path`.
+    Wildcard(String),
+}
+
+/// A compiled route pattern such as `/users/:id` or `/files/*path`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoutePattern {
+    pattern:  String,
+    segments: Vec<Segment>,
+}
+
+impl RoutePattern {
+    /// Parses a route pattern.
+    ///
+    /// # Arguments
+    ///
+    /// * `pattern` - The pattern, made of `/`-separated static segments, `:name` parameters,
+    ///   `:name?` optional parameters and a trailing `*name` wildcard.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the pattern, or an error if a parameter is unnamed, a name is
+    /// repeated, or a wildcard is not the last segment.
+    pub fn parse(pattern: &str) -> Result<Self> {
+        let invalid = || ServerError::RouterError(RouterError::InvalidPattern(pattern.to_string()));
+        let mut segments = Vec::new();
+        let mut names = Vec::new();
+        for part in split_path(pattern) {
+            let segment = if let Some(name) = part.strip_prefix('*') {
+                Segment::Wildcard(name.to_string())
+            } else if let Some(name) = part.strip_prefix(':') {
+                match name.strip_suffix('?') {
+                    Some(name) => Segment::OptionalParam(name.to_string()),
+                    None => Segment::Param(name.to_string()),
+                }
+            } else {
+                Segment::Static(part.to_string())
+            };
+            if let Segment::Param(name) | Segment::OptionalParam(name) | Segment::Wildcard(name) =
+                &segment
+            {
+                if name.is_empty() || names.contains(name) {
+                    return Err(invalid().into());
+                }
+                names.push(name.clone());
+            }
+            if matches!(segments.last(), Some(Segment::Wildcard(_))) {
+                return Err(invalid().into());
+            }
+            segments.push(segment);
+        }
+        Ok(Self { pattern: pattern.to_string(), segments })
+    }
+
+    /// Returns the pattern as it was registered.
+    pub fn as_str(&self) -> &str { &self.pattern }
+
+    /// Returns the compiled segments.
+    pub fn segments(&self) -> &[Segment] { &self.segments }
+
+    /// Matches a request path against the pattern.
+    ///
+    /// Matching is segment-exact: `/echo` does not match `/echo/abc`. Empty segments and the
+    /// query string are ignored, and captured values are percent-decoded.
+    ///
+    /// # Returns
+    ///
+    /// The captured parameters if the path matches, `None` otherwise.
+    pub fn matches(&self, path: &str) -> Option<PathParams> {
+        let path = path.split('?').next().unwrap_or_default();
+        let parts = split_path(path).collect::<Vec<_>>();
+        let mut params = PathParams::new();
+        match_segments(&self.segments, &parts, &mut params).then_some(params)
+    }
+}
+
+/// Splits a path into its non-empty segments.
+pub fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|segment| !segment.is_empty())
+}
+
+fn match_segments(segments: &[Segment], parts: &[&str], params: &mut PathParams) -> bool {
+    let Some((segment, rest)) = segments.split_first() else {
+        return parts.is_empty();
+    };
+    match segment {
+        Segment::Static(expected) => match parts.split_first() {
+            Some((part, parts)) if part == expected => match_segments(rest, parts, params),
+            _ => false,
+        },
+        Segment::Param(name) => {
+            let Some((part, parts)) = parts.split_first() else {
+                return false;
+            };
+            params.insert(name.clone(), percent_decode(part, false));
+            if match_segments(rest, parts, params) {
+                return true;
+            }
+            params.remove(name);
+            false
+        },
+        Segment::OptionalParam(name) => {
+            if let Some((part, parts)) = parts.split_first() {
+                params.insert(name.clone(), percent_decode(part, false));
+                if match_segments(rest, parts, params) {
+                    return true;
+                }
+                params.remove(name);
+            }
+            match_segments(rest, parts, params)
+        },
+        Segment::Wildcard(name) => {
+            let value = parts.iter().map(|part| percent_decode(part, false)).collect::<Vec<_>>();
+            params.insert(name.clone(), value.join("/"));
+            true
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn params(pairs: &[(&str, &str)]) -> PathParams {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn static_is_segment_exact() {
+        let pattern = RoutePattern::parse("/echo").unwrap();
+        assert_eq!(pattern.matches("/echo"), Some(params(&[])));
+        assert_eq!(pattern.matches("/echo/"), Some(params(&[])));
+        assert_eq!(pattern.matches("/echo?x=1"), Some(params(&[])));
+        assert_eq!(pattern.matches("/echo/abc"), None);
+        assert_eq!(pattern.matches("/echoes"), None);
+        assert_eq!(RoutePattern::parse("/").unwrap().matches("/"), Some(params(&[])));
+    }
+
+    #[test]
+    fn named_params() {
+        let pattern = RoutePattern::parse("/users/:id/posts/:post").unwrap();
+        assert_eq!(
+            pattern.matches("/users/42/posts/hello%20world"),
+            Some(params(&[("id", "42"), ("post", "hello world")]))
+        );
+        assert_eq!(pattern.matches("/users/42/posts"), None);
+        assert_eq!(pattern.matches("/users/42/posts/1/extra"), None);
+    }
+
+    #[test]
+    fn optional_params() {
+        let pattern = RoutePattern::parse("/archive/:year?/:month?").unwrap();
+        assert_eq!(pattern.matches("/archive"), Some(params(&[])));
+        assert_eq!(pattern.matches("/archive/2024"), Some(params(&[("year", "2024")])));
+        assert_eq!(
+            pattern.matches("/archive/2024/05"),
+            Some(params(&[("year", "2024"), ("month", "05")]))
+        );
+        assert_eq!(pattern.matches("/archive/2024/05/01"), None);
+
+        let pattern = RoutePattern::parse("/docs/:lang?/index").unwrap();
+        assert_eq!(pattern.matches("/docs/index"), Some(params(&[])));
+        assert_eq!(pattern.matches("/docs/en/index"), Some(params(&[("lang", "en")])));
+    }
+
+    #[test]
+    fn wildcards() {
+        let pattern = RoutePattern::parse("/files/*path").unwrap();
+        assert_eq!(pattern.matches("/files/a/b.txt"), Some(params(&[("path", "a/b.txt")])));
+        assert_eq!(pattern.matches("/files/"), Some(params(&[("path", "")])));
+        assert_eq!(pattern.matches("/files"), Some(params(&[("path", "")])));
+        assert_eq!(pattern.matches("/other/a"), None);
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        assert!(RoutePattern::parse("/files/*path/more").is_err());
+        assert!(RoutePattern::parse("/users/:").is_err());
+        assert!(RoutePattern::parse("/users/:id/:id").is_err());
+        assert!(RoutePattern::parse("/files/*").is_err());
+    }
+}
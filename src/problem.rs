@@ -0,0 +1,89 @@
+use eyre::Report;
+use serde::Serialize;
+
+use crate::{
+    error::ServerError,
+    http::{HttpResponse, StatusCode, CONTENT_TYPE},
+};
+
+pub const CT_APPLICATION_PROBLEM_JSON: &str = "application/problem+json";
+
+/// An RFC 9457 problem details object.
+#[derive(Debug, Serialize)]
+pub struct ProblemDetails {
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title:        String,
+    pub status:       u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail:       Option<String>,
+}
+
+impl ProblemDetails {
+    /// Creates a `ProblemDetails` for a status code, using `about:blank` as the type.
+    pub fn new(status_code: StatusCode, detail: Option<String>) -> Self {
+        Self {
+            problem_type: "about:blank".to_string(),
+            title: status_code.reason().to_string(),
+            status: status_code.as_u16(),
+            detail,
+        }
+    }
+}
+
+/// An error handler that renders errors as `application/problem+json` responses.
+///
+/// Request errors carry their message as the `detail` member; other errors only report the
+/// status, so that internal details are not leaked to clients.
+///
+/// # Arguments
+///
+/// * `error` - The error returned by a route handler.
+pub fn problem_details(error: &Report) -> HttpResponse {
+    let status_code = StatusCode::for_error(error);
+    let detail = match error.downcast_ref::<ServerError>() {
+        Some(ServerError::HttpError(http_error)) => Some(http_error.to_string()),
+        _ => None,
+    };
+    let problem = ProblemDetails::new(status_code, detail);
+    match HttpResponse::json(status_code, &problem) {
+        Ok(mut response) => {
+            response
+                .headers
+                .insert(CONTENT_TYPE.to_string(), CT_APPLICATION_PROBLEM_JSON.to_string());
+            response
+        },
+        Err(_) => HttpResponse::from_status_code(status_code),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use eyre::{eyre, WrapErr};
+
+    use super::*;
+    use crate::error::HttpError;
+
+    #[test]
+    fn request_error_problem() {
+        let error = Report::new(ServerError::HttpError(HttpError::UnsupportedMediaType));
+        let response = problem_details(&error);
+        assert_eq!(response.status_code, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(response.headers.get(CONTENT_TYPE).unwrap(), CT_APPLICATION_PROBLEM_JSON);
+        assert_eq!(
+            String::from_utf8(response.body).unwrap(),
+            r#"{"type":"about:blank","title":"Unsupported Media Type","status":415,"detail":"Unsupported media type"}"#
+        );
+    }
+
+    #[test]
+    fn internal_error_problem() {
+        let error = Err::<(), _>(eyre!("disk full")).wrap_err("Failed to write").unwrap_err();
+        let response = problem_details(&error);
+        assert_eq!(response.status_code, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            String::from_utf8(response.body).unwrap(),
+            r#"{"type":"about:blank","title":"Internal Server Error","status":500}"#
+        );
+    }
+}
@@ -0,0 +1,275 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    io::SeekFrom,
+    path::Path,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use eyre::{Result, WrapErr};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::{
+    conditional::{EntityTag, Validators},
+    http::{
+        HttpRequest, HttpResponse, ResponseHeaders, StatusCode, CONTENT_RANGE, CONTENT_TYPE,
+        IF_RANGE, METHOD_GET, RANGE,
+    },
+};
+
+pub const CT_MULTIPART_BYTERANGES: &str = "multipart/byteranges";
+
+/// The range unit, as sent in `Accept-Ranges`.
+pub const BYTES: &str = "bytes";
+
+/// The most ranges served in one response; requests for more get the whole file.
+pub const MAX_RANGES: usize = 16;
+
+/// A satisfiable byte range, with both ends inclusive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end:   u64,
+}
+
+impl ByteRange {
+    /// Returns the number of bytes in the range.
+    pub fn size(&self) -> u64 { self.end - self.start + 1 }
+
+    /// Returns the `Content-Range` value of the range in a representation of `total` bytes.
+    pub fn content_range(&self, total: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, total)
+    }
+}
+
+/// What a request asks for of a representation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RangeRequest {
+    /// The whole representation, with 200 OK.
+    Full,
+    /// These ranges, with 206 Partial Content.
+    Partial(Vec<ByteRange>),
+    /// None of the requested ranges overlap the representation; answer 416.
+    Unsatisfiable,
+}
+
+/// Parses a `Range` header against a representation of `len` bytes.
+///
+/// Headers in another unit, malformed ones and ones with more than [`MAX_RANGES`] ranges are
+/// ignored as RFC 9110 allows, which yields [`RangeRequest::Full`]. Overlapping and adjacent
+/// ranges are coalesced, so the result is sorted.
+///
+/// # Arguments
+///
+/// * `header` - The header value, e.g. `bytes=0-99,200-,-50`.
+/// * `len` - The length of the representation.
+pub fn parse_range(header: &str, len: u64) -> RangeRequest {
+    let Some(specs) = header.trim().strip_prefix("bytes=") else {
+        return RangeRequest::Full;
+    };
+    let specs = specs.split(',').map(str::trim).filter(|spec| !spec.is_empty()).collect::<Vec<_>>();
+    if specs.is_empty() || specs.len() > MAX_RANGES {
+        return RangeRequest::Full;
+    }
+    let mut ranges = Vec::new();
+    for spec in specs {
+        let Some((first, last)) = spec.split_once('-') else {
+            return RangeRequest::Full;
+        };
+        let number = |value: &str| match value.bytes().all(|b| b.is_ascii_digit()) {
+            true => value.parse::<u64>().ok(),
+            false => None,
+        };
+        let range = match (first, last) {
+            ("", suffix) => match number(suffix) {
+                Some(suffix) => (suffix > 0 && len > 0)
+                    .then(|| ByteRange { start: len.saturating_sub(suffix), end: len - 1 }),
+                None => return RangeRequest::Full,
+            },
+            (first, "") => match number(first) {
+                Some(start) => (start < len).then(|| ByteRange { start, end: len - 1 }),
+                None => return RangeRequest::Full,
+            },
+            (first, last) => match (number(first), number(last)) {
+                (Some(start), Some(end)) if start <= end =>
+                    (start < len).then(|| ByteRange { start, end: end.min(len - 1) }),
+                _ => return RangeRequest::Full,
+            },
+        };
+        ranges.extend(range);
+    }
+    if ranges.is_empty() {
+        return RangeRequest::Unsatisfiable;
+    }
+    ranges.sort_by_key(|range| range.start);
+    let mut coalesced: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match coalesced.last_mut() {
+            Some(last) if range.start <= last.end + 1 => last.end = last.end.max(range.end),
+            _ => coalesced.push(range),
+        }
+    }
+    RangeRequest::Partial(coalesced)
+}
+
+/// Returns the ranges a `GET` request asks for, honouring `If-Range`.
+///
+/// An `If-Range` tag must match the current tag by strong comparison, and an `If-Range` date
+/// must equal the last modification time; otherwise the whole representation is sent.
+///
+/// # Arguments
+///
+/// * `request` - The HTTP request.
+/// * `current` - The validators of the representation.
+/// * `len` - The length of the representation.
+pub fn requested_ranges(request: &HttpRequest, current: &Validators, len: u64) -> RangeRequest {
+    let Some(range) = request.headers.get(RANGE).filter(|_| request.line.method == METHOD_GET)
+    else {
+        return RangeRequest::Full;
+    };
+    if let Some(if_range) = request.headers.get(IF_RANGE) {
+        let matches = match EntityTag::parse(if_range) {
+            Some(tag) => tag.strong_eq(&current.etag),
+            None => httpdate::parse_http_date(if_range).is_ok_and(|date| {
+                current.last_modified.is_some_and(|modified| {
+                    let secs = modified.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+                    UNIX_EPOCH + Duration::from_secs(secs) == date
+                })
+            }),
+        };
+        if !matches {
+            return RangeRequest::Full;
+        }
+    }
+    parse_range(range, len)
+}
+
+/// Reads byte ranges from a file, seeking to each so only the requested bytes are read.
+pub async fn read_ranges(path: &Path, ranges: &[ByteRange]) -> Result<Vec<Vec<u8>>> {
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+    let mut parts = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        let mut part = vec![0; range.size() as usize];
+        file.seek(SeekFrom::Start(range.start)).await?;
+        file.read_exact(&mut part)
+            .await
+            .wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+        parts.push(part);
+    }
+    Ok(parts)
+}
+
+/// Creates a 206 Partial Content response.
+///
+/// A single range is sent as is with a `Content-Range` header; several are sent as a
+/// `multipart/byteranges` body whose parts each carry their `Content-Type` and `Content-Range`.
+///
+/// # Arguments
+///
+/// * `ranges` - The ranges served.
+/// * `parts` - The bytes of each range.
+/// * `total` - The length of the whole representation.
+/// * `content_type` - The content type of the representation.
+/// * `headers` - Further headers, e.g. validators.
+pub fn partial_content(
+    ranges: &[ByteRange],
+    parts: Vec<Vec<u8>>,
+    total: u64,
+    content_type: &str,
+    mut headers: ResponseHeaders,
+) -> HttpResponse {
+    if let ([range], [part]) = (ranges, parts.as_slice()) {
+        headers.insert(CONTENT_TYPE.to_string(), content_type.to_string());
+        headers.insert(CONTENT_RANGE.to_string(), range.content_range(total));
+        return HttpResponse::new(StatusCode::PARTIAL_CONTENT, part, headers);
+    }
+    let boundary = format!("{:016x}", RandomState::new().build_hasher().finish());
+    let mut body = Vec::new();
+    for (range, part) in ranges.iter().zip(parts) {
+        body.extend_from_slice(
+            format!(
+                "--{}\r\n{}: {}\r\n{}: {}\r\n\r\n",
+                boundary,
+                CONTENT_TYPE,
+                content_type,
+                CONTENT_RANGE,
+                range.content_range(total)
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(&part);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+    headers.insert(
+        CONTENT_TYPE.to_string(),
+        format!("{}; boundary={}", CT_MULTIPART_BYTERANGES, boundary),
+    );
+    HttpResponse::new(StatusCode::PARTIAL_CONTENT, &body, headers)
+}
+
+/// Creates a 416 Range Not Satisfiable response for a representation of `total` bytes.
+pub fn range_not_satisfiable(total: u64) -> HttpResponse {
+    let mut headers = ResponseHeaders::new();
+    headers.insert(CONTENT_RANGE.to_string(), format!("bytes */{}", total));
+    HttpResponse::new(StatusCode::RANGE_NOT_SATISFIABLE, b"", headers)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn partial(ranges: &[(u64, u64)]) -> RangeRequest {
+        RangeRequest::Partial(ranges.iter().map(|&(start, end)| ByteRange { start, end }).collect())
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_range("bytes=0-4", 10), partial(&[(0, 4)]));
+        assert_eq!(parse_range("bytes=5-", 10), partial(&[(5, 9)]));
+        assert_eq!(parse_range("bytes=-3", 10), partial(&[(7, 9)]));
+        assert_eq!(parse_range("bytes=-30", 10), partial(&[(0, 9)]));
+        assert_eq!(parse_range("bytes=8-100", 10), partial(&[(8, 9)]));
+        assert_eq!(parse_range("bytes=0-1, 7-8", 10), partial(&[(0, 1), (7, 8)]));
+        assert_eq!(parse_range("bytes=10-, -0", 10), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=20-30, 2-3", 10), partial(&[(2, 3)]));
+    }
+
+    #[test]
+    fn coalesce() {
+        assert_eq!(parse_range("bytes=5-7,0-2,3-4", 10), partial(&[(0, 7)]));
+        assert_eq!(parse_range("bytes=0-5,2-3,-2", 10), partial(&[(0, 5), (8, 9)]));
+    }
+
+    #[test]
+    fn ignored_headers() {
+        for header in ["items=0-1", "bytes=", "bytes=5-2", "bytes=a-b", "bytes=1", "bytes=+1-2"] {
+            assert_eq!(parse_range(header, 10), RangeRequest::Full, "{}", header);
+        }
+        let many = (0..=MAX_RANGES).map(|i| format!("{}-{}", i * 2, i * 2)).collect::<Vec<_>>();
+        assert_eq!(parse_range(&format!("bytes={}", many.join(",")), 100), RangeRequest::Full);
+    }
+
+    #[test]
+    fn multipart_body() {
+        let ranges = [ByteRange { start: 0, end: 1 }, ByteRange { start: 4, end: 5 }];
+        let parts = vec![b"ab".to_vec(), b"ef".to_vec()];
+        let response = partial_content(&ranges, parts, 6, "text/plain", ResponseHeaders::new());
+        assert_eq!(response.status_code, StatusCode::PARTIAL_CONTENT);
+        let content_type = &response.headers[CONTENT_TYPE];
+        let boundary = content_type.strip_prefix("multipart/byteranges; boundary=").unwrap();
+        let body = String::from_utf8(response.body).unwrap();
+        assert_eq!(
+            body,
+            format!(
+                "--{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes \
+                 0-1/6\r\n\r\nab\r\n--{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes \
+                 4-5/6\r\n\r\nef\r\n--{0}--\r\n",
+                boundary
+            )
+        );
+    }
+}
@@ -0,0 +1,391 @@
+use std::{collections::HashSet, fs, path::Path};
+
+use eyre::{Result, WrapErr};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::{
+    error::{RouterError, ServerError},
+    http::StatusCode,
+};
+
+/// The most rewrites applied to a single request before it is treated as a loop.
+const MAX_REWRITES: usize = 16;
+
+/// How paths are canonicalised with respect to a trailing slash.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrailingSlash {
+    /// Adds a trailing slash, except to paths whose last segment looks like a file name.
+    Add,
+    /// Removes the trailing slash.
+    Remove,
+}
+
+/// What a rule does with a matching path.
+#[derive(Debug, Clone)]
+enum Action {
+    Redirect(StatusCode, String),
+    Rewrite(String),
+}
+
+/// A regex rule; `$1` or `${name}` in the target are replaced with captures.
+#[derive(Debug, Clone)]
+struct Rule {
+    pattern: Regex,
+    action:  Action,
+}
+
+/// The result of applying rewrite rules to a request target.
+#[derive(Debug, PartialEq)]
+pub enum Rewritten {
+    /// Route the request with this target, which may have been rewritten internally.
+    Path(String),
+    /// Redirect the client to this location.
+    Redirect(StatusCode, String),
+}
+
+/// Rules for a single `[[rule]]` table of a rules file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    pattern:  String,
+    redirect: Option<String>,
+    rewrite:  Option<String>,
+    status:   Option<u16>,
+}
+
+/// The contents of a rules file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesConfig {
+    #[serde(default)]
+    lowercase:      bool,
+    trailing_slash: Option<TrailingSlash>,
+    #[serde(default, rename = "rule")]
+    rules:          Vec<RuleConfig>,
+}
+
+/// Redirect and rewrite rules, evaluated before a request is routed.
+///
+/// Paths are first canonicalised: if forcing lowercase or the trailing slash policy changes
+/// the path, the client is sent a 308 to the canonical path. The regex rules then run in order
+/// against the path, without the query string. A redirect rule answers with its status and
+/// the expanded target as `Location`; a rewrite rule replaces the path internally and
+/// evaluation restarts from the first rule, until no rule changes the path. A rewrite back to
+/// an earlier path is a loop and fails the request. Targets without a query string keep the
+/// request's query string.
+#[derive(Debug, Clone, Default)]
+pub struct RewriteRules {
+    rules:          Vec<Rule>,
+    lowercase:      bool,
+    trailing_slash: Option<TrailingSlash>,
+}
+
+impl RewriteRules {
+    /// Creates a `RewriteRules` without rules.
+    pub fn new() -> Self { Self::default() }
+
+    /// Parses rules from TOML.
+    ///
+    /// ```toml
+    /// lowercase = true
+    /// trailing_slash = "remove"
+    ///
+    /// [[rule]]
+    /// pattern = "^/old/(.*)$"
+    /// redirect = "/new/$1"
+    /// status = 301
+    ///
+    /// [[rule]]
+    /// pattern = "^/posts/(\\d+)$"
+    /// rewrite = "/post?id=$1"
+    /// ```
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the rules, or an error if the TOML is malformed or a rule is
+    /// invalid.
+    pub fn from_toml(config: &str) -> Result<Self> {
+        let config =
+            toml::from_str::<RulesConfig>(config).map_err(|e| invalid_rule(e.message()))?;
+        let mut rules = Self::new();
+        rules.lowercase(config.lowercase).trailing_slash(config.trailing_slash);
+        for rule in config.rules {
+            let invalid = || invalid_rule(&rule.pattern);
+            match (rule.redirect, rule.rewrite, rule.status) {
+                (Some(target), None, status) => {
+                    let status = status.unwrap_or(StatusCode::FOUND.as_u16());
+                    let status = StatusCode::from_u16(status).ok_or_else(invalid)?;
+                    rules.redirect(&rule.pattern, &target, status)?
+                },
+                (None, Some(target), None) => rules.rewrite(&rule.pattern, &target)?,
+                _ => return Err(invalid().into()),
+            };
+        }
+        Ok(rules)
+    }
+
+    /// Loads rules from a TOML file, see [`RewriteRules::from_toml`].
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let config = fs::read_to_string(path)
+            .wrap_err_with(|| format!("Failed to read rewrite rules {}", path.display()))?;
+        Self::from_toml(&config)
+    }
+
+    /// Adds a redirect rule.
+    ///
+    /// # Arguments
+    ///
+    /// * `pattern` - The regex matched against the path.
+    /// * `target` - The location, with `$1` or `${name}` replaced by captures.
+    /// * `status_code` - 301, 302, 307 or 308.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing `self`, or an `InvalidRewriteRule` error if the pattern is not a
+    /// valid regex or the status code is not a redirect.
+    pub fn redirect(
+        &mut self,
+        pattern: &str,
+        target: &str,
+        status_code: StatusCode,
+    ) -> Result<&mut Self> {
+        if !matches!(status_code.as_u16(), 301 | 302 | 307 | 308) {
+            return Err(invalid_rule(pattern).into());
+        }
+        self.push(pattern, Action::Redirect(status_code, target.to_string()))
+    }
+
+    /// Adds an internal rewrite rule.
+    ///
+    /// # Arguments
+    ///
+    /// * `pattern` - The regex matched against the path.
+    /// * `target` - The new path, with `$1` or `${name}` replaced by captures.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing `self`, or an `InvalidRewriteRule` error if the pattern is not a
+    /// valid regex.
+    pub fn rewrite(&mut self, pattern: &str, target: &str) -> Result<&mut Self> {
+        self.push(pattern, Action::Rewrite(target.to_string()))
+    }
+
+    /// Sets whether paths are redirected to their lowercase form.
+    pub fn lowercase(&mut self, lowercase: bool) -> &mut Self {
+        self.lowercase = lowercase;
+        self
+    }
+
+    /// Sets the trailing slash policy, or `None` to leave trailing slashes alone.
+    pub fn trailing_slash(&mut self, trailing_slash: Option<TrailingSlash>) -> &mut Self {
+        self.trailing_slash = trailing_slash;
+        self
+    }
+
+    fn push(&mut self, pattern: &str, action: Action) -> Result<&mut Self> {
+        let pattern = Regex::new(pattern).map_err(|_| invalid_rule(pattern))?;
+        self.rules.push(Rule { pattern, action });
+        Ok(self)
+    }
+
+    /// Applies the rules to a request target.
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - The request target, i.e. the path and an optional query string.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the target to route or a redirect, or a `RewriteLoop` error if
+    /// rewrites cycle.
+    pub fn apply(&self, target: &str) -> Result<Rewritten> {
+        let (path, query) = split_target(target);
+        let canonical = self.canonicalise(path);
+        if canonical != path {
+            let location = join_target(&canonical, query);
+            return Ok(Rewritten::Redirect(StatusCode::PERMANENT_REDIRECT, location));
+        }
+
+        let (mut path, mut query) = (path.to_string(), query.map(str::to_string));
+        let mut seen = HashSet::from([path.clone()]);
+        for _ in 0..MAX_REWRITES {
+            let Some((rule, target)) = self.rules.iter().find_map(|rule| {
+                let captures = rule.pattern.captures(&path)?;
+                let mut expanded = String::new();
+                let target = match &rule.action {
+                    Action::Redirect(_, target) | Action::Rewrite(target) => target,
+                };
+                captures.expand(target, &mut expanded);
+                Some((rule, expanded))
+            }) else {
+                return Ok(Rewritten::Path(join_target(&path, query.as_deref())));
+            };
+            let (new_path, new_query) = split_target(&target);
+            let new_query = new_query.map(str::to_string).or_else(|| query.clone());
+            match rule.action {
+                Action::Redirect(status_code, _) => {
+                    let location = join_target(new_path, new_query.as_deref());
+                    return Ok(Rewritten::Redirect(status_code, location));
+                },
+                // A rewrite that leaves the path unchanged is where rewriting settles.
+                Action::Rewrite(_) if new_path == path => break,
+                Action::Rewrite(_) => {
+                    if !seen.insert(new_path.to_string()) {
+                        return Err(rewrite_loop(new_path).into());
+                    }
+                    path = new_path.to_string();
+                    query = new_query;
+                },
+            }
+        }
+        match seen.len() > MAX_REWRITES {
+            true => Err(rewrite_loop(&path).into()),
+            false => Ok(Rewritten::Path(join_target(&path, query.as_deref()))),
+        }
+    }
+
+    /// Returns the path with the lowercase and trailing slash policies applied.
+    fn canonicalise(&self, path: &str) -> String {
+        let mut path = match self.lowercase {
+            true => path.to_lowercase(),
+            false => path.to_string(),
+        };
+        match self.trailing_slash {
+            Some(TrailingSlash::Remove) if path.len() > 1 => {
+                path.truncate(path.trim_end_matches('/').len().max(1));
+            },
+            Some(TrailingSlash::Add) if !path.ends_with('/') => {
+                let last = path.rsplit('/').next().unwrap_or_default();
+                if !last.contains('.') {
+                    path.push('/');
+                }
+            },
+            _ => {},
+        }
+        path
+    }
+}
+
+fn invalid_rule(pattern: &str) -> ServerError {
+    ServerError::RouterError(RouterError::InvalidRewriteRule(pattern.to_string()))
+}
+
+fn rewrite_loop(path: &str) -> ServerError {
+    ServerError::RouterError(RouterError::RewriteLoop(path.to_string()))
+}
+
+fn split_target(target: &str) -> (&str, Option<&str>) {
+    match target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (target, None),
+    }
+}
+
+fn join_target(path: &str, query: Option<&str>) -> String {
+    match query {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn path(target: &str) -> Rewritten { Rewritten::Path(target.to_string()) }
+
+    fn redirect(status_code: StatusCode, location: &str) -> Rewritten {
+        Rewritten::Redirect(status_code, location.to_string())
+    }
+
+    #[test]
+    fn redirects_and_rewrites() {
+        let mut rules = RewriteRules::new();
+        rules
+            .redirect("^/old/(.*)$", "/new/$1", StatusCode::MOVED_PERMANENTLY)
+            .unwrap()
+            .redirect("^/search$", "https://example.com/find?q=all", StatusCode::TEMPORARY_REDIRECT)
+            .unwrap()
+            .rewrite(r"^/posts/(?<id>\d+)$", "/post?id=${id}")
+            .unwrap()
+            .rewrite("^/blog/(.*)$", "/posts/$1")
+            .unwrap();
+
+        assert_eq!(
+            rules.apply("/old/a/b?x=1").unwrap(),
+            redirect(StatusCode::MOVED_PERMANENTLY, "/new/a/b?x=1")
+        );
+        assert_eq!(
+            rules.apply("/search?q=x").unwrap(),
+            redirect(StatusCode::TEMPORARY_REDIRECT, "https://example.com/find?q=all")
+        );
+        assert_eq!(rules.apply("/blog/42?utm=1").unwrap(), path("/post?id=42"));
+        assert_eq!(rules.apply("/blog/new").unwrap(), path("/posts/new"));
+        assert_eq!(rules.apply("/other").unwrap(), path("/other"));
+    }
+
+    #[test]
+    fn canonicalises_paths() {
+        let mut rules = RewriteRules::new();
+        rules.lowercase(true).trailing_slash(Some(TrailingSlash::Remove));
+        let moved = |location| redirect(StatusCode::PERMANENT_REDIRECT, location);
+        assert_eq!(rules.apply("/About/Team/?x=Y").unwrap(), moved("/about/team?x=Y"));
+        assert_eq!(rules.apply("/about/").unwrap(), moved("/about"));
+        assert_eq!(rules.apply("/").unwrap(), path("/"));
+
+        rules.lowercase(false).trailing_slash(Some(TrailingSlash::Add));
+        assert_eq!(rules.apply("/Docs").unwrap(), moved("/Docs/"));
+        assert_eq!(rules.apply("/docs/index.html").unwrap(), path("/docs/index.html"));
+    }
+
+    #[test]
+    fn detects_loops() {
+        let mut rules = RewriteRules::new();
+        rules.rewrite("^/a$", "/b").unwrap().rewrite("^/b$", "/a").unwrap();
+        assert!(rules.apply("/a").is_err());
+
+        let mut rules = RewriteRules::new();
+        rules.rewrite("^/(.*)$", "/index.php?page=$1").unwrap();
+        assert_eq!(rules.apply("/home").unwrap(), path("/index.php?page=home"));
+
+        let mut rules = RewriteRules::new();
+        rules.rewrite("^/(x*)$", "/x$1").unwrap();
+        assert!(rules.apply("/").is_err());
+    }
+
+    #[test]
+    fn from_toml() {
+        let rules = RewriteRules::from_toml(
+            r#"
+            lowercase = true
+
+            [[rule]]
+            pattern = "^/old$"
+            redirect = "/new"
+            status = 308
+
+            [[rule]]
+            pattern = "^/p/(.*)$"
+            rewrite = "/pages/$1"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(rules.apply("/old").unwrap(), redirect(StatusCode::PERMANENT_REDIRECT, "/new"));
+        assert_eq!(rules.apply("/p/x").unwrap(), path("/pages/x"));
+        assert_eq!(rules.apply("/P/x").unwrap(), redirect(StatusCode::PERMANENT_REDIRECT, "/p/x"));
+
+        assert!(RewriteRules::from_toml("[[rule]]\npattern = \"(\"\nrewrite = \"/\"").is_err());
+        assert!(RewriteRules::from_toml(
+            "[[rule]]\npattern = \"/\"\nredirect = \"/\"\nstatus = 200"
+        )
+        .is_err());
+        assert!(RewriteRules::from_toml(
+            "[[rule]]\npattern = \"/\"\nredirect = \"/\"\nstatus = 30"
+        )
+        .is_err());
+        assert!(RewriteRules::from_toml("[[rule]]\npattern = \"/\"").is_err());
+    }
+}
@@ -1,56 +1,380 @@
-use std::collections::HashMap;
+use std::{
+    future::{ready, Future, Ready},
+    path::PathBuf,
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+};
 
-use eyre::Result;
+use eyre::{Report, Result};
 
-use crate::http::{
-    HttpRequest, HttpResponse, ResponseHeaders, StatusCode, ACCEPT_ENCODING, CONTENT_ENCODING,
-    CONTENT_TYPE, CT_APPLICATION_OCTET_STREAM, CT_TEXT_PLAIN, ENCODING_GZIP, METHOD_GET,
-    METHOD_POST, USER_AGENT,
+use crate::{
+    body::MAX_BUFFERED_BODY,
+    error::{RouterError, ServerError},
+    extract::AppState,
+    files::{file_router, serve_site, PublicDir, StaticSite, WriteLocks},
+    guard::Guard,
+    http::{
+        HttpRequest, HttpResponse, ResponseHeaders, StatusCode, ALLOW, CONTENT_TYPE, CT_TEXT_PLAIN,
+        METHOD_DELETE, METHOD_GET, METHOD_HEAD, METHOD_OPTIONS, METHOD_PATCH, METHOD_POST,
+        METHOD_PUT, USER_AGENT,
+    },
+    middleware::{Compression, ErrorPages, Middleware, Next},
+    pattern::{split_path, RoutePattern, Segment},
+    rewrite::{RewriteRules, Rewritten},
+    trie::RouteTrie,
+    webdav::{webdav_router, LockTable, DAV_PREFIX},
 };
 
+/// The wildcard that captures the remainder of the path below a nested router's prefix.
+const NESTED_PATH_PARAM: &str = "__nested_path";
+
 /// Represents a router that handles HTTP requests.
 pub struct Router {
-    routes: Vec<Route>,
+    routes:        Vec<Route>,
+    trie:          RouteTrie<usize>,
+    middleware:    Vec<Arc<dyn Middleware>>,
+    error_handler: ErrorHandler,
+    fallback:      Option<RouteHandler>,
+    rewrites:      Option<Arc<RewriteRules>>,
+    state:         Arc<AppState>,
+}
+
+impl Default for Router {
+    fn default() -> Self { Self::new() }
 }
 
 impl Router {
     /// Creates a new `Router`.
-    pub fn new() -> Self { Self { routes: Vec::new() } }
+    pub fn new() -> Self {
+        Self {
+            routes:        Vec::new(),
+            trie:          RouteTrie::new(),
+            middleware:    Vec::new(),
+            error_handler: Arc::new(HttpResponse::from_error),
+            fallback:      None,
+            rewrites:      None,
+            state:         Arc::default(),
+        }
+    }
+
+    /// Sets the function that turns errors returned by route handlers into responses.
+    ///
+    /// The default is [`HttpResponse::from_error`]; [`crate::problem::problem_details`] produces
+    /// RFC 9457 `application/problem+json` bodies instead. The full cause chain of every
+    /// handler error is logged before the error handler runs.
+    ///
+    /// # Arguments
+    ///
+    /// * `handler` - The error handler.
+    pub fn error_handler<F>(&mut self, handler: F) -> &mut Self
+    where F: Fn(&Report) -> HttpResponse + Send + Sync + 'static {
+        self.error_handler = Arc::new(handler);
+        self
+    }
+
+    /// Sets the handler for requests whose path matches no route, instead of a bodiless 404.
+    ///
+    /// The fallback gets the body as it arrived, like a route set to
+    /// [`stream_body`](Route::stream_body), so it can hand the request on to another router;
+    /// [`HttpRequest::buffer_body`] reads it whole.
+    ///
+    /// # Arguments
+    ///
+    /// * `handler` - The fallback handler function.
+    pub fn fallback<F, Fut>(&mut self, handler: F) -> &mut Self
+    where
+        F: Fn(HttpRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future + Send + 'static,
+        Fut::Output: HandlerOutput, {
+        self.fallback = Some(boxed(handler));
+        self
+    }
+
+    /// Sets the redirect and rewrite rules applied to every request before it is routed.
+    ///
+    /// Redirects are answered through this router's middleware; rewritten requests are routed
+    /// by their new path.
+    ///
+    /// # Arguments
+    ///
+    /// * `rules` - The rules, see [`RewriteRules`].
+    pub fn rewrites(&mut self, rules: RewriteRules) -> &mut Self {
+        self.rewrites = Some(Arc::new(rules));
+        self
+    }
+
+    /// Registers shared state, replacing any previous state of the same type.
+    ///
+    /// Handlers read it with the [`State`](crate::extract::State) extractor or from
+    /// `request.state`.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The state, e.g. a connection pool, configuration or counters.
+    pub fn state<T: Send + Sync + 'static>(&mut self, value: T) -> &mut Self {
+        Arc::make_mut(&mut self.state).insert(value);
+        self
+    }
+
+    /// Adds a middleware that wraps every request handled by this router.
+    ///
+    /// Router middleware runs in registration order, so the first one added is the outermost,
+    /// and always runs before route middleware. It also wraps the 404 and 405 responses
+    /// produced when no route handles the request.
+    ///
+    /// # Arguments
+    ///
+    /// * `middleware` - The middleware to add.
+    pub fn layer<M: Middleware>(&mut self, middleware: M) -> &mut Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Mounts a router under a path prefix.
+    ///
+    /// Requests below the prefix are resolved by `router` as if the prefix were not there, so
+    /// a route `/users` nested at `/api/v1` answers `/api/v1/users` and sees the path `/users`.
+    /// The nested router runs its own middleware, fallback and error handler inside this
+    /// router's middleware, and sees this router's state alongside its own. Parameters captured
+    /// by the prefix, e.g. `/orgs/:org`, are kept on the request. Routes of this router that
+    /// are more specific than the prefix take precedence.
+    ///
+    /// # Arguments
+    ///
+    /// * `prefix` - The mount point, made of static segments and `:name` parameters.
+    /// * `router` - The router to mount.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the mount route, or an error if the prefix is invalid or already
+    /// taken.
+    pub fn nest(&mut self, prefix: &str, router: Router) -> Result<&mut Route> {
+        let prefix_pattern = RoutePattern::parse(prefix)?;
+        if !prefix_pattern
+            .segments()
+            .iter()
+            .all(|s| matches!(s, Segment::Static(_) | Segment::Param(_)))
+        {
+            return Err(
+                ServerError::RouterError(RouterError::InvalidPattern(prefix.to_string())).into()
+            );
+        }
+        let depth = prefix_pattern.segments().len();
+        let path = format!("{}/*{}", prefix.trim_end_matches('/'), NESTED_PATH_PARAM);
+        let router = Arc::new(router);
+        let handler: RouteHandler = Arc::new(move |mut request: HttpRequest| {
+            request.params.remove(NESTED_PATH_PARAM);
+            request.line.path = strip_path_prefix(&request.line.path, depth);
+            let router = router.clone();
+            Box::pin(async move { router.resolve(request).await })
+        });
+        // The nested router buffers the body for those of its routes that need it.
+        Ok(self.add_route(Route::new(None, &path, handler)?)?.stream_body())
+    }
+
+    /// Moves every route of another router into this one.
+    ///
+    /// The other router's middleware keeps wrapping its own routes, and its state is added to
+    /// this router's. Its fallback and rewrite rules are taken over only where this router has
+    /// none, and its routes' errors go through this router's error handler; [`nest`](Self::nest)
+    /// keeps all of them apart instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `router` - The router to merge.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success, or a `RouteConflict` error if a route of `router`
+    /// conflicts with an existing one, in which case this router is left unchanged.
+    pub fn merge(&mut self, router: Router) -> Result<&mut Self> {
+        for route in &router.routes {
+            self.trie.check(
+                &route.pattern,
+                &self.routes.len(),
+                overlapping(&self.routes, route),
+            )?;
+        }
+        for mut route in router.routes {
+            route.middleware.splice(0..0, router.middleware.iter().cloned());
+            self.add_route(route)?;
+        }
+        if self.fallback.is_none() {
+            self.fallback = router.fallback;
+        }
+        if self.rewrites.is_none() {
+            self.rewrites = router.rewrites;
+        }
+        Arc::make_mut(&mut self.state).extend(&router.state);
+        Ok(self)
+    }
 
     /// Adds a route to the router.
     ///
     /// # Arguments
     ///
     /// * `route` - The route to add.
-    pub fn add_route(&mut self, route: Route) { self.routes.push(route); }
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the added route, or a `RouteConflict` error if an existing
+    /// unguarded route with the same path shape accepts the same method. Guarded routes never
+    /// conflict, so any number of them can share a path with one unguarded default, registered
+    /// in any order.
+    pub fn add_route(&mut self, route: Route) -> Result<&mut Route> {
+        self.trie.insert(&route.pattern, self.routes.len(), overlapping(&self.routes, &route))?;
+        self.routes.push(route);
+        Ok(self.routes.last_mut().expect("route was just added"))
+    }
 
-    /// Creates a route with a handler function.
+    /// Creates a route with a handler function that accepts any method.
     ///
     /// # Arguments
     ///
-    /// * `path` - The path for the route.
+    /// * `path` - The path pattern for the route, see [`RoutePattern::parse`].
     /// * `handler` - The handler function for the route.
-    pub fn create_route<F>(&mut self, path: &str, handler: F)
-    where F: Fn(&HttpRequest) -> HttpResponse + Send + Sync + 'static {
-        self.add_route(Route::new(path, Box::new(handler)));
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the added route, or an error if the path pattern is invalid or
+    /// conflicts with an existing route.
+    pub fn create_route<F, Fut>(&mut self, path: &str, handler: F) -> Result<&mut Route>
+    where
+        F: Fn(HttpRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future + Send + 'static,
+        Fut::Output: HandlerOutput, {
+        self.add_route(Route::new(None, path, boxed(handler))?)
+    }
+
+    /// Creates a route with a handler function for a single method.
+    ///
+    /// # Arguments
+    ///
+    /// * `method` - The method the route responds to.
+    /// * `path` - The path pattern for the route, see [`RoutePattern::parse`].
+    /// * `handler` - The handler function for the route.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the added route, or an error if the path pattern is invalid or
+    /// conflicts with an existing route.
+    pub fn route<F, Fut>(&mut self, method: &str, path: &str, handler: F) -> Result<&mut Route>
+    where
+        F: Fn(HttpRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future + Send + 'static,
+        Fut::Output: HandlerOutput, {
+        self.add_route(Route::new(Some(method), path, boxed(handler))?)
     }
 
-    /// Parses the path from a URL.
+    /// Creates a route for a single method that only handles requests passing a guard.
+    ///
+    /// The guard is checked after the path has matched. Guarded routes are tried before the
+    /// unguarded route of the same path, and when the guards of every route matching a path
+    /// fail, less specific routes, e.g. `/docs/:page` for `/docs/internal`, are tried next.
     ///
     /// # Arguments
     ///
-    /// * `path` - The URL path to parse.
+    /// * `method` - The method the route responds to.
+    /// * `path` - The path pattern for the route, see [`RoutePattern::parse`].
+    /// * `guard` - The guard requests must pass, see [`Guard`].
+    /// * `handler` - The handler function for the route.
     ///
     /// # Returns
     ///
-    /// The parsed path as a string.
-    pub fn parse_path(&self, path: &str) -> String {
-        let path_without_prefix = path.trim_start_matches('/');
-        path_without_prefix.split('/').next().unwrap_or_default().to_string()
+    /// A `Result` containing the added route, or an error if the path pattern is invalid or
+    /// conflicts with an existing route.
+    pub fn route_with_guard<F, Fut>(
+        &mut self,
+        method: &str,
+        path: &str,
+        guard: Guard,
+        handler: F,
+    ) -> Result<&mut Route>
+    where
+        F: Fn(HttpRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future + Send + 'static,
+        Fut::Output: HandlerOutput,
+    {
+        let mut route = Route::new(Some(method), path, boxed(handler))?;
+        route.guard = Some(guard);
+        self.add_route(route)
+    }
+
+    /// Creates a `GET` route.
+    pub fn get<F, Fut>(&mut self, path: &str, handler: F) -> Result<&mut Route>
+    where
+        F: Fn(HttpRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future + Send + 'static,
+        Fut::Output: HandlerOutput, {
+        self.route(METHOD_GET, path, handler)
+    }
+
+    /// Creates a `HEAD` route.
+    pub fn head<F, Fut>(&mut self, path: &str, handler: F) -> Result<&mut Route>
+    where
+        F: Fn(HttpRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future + Send + 'static,
+        Fut::Output: HandlerOutput, {
+        self.route(METHOD_HEAD, path, handler)
+    }
+
+    /// Creates a `POST` route.
+    pub fn post<F, Fut>(&mut self, path: &str, handler: F) -> Result<&mut Route>
+    where
+        F: Fn(HttpRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future + Send + 'static,
+        Fut::Output: HandlerOutput, {
+        self.route(METHOD_POST, path, handler)
+    }
+
+    /// Creates a `PUT` route.
+    pub fn put<F, Fut>(&mut self, path: &str, handler: F) -> Result<&mut Route>
+    where
+        F: Fn(HttpRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future + Send + 'static,
+        Fut::Output: HandlerOutput, {
+        self.route(METHOD_PUT, path, handler)
+    }
+
+    /// Creates a `PATCH` route.
+    pub fn patch<F, Fut>(&mut self, path: &str, handler: F) -> Result<&mut Route>
+    where
+        F: Fn(HttpRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future + Send + 'static,
+        Fut::Output: HandlerOutput, {
+        self.route(METHOD_PATCH, path, handler)
+    }
+
+    /// Creates a `DELETE` route.
+    pub fn delete<F, Fut>(&mut self, path: &str, handler: F) -> Result<&mut Route>
+    where
+        F: Fn(HttpRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future + Send + 'static,
+        Fut::Output: HandlerOutput, {
+        self.route(METHOD_DELETE, path, handler)
+    }
+
+    /// Creates an `OPTIONS` route.
+    pub fn options<F, Fut>(&mut self, path: &str, handler: F) -> Result<&mut Route>
+    where
+        F: Fn(HttpRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future + Send + 'static,
+        Fut::Output: HandlerOutput, {
+        self.route(METHOD_OPTIONS, path, handler)
     }
 
     /// Resolves an HTTP request to a response.
     ///
+    /// Rewrite rules, if any, run first and may answer with a redirect. The path is matched against
+    /// the route trie, which prefers static segments over parameters and parameters over
+    /// wildcards. The route for the request's method then handles it, with the captured path
+    /// parameters stored on the request. Routes whose guards fail are skipped, and guarded
+    /// routes are tried before unguarded ones; when no route of the most specific match
+    /// accepts the method and passes its guard, less specific matches are tried. If the path
+    /// matches only routes for other methods, the response is a 405 with an `Allow` header
+    /// listing them; if no pattern matches at all, the fallback handles it, or the response is
+    /// a 404.
+    ///
     /// # Arguments
     ///
     /// * `request` - The HTTP request to resolve.
@@ -58,22 +382,251 @@ impl Router {
     /// # Returns
     ///
     /// A `Result` containing the HTTP response or an error.
-    pub fn resolve(&self, request: &HttpRequest) -> Result<HttpResponse> {
-        for route in &self.routes {
-            if self.parse_path(&request.line.path) == self.parse_path(&route.path) {
-                return Ok((route.handler)(request));
+    pub async fn resolve(&self, mut request: HttpRequest) -> Result<HttpResponse> {
+        if request.state.is_empty() {
+            request.state = self.state.clone();
+        } else if !self.state.is_empty() {
+            Arc::make_mut(&mut request.state).extend(&self.state);
+        }
+        let mut chain = self.middleware.clone();
+        let rewritten = match &self.rewrites {
+            Some(rules) => rules.apply(&request.line.path),
+            None => Ok(Rewritten::Path(request.line.path.clone())),
+        };
+        let endpoint = match rewritten {
+            Ok(Rewritten::Path(path)) => {
+                request.line.path = path;
+                self.route_endpoint(&mut request, &mut chain)
+            },
+            Ok(Rewritten::Redirect(status_code, location)) =>
+                respond(move || HttpResponse::redirect(status_code, &location)),
+            Err(error) => {
+                // Next runs the endpoint at most once, so the error can be moved out on that call.
+                let error = Mutex::new(Some(error));
+                self.endpoint(Arc::new(move |_| {
+                    let error = error.lock().unwrap_or_else(PoisonError::into_inner).take();
+                    Box::pin(ready(Err(error.expect("the endpoint runs once per request"))))
+                }))
+            },
+        };
+        Ok(Next::new(chain, endpoint).run(request).await)
+    }
+
+    /// Picks the endpoint for a request by its path, method and guards, storing the captured
+    /// path parameters on the request and adding the route's middleware to `chain`.
+    fn route_endpoint(
+        &self,
+        request: &mut HttpRequest,
+        chain: &mut Vec<Arc<dyn Middleware>>,
+    ) -> Endpoint {
+        let method_matches = |route: &Route| {
+            route.method.as_ref().is_none_or(|method| *method == request.line.method)
+        };
+        let passes = |route: &Route| route.guard.as_ref().is_none_or(|guard| guard.check(request));
+        // A match with no route for the method, or whose guards all fail, falls back to less
+        // specific ones, e.g. from a static segment to a parameter.
+        let matched = self.trie.lookup_by(&request.line.path, |indices| {
+            indices
+                .iter()
+                .map(|index| &self.routes[*index])
+                .any(|route| method_matches(route) && passes(route))
+        });
+        let matched = matched.and_then(|(indices, params)| {
+            let mut routes = indices
+                .iter()
+                .map(|index| &self.routes[*index])
+                .filter(|route| method_matches(route) && passes(route))
+                .collect::<Vec<_>>();
+            // Guarded routes are more specific than the unguarded route they share a path with.
+            routes.sort_by_key(|route| route.guard.is_none());
+            routes.first().map(|route| (*route, params))
+        });
+        if let Some((route, params)) = matched {
+            request.params.extend(params);
+            chain.extend(route.middleware.iter().cloned());
+            return match request.body_stream.is_some() && !route.streams_body {
+                true => self.endpoint(buffered(route.handler.clone())),
+                false => self.endpoint(route.handler.clone()),
+            };
+        }
+        // Every match is offered to the closure, which collects the methods that routes of the
+        // path accept and then refuses it.
+        let (mut matched, mut allowed) = (false, Vec::new());
+        self.trie.lookup_by(&request.line.path, |indices| {
+            for route in
+                indices.iter().map(|index| &self.routes[*index]).filter(|route| passes(route))
+            {
+                matched = true;
+                allowed.extend(route.method.clone());
             }
+            false
+        });
+        if !matched {
+            return match &self.fallback {
+                Some(fallback) => self.endpoint(fallback.clone()),
+                None => respond(HttpResponse::not_found),
+            };
         }
-        Ok(HttpResponse::not_found())
+        allowed.sort_unstable();
+        allowed.dedup();
+        let allowed = allowed.join(", ");
+        respond(move || {
+            let mut response = HttpResponse::method_not_allowed();
+            response.headers.insert(ALLOW.to_string(), allowed.clone());
+            response
+        })
     }
+
+    /// Wraps a route handler so that its errors are logged and mapped by the error handler.
+    fn endpoint(&self, handler: RouteHandler) -> Endpoint {
+        let error_handler = self.error_handler.clone();
+        Arc::new(move |request| {
+            let route = format!("{} {}", request.line.method, request.line.path);
+            let response = handler(request);
+            let error_handler = error_handler.clone();
+            Box::pin(async move {
+                response.await.unwrap_or_else(|error| {
+                    eprintln!("Handler error for {}: {:?}", route, error);
+                    error_handler(&error)
+                })
+            })
+        })
+    }
+}
+
+/// Wraps a route handler so that a streamed body is read into [`HttpRequest::body`] before
+/// the handler runs.
+fn buffered(handler: RouteHandler) -> RouteHandler {
+    Arc::new(move |mut request: HttpRequest| {
+        let handler = handler.clone();
+        Box::pin(async move {
+            request.buffer_body(MAX_BUFFERED_BODY).await?;
+            handler(request).await
+        })
+    })
 }
 
-pub type RouteHandler = Box<dyn Fn(&HttpRequest) -> HttpResponse + Send + Sync>;
+pub type HandlerFuture = Pin<Box<dyn Future<Output = HttpResponse> + Send>>;
+pub type Endpoint = Arc<dyn Fn(HttpRequest) -> HandlerFuture + Send + Sync>;
+pub type RouteFuture = Pin<Box<dyn Future<Output = Result<HttpResponse>> + Send>>;
+pub type RouteHandler = Arc<dyn Fn(HttpRequest) -> RouteFuture + Send + Sync>;
+pub type ErrorHandler = Arc<dyn Fn(&Report) -> HttpResponse + Send + Sync>;
+
+/// The value a route handler may return: a response, or a `Result` whose error is turned into a
+/// response by the router's error handler.
+pub trait HandlerOutput: Send + 'static {
+    /// Converts the value into a `Result`.
+    fn into_result(self) -> Result<HttpResponse>;
+}
+
+impl HandlerOutput for HttpResponse {
+    fn into_result(self) -> Result<HttpResponse> { Ok(self) }
+}
+
+impl HandlerOutput for Result<HttpResponse> {
+    fn into_result(self) -> Result<HttpResponse> { self }
+}
+
+/// Creates a handler that responds with the contents of a file, or a 404 if it cannot be read.
+///
+/// Registered as the [`Router::fallback`], it serves a single-page app's `index.html` for every
+/// unknown path.
+///
+/// # Arguments
+///
+/// * `path` - The file to serve.
+/// * `content_type` - The `Content-Type` of the file.
+pub fn serve_file(
+    path: impl Into<PathBuf>,
+    content_type: &str,
+) -> impl Fn(HttpRequest) -> HandlerFuture + Send + Sync + 'static {
+    let path = Arc::new(path.into());
+    let content_type = content_type.to_string();
+    move |_| {
+        let path = path.clone();
+        let content_type = content_type.clone();
+        Box::pin(async move {
+            let mut headers = ResponseHeaders::new();
+            headers.insert(CONTENT_TYPE.to_string(), content_type);
+            match tokio::fs::read(path.as_path()).await {
+                Ok(body) => HttpResponse::new(StatusCode::OK, &body, headers),
+                Err(_) => HttpResponse::not_found(),
+            }
+        })
+    }
+}
+
+/// Returns the conflict check for adding `route` next to `routes`: an existing route conflicts
+/// if both are unguarded and accept a common method.
+fn overlapping<'a>(routes: &'a [Route], route: &'a Route) -> impl Fn(&usize, &usize) -> bool + 'a {
+    move |existing, _| {
+        let existing = &routes[*existing];
+        let methods_overlap = match (&existing.method, &route.method) {
+            (Some(a), Some(b)) => a == b,
+            _ => true,
+        };
+        methods_overlap && existing.guard.is_none() && route.guard.is_none()
+    }
+}
+
+/// Removes the first `depth` segments from a request path, keeping the query string.
+fn strip_path_prefix(path: &str, depth: usize) -> String {
+    let (path, query) = match path.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (path, None),
+    };
+    let rest = split_path(path).skip(depth).collect::<Vec<_>>();
+    let mut stripped = format!("/{}", rest.join("/"));
+    if !rest.is_empty() && path.ends_with('/') {
+        stripped.push('/');
+    }
+    if let Some(query) = query {
+        stripped = format!("{}?{}", stripped, query);
+    }
+    stripped
+}
+
+/// Boxes an async handler function into a `RouteHandler`.
+fn boxed<F, Fut>(handler: F) -> RouteHandler
+where
+    F: Fn(HttpRequest) -> Fut + Send + Sync + 'static,
+    Fut: Future + Send + 'static,
+    Fut::Output: HandlerOutput, {
+    Arc::new(move |request| {
+        let response = handler(request);
+        Box::pin(async move { response.await.into_result() })
+    })
+}
+
+/// Creates an `Endpoint` that ignores the request and returns a fixed kind of response.
+fn respond<F>(response: F) -> Endpoint
+where F: Fn() -> HttpResponse + Send + Sync + 'static {
+    Arc::new(move |_| Box::pin(ready(response())))
+}
+
+/// Adapts a synchronous handler function so it can be registered as a route.
+///
+/// The handler runs inline on the runtime's worker thread, so it must not block; wrap blocking
+/// work in `tokio::task::spawn_blocking` inside an async handler instead.
+///
+/// # Arguments
+///
+/// * `handler` - The synchronous handler function.
+pub fn sync<F, R>(handler: F) -> impl Fn(HttpRequest) -> Ready<R> + Send + Sync + 'static
+where
+    F: Fn(&HttpRequest) -> R + Send + Sync + 'static,
+    R: HandlerOutput, {
+    move |request| ready(handler(&request))
+}
 
 /// Represents a route in the router.
 pub struct Route {
-    path:    String,
-    handler: RouteHandler,
+    method:       Option<String>,
+    pattern:      RoutePattern,
+    handler:      RouteHandler,
+    middleware:   Vec<Arc<dyn Middleware>>,
+    guard:        Option<Guard>,
+    streams_body: bool,
 }
 
 impl Route {
@@ -81,98 +634,106 @@ impl Route {
     ///
     /// # Arguments
     ///
-    /// * `path` - The path for the route.
+    /// * `method` - The method for the route, or `None` to accept any method.
+    /// * `path` - The path pattern for the route.
     /// * `handler` - The handler for the route.
     ///
     /// # Returns
     ///
-    /// A new `Route` instance.
-    pub fn new(path: &str, handler: RouteHandler) -> Self {
-        Self { path: path.to_string(), handler }
+    /// A `Result` containing the new `Route` instance, or an error if the pattern is invalid.
+    pub fn new(method: Option<&str>, path: &str, handler: RouteHandler) -> Result<Self> {
+        let method = method.map(|method| method.to_string());
+        let pattern = RoutePattern::parse(path)?;
+        Ok(Self {
+            method,
+            pattern,
+            handler,
+            middleware: Vec::new(),
+            guard: None,
+            streams_body: false,
+        })
+    }
+
+    /// Adds a middleware that wraps only this route.
+    ///
+    /// Route middleware runs in registration order, inside any router middleware.
+    ///
+    /// # Arguments
+    ///
+    /// * `middleware` - The middleware to add.
+    pub fn layer<M: Middleware>(&mut self, middleware: M) -> &mut Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Lets the handler read the body from [`HttpRequest::body_stream`] as it arrives, e.g. to
+    /// write an upload to disk without holding it in memory.
+    ///
+    /// Other routes get a body that was not read along with the request head buffered into
+    /// [`HttpRequest::body`] first, and one over [`MAX_BUFFERED_BODY`] bytes is refused with
+    /// 413 Content Too Large.
+    pub fn stream_body(&mut self) -> &mut Self {
+        self.streams_body = true;
+        self
     }
 }
 
 /// Creates a router with predefined routes.
 ///
+/// The public directory is served under `/files` and, once [`WebDav`](crate::webdav::WebDav) is
+/// registered as state, to WebDAV clients under [`DAV_PREFIX`]. Paths matching no route go to
+/// [`serve_site`], which serves the public directory once a [`StaticSite`] is registered as state,
+/// and `/` serves its index then.
+///
 /// # Arguments
 ///
 /// * `pub_dir` - The public directory for file routes.
 ///
 /// # Returns
 ///
-/// A `Router` instance.
-pub fn make_router(pub_dir: &str) -> Router {
-    let pub_dir = pub_dir.to_string();
+/// A `Result` containing the `Router` instance.
+pub fn make_router(pub_dir: &str) -> Result<Router> {
     let mut router = Router::new();
+    router
+        .layer(Compression)
+        .layer(ErrorPages::load(pub_dir)?)
+        .state(PublicDir(pub_dir.to_string()))
+        .state(WriteLocks::new())
+        .state(LockTable::new());
 
-    router.create_route("/", |request| match request.line.method.as_str() {
-        METHOD_GET => HttpResponse::ok(b"", ResponseHeaders::new()),
-        _ => HttpResponse::method_not_allowed(),
-    });
+    router.get("/", |request: HttpRequest| async move {
+        match request.state.get::<StaticSite>() {
+            Some(_) => serve_site(request).await,
+            None => Ok(HttpResponse::ok(b"", ResponseHeaders::new())),
+        }
+    })?;
 
-    router.create_route("/echo", move |request| match request.line.method.as_str() {
-        METHOD_GET => {
-            let path_without_prefix = request.line.path.trim_start_matches("/echo/");
+    router.get(
+        "/echo/*message",
+        sync(|request| {
+            let message = request.param("message").unwrap_or_default();
             let mut headers = ResponseHeaders::new();
             headers.insert(CONTENT_TYPE.to_string(), CT_TEXT_PLAIN.to_string());
-            accept_encoding(request, &mut headers);
-            HttpResponse::ok(path_without_prefix.as_bytes(), headers)
-        },
-        _ => HttpResponse::method_not_allowed(),
-    });
-
-    router.create_route("/user-agent", move |request| match request.line.method.as_str() {
-        METHOD_GET => {
+            HttpResponse::ok(message.as_bytes(), headers)
+        }),
+    )?;
+
+    router.get(
+        "/user-agent",
+        sync(|request| {
             let default = String::new();
             let user_agent = request.headers.get(USER_AGENT).unwrap_or(&default);
             let mut headers = ResponseHeaders::new();
             headers.insert(CONTENT_TYPE.to_string(), CT_TEXT_PLAIN.to_string());
-            accept_encoding(request, &mut headers);
             HttpResponse::ok(user_agent.as_bytes(), headers)
-        },
-        _ => HttpResponse::method_not_allowed(),
-    });
-
-    router.create_route("/files", move |request| {
-        let mut headers = ResponseHeaders::new();
-        headers.insert(CONTENT_TYPE.to_string(), CT_APPLICATION_OCTET_STREAM.to_string());
-        accept_encoding(request, &mut headers);
-        let file = request.line.path.trim_start_matches("/files/");
-        let file = format!("{}/{}", pub_dir, file);
-        match request.line.method.as_str() {
-            METHOD_GET => match std::fs::read(&file) {
-                Ok(body) => HttpResponse::new(StatusCode::OK, &body, headers),
-                Err(_) => HttpResponse::not_found(),
-            },
-            METHOD_POST => {
-                let request_body = request.body.clone();
-                match std::fs::write(&file, request_body) {
-                    Ok(_) => HttpResponse::created(),
-                    Err(_) => HttpResponse::internal_server_error(),
-                }
-            },
-            _ => HttpResponse::method_not_allowed(),
-        }
-    });
+        }),
+    )?;
 
-    router
-}
+    router.nest("/files", file_router()?)?;
+    router.nest(DAV_PREFIX, webdav_router(DAV_PREFIX)?)?;
+    router.fallback(serve_site);
 
-/// Adds the appropriate encoding to the response headers based on the request.
-///
-/// # Arguments
-///
-/// * `request` - The HTTP request.
-/// * `headers` - The response headers to modify.
-fn accept_encoding(request: &HttpRequest, headers: &mut HashMap<String, String>) {
-    if let Some(encoding_str) = request.headers.get(ACCEPT_ENCODING) {
-        let encodings = encoding_str.split(", ").map(|s| s.trim()).filter(|s| !s.is_empty());
-        for encoding in encodings {
-            if encoding == ENCODING_GZIP {
-                headers.insert(CONTENT_ENCODING.to_string(), encoding.to_string());
-            }
-        }
-    }
+    Ok(router)
 }
 
 #[cfg(test)]
@@ -180,37 +741,356 @@ mod test {
     use tempdir::TempDir;
 
     use super::*;
-    use crate::http::StatusCode;
+    use crate::{
+        extract::{FromRequest, State},
+        form::MultipartLimits,
+        http::{StatusCode, CT_TEXT_HTML, ETAG, LAST_MODIFIED, LOCATION},
+    };
 
     const TEST_PUBLIC_DIR: &str = "/tmp/test_public";
 
-    fn make_test_router() -> Router { make_router(TEST_PUBLIC_DIR) }
+    fn make_test_router() -> Router { make_router(TEST_PUBLIC_DIR).unwrap() }
 
-    #[test]
-    fn test_router_parse_path() {
-        let router = make_test_router();
-        assert_eq!(router.parse_path("/"), "");
-        assert_eq!(router.parse_path("/echo"), "echo");
-        assert_eq!(router.parse_path("/echo/"), "echo");
-        assert_eq!(router.parse_path("/echo/123"), "echo");
+    #[tokio::test]
+    async fn test_router_path_params() {
+        let mut router = Router::new();
+        router
+            .create_route(
+                "/users/:id",
+                sync(|request| {
+                    HttpResponse::ok(
+                        request.param("id").unwrap().as_bytes(),
+                        ResponseHeaders::new(),
+                    )
+                }),
+            )
+            .unwrap();
+        let request = HttpRequest::from_string("GET /users/42 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(router.resolve(request).await.unwrap().body, b"42");
+        let request = HttpRequest::from_string("GET /users/42/posts HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(router.resolve(request).await.unwrap().status_code, StatusCode::NOT_FOUND);
+        assert!(router
+            .create_route("/files/*path/more", sync(|_| HttpResponse::not_found()))
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_router_async_handler() {
+        let mut router = Router::new();
+        router
+            .get("/slow/:ms", |request| async move {
+                let ms = request.param("ms").unwrap().parse().unwrap();
+                tokio::time::sleep(std::time::Duration::from_millis(ms)).await;
+                HttpResponse::ok(b"done", ResponseHeaders::new())
+            })
+            .unwrap();
+        let request = HttpRequest::from_string("GET /slow/5 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(router.resolve(request).await.unwrap().body, b"done");
+    }
+
+    #[tokio::test]
+    async fn test_router_fallible_handler() {
+        let mut router = Router::new();
+        router
+            .get("/json", |request: HttpRequest| async move {
+                let value = request.json::<serde_json::Value>()?;
+                HttpResponse::json(StatusCode::OK, &value)
+            })
+            .unwrap();
+        router
+            .get("/broken", |_| async { Err::<HttpResponse, _>(eyre::eyre!("database is down")) })
+            .unwrap();
+
+        let request = HttpRequest::from_string("GET /json HTTP/1.1\r\n\r\n").unwrap();
+        let response = router.resolve(request).await.unwrap();
+        assert_eq!(response.status_code, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let request = HttpRequest::from_string("GET /broken HTTP/1.1\r\n\r\n").unwrap();
+        let response = router.resolve(request).await.unwrap();
+        assert_eq!(response.status_code, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(response.body, b"");
+
+        router.error_handler(crate::problem::problem_details);
+        let request = HttpRequest::from_string(
+            "GET /json HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: 3\r\n\r\n{x}",
+        )
+        .unwrap();
+        let response = router.resolve(request).await.unwrap();
+        assert_eq!(response.status_code, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response.headers.get(CONTENT_TYPE).unwrap(),
+            crate::problem::CT_APPLICATION_PROBLEM_JSON
+        );
+    }
+
+    #[tokio::test]
+    async fn test_router_nest() {
+        let echo_path = |request: &HttpRequest| {
+            let body = format!("{} {:?}", request.line.path, request.param("org"));
+            HttpResponse::ok(body.as_bytes(), ResponseHeaders::new())
+        };
+        let mut api = Router::new();
+        api.layer(|request: HttpRequest, next: Next| async move {
+            let mut response = next.run(request).await;
+            response.headers.insert("X-Api".to_string(), "v1".to_string());
+            response
+        });
+        api.get("/users/:id", sync(echo_path)).unwrap();
+        api.fallback(sync(|_| HttpResponse::ok(b"api fallback", ResponseHeaders::new())));
+        let mut router = Router::new();
+        router.nest("/orgs/:org/api", api).unwrap();
+        router.get("/orgs/:org/api/health", sync(echo_path)).unwrap();
+
+        let request =
+            HttpRequest::from_string("GET /orgs/acme/api/users/1?x=1 HTTP/1.1\r\n\r\n").unwrap();
+        let response = router.resolve(request).await.unwrap();
+        assert_eq!(response.body, b"/users/1?x=1 Some(\"acme\")");
+        assert_eq!(response.headers.get("X-Api").unwrap(), "v1");
+        let request =
+            HttpRequest::from_string("GET /orgs/acme/api/health HTTP/1.1\r\n\r\n").unwrap();
+        let response = router.resolve(request).await.unwrap();
+        assert_eq!(response.body, b"/orgs/acme/api/health Some(\"acme\")");
+        assert!(!response.headers.contains_key("X-Api"));
+        let request = HttpRequest::from_string("GET /orgs/acme/api/nope HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(router.resolve(request).await.unwrap().body, b"api fallback");
+        let request = HttpRequest::from_string("GET /orgs/acme HTTP/1.1\r\n\r\n").unwrap();
+        let response = router.resolve(request).await.unwrap();
+        assert_eq!(response.status_code, StatusCode::NOT_FOUND);
+
+        assert!(router.nest("/orgs/:org/api", Router::new()).is_err());
+        assert!(router.nest("/static/*path", Router::new()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_router_merge() {
+        let mut admin = Router::new();
+        admin.state(PublicDir("/srv".to_string()));
+        admin.layer(|request: HttpRequest, next: Next| async move {
+            match request.headers.get("Authorization") {
+                Some(_) => next.run(request).await,
+                None => HttpResponse::from_status_code(StatusCode::BAD_REQUEST),
+            }
+        });
+        admin
+            .get("/admin", |request: HttpRequest| async move {
+                let State(PublicDir(dir)) = State::from_request(&request)?;
+                Ok(HttpResponse::ok(dir.as_bytes(), ResponseHeaders::new()))
+            })
+            .unwrap();
+        let mut router = Router::new();
+        router.get("/", sync(|_| HttpResponse::ok(b"home", ResponseHeaders::new()))).unwrap();
+        router.merge(admin).unwrap();
+
+        let request = HttpRequest::from_string("GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(router.resolve(request).await.unwrap().body, b"home");
+        let request = HttpRequest::from_string("GET /admin HTTP/1.1\r\n\r\n").unwrap();
+        let response = router.resolve(request).await.unwrap();
+        assert_eq!(response.status_code, StatusCode::BAD_REQUEST);
+        let request =
+            HttpRequest::from_string("GET /admin HTTP/1.1\r\nAuthorization: yes\r\n\r\n").unwrap();
+        assert_eq!(router.resolve(request).await.unwrap().body, b"/srv");
+
+        let mut other = Router::new();
+        other.get("/fine", sync(|_| HttpResponse::ok(b"fine", ResponseHeaders::new()))).unwrap();
+        other.get("/", sync(|_| HttpResponse::not_found())).unwrap();
+        assert!(router.merge(other).is_err());
+        let request = HttpRequest::from_string("GET /fine HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(router.resolve(request).await.unwrap().status_code, StatusCode::NOT_FOUND);
+
+        let mut other = Router::new();
+        other.fallback(sync(|_| HttpResponse::ok(b"fallback", ResponseHeaders::new())));
+        router.merge(other).unwrap();
+        let request = HttpRequest::from_string("GET /nope HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(router.resolve(request).await.unwrap().body, b"fallback");
+    }
+
+    #[tokio::test]
+    async fn test_router_spa_fallback() {
+        let dir = TempDir::new("spa").unwrap();
+        std::fs::write(dir.path().join("index.html"), "<div id=app>").unwrap();
+        std::fs::write(dir.path().join("404.html"), "<h1>{{status}} {{reason}}</h1>").unwrap();
+        let mut router = make_router(dir.path().to_str().unwrap()).unwrap();
+
+        let request = HttpRequest::from_string("GET /missing HTTP/1.1\r\n\r\n").unwrap();
+        let response = router.resolve(request).await.unwrap();
+        assert_eq!(response.status_code, StatusCode::NOT_FOUND);
+        assert_eq!(response.body, b"<h1>404 Not Found</h1>");
+
+        router.fallback(serve_file(dir.path().join("index.html"), CT_TEXT_HTML));
+        let request = HttpRequest::from_string("GET /app/settings HTTP/1.1\r\n\r\n").unwrap();
+        let response = router.resolve(request).await.unwrap();
+        assert_eq!(response.status_code, StatusCode::OK);
+        assert_eq!(response.body, b"<div id=app>");
+        assert_eq!(response.headers.get(CONTENT_TYPE).unwrap(), CT_TEXT_HTML);
+    }
+
+    #[tokio::test]
+    async fn test_router_guards() {
+        let text = |body: &'static str| {
+            sync(move |_| HttpResponse::ok(body.as_bytes(), ResponseHeaders::new()))
+        };
+        let mut router = Router::new();
+        // Guarded routes may come before or after the unguarded default.
+        router.route_with_guard(METHOD_GET, "/", Guard::host("a.com"), text("site a")).unwrap();
+        router.get("/", text("default")).unwrap();
+        router
+            .route_with_guard(
+                METHOD_GET,
+                "/",
+                Guard::host("b.com").and(Guard::header("X-Api-Version", "2")),
+                text("site b v2"),
+            )
+            .unwrap();
+        router
+            .route_with_guard(
+                METHOD_POST,
+                "/upload",
+                Guard::content_type("application/json").or(Guard::query("format", "json")),
+                text("json"),
+            )
+            .unwrap();
+        router
+            .route_with_guard(
+                METHOD_GET,
+                "/docs/internal",
+                Guard::header("X-Staff", "1"),
+                text("internal"),
+            )
+            .unwrap();
+        router.get("/docs/:page", text("page")).unwrap();
+        router.get("/docs/*rest", text("rest")).unwrap();
+        router
+            .route_with_guard(METHOD_GET, "/docs/a/b", Guard::header("X-Staff", "1"), text("ab"))
+            .unwrap();
+        assert!(router.get("/", text("again")).is_err());
+
+        let body = |request: &str| {
+            let request = HttpRequest::from_string(request).unwrap();
+            async { router.resolve(request).await.unwrap() }
+        };
+        assert_eq!(body("GET / HTTP/1.1\r\nHost: a.com:80\r\n\r\n").await.body, b"site a");
+        assert_eq!(body("GET / HTTP/1.1\r\nHost: b.com\r\n\r\n").await.body, b"default");
+        assert_eq!(
+            body("GET / HTTP/1.1\r\nHost: b.com\r\nX-Api-Version: 2\r\n\r\n").await.body,
+            b"site b v2"
+        );
+        assert_eq!(body("POST /upload?format=json HTTP/1.1\r\n\r\n").await.body, b"json");
+        let response = body("POST /upload HTTP/1.1\r\nContent-Type: text/plain\r\n\r\n").await;
+        assert_eq!(response.status_code, StatusCode::NOT_FOUND);
+
+        // A failed guard falls back to less specific routes for the same path.
+        let response = body("GET /docs/internal HTTP/1.1\r\nX-Staff: 1\r\n\r\n").await;
+        assert_eq!(response.body, b"internal");
+        assert_eq!(body("GET /docs/internal HTTP/1.1\r\n\r\n").await.body, b"page");
+        assert_eq!(body("GET /docs/a/b HTTP/1.1\r\nX-Staff: 1\r\n\r\n").await.body, b"ab");
+        assert_eq!(body("GET /docs/a/b HTTP/1.1\r\n\r\n").await.body, b"rest");
+    }
+
+    #[tokio::test]
+    async fn test_router_rewrites() {
+        let mut rules = RewriteRules::new();
+        rules
+            .redirect("^/old/(.*)$", "/users/$1", StatusCode::MOVED_PERMANENTLY)
+            .unwrap()
+            .rewrite("^/u/(.*)$", "/users/$1")
+            .unwrap()
+            .rewrite("^/loop$", "/loop2")
+            .unwrap()
+            .rewrite("^/loop2$", "/loop")
+            .unwrap();
+        let loop_error = rules.apply("/loop").unwrap_err().to_string();
+        let mut router = Router::new();
+        router.rewrites(rules);
+        router
+            .get(
+                "/users/:id",
+                sync(|request| {
+                    let body = format!("{} {}", request.line.path, request.param("id").unwrap());
+                    HttpResponse::ok(body.as_bytes(), ResponseHeaders::new())
+                }),
+            )
+            .unwrap();
+
+        let resolve = |request: &str| router.resolve(HttpRequest::from_string(request).unwrap());
+        let response = resolve("GET /old/7?x=1 HTTP/1.1\r\n\r\n").await.unwrap();
+        assert_eq!(response.status_code, StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(response.headers.get(LOCATION).unwrap(), "/users/7?x=1");
+        let response = resolve("GET /u/7 HTTP/1.1\r\n\r\n").await.unwrap();
+        assert_eq!(response.body, b"/users/7 7");
+        let response = resolve("GET /loop HTTP/1.1\r\n\r\n").await.unwrap();
+        assert_eq!(response.status_code, StatusCode::INTERNAL_SERVER_ERROR);
+
+        // The error handler gets the error the rules returned.
+        router.error_handler(|error| {
+            HttpResponse::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                error.to_string().as_bytes(),
+                ResponseHeaders::new(),
+            )
+        });
+        let response =
+            router.resolve(HttpRequest::from_string("GET /loop HTTP/1.1\r\n\r\n").unwrap());
+        assert_eq!(response.await.unwrap().body, loop_error.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_router_conflicts() {
+        let mut router = Router::new();
+        router.get("/items/:id", sync(|_| HttpResponse::not_found())).unwrap();
+        router.post("/items/:id", sync(|_| HttpResponse::not_found())).unwrap();
+        assert!(router.get("/items/:id", sync(|_| HttpResponse::not_found())).is_err());
+        assert!(router.get("/items/:name", sync(|_| HttpResponse::not_found())).is_err());
+        assert!(router.create_route("/items/:id", sync(|_| HttpResponse::not_found())).is_err());
+        router.get("/items/new", sync(|_| HttpResponse::not_found())).unwrap();
+
+        // A conflicting optional parameter must not leave its other shape behind.
+        let text = |body: &'static str| {
+            sync(move |_| HttpResponse::ok(body.as_bytes(), ResponseHeaders::new()))
+        };
+        let mut router = Router::new();
+        router.get("/a/:c", text("param")).unwrap();
+        assert!(router.get("/a/:b?", text("optional")).is_err());
+        router.get("/other", text("other")).unwrap();
+        let request = HttpRequest::from_string("GET /a HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(router.resolve(request).await.unwrap().status_code, StatusCode::NOT_FOUND);
+        let request = HttpRequest::from_string("GET /other HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(router.resolve(request).await.unwrap().body, b"other");
+    }
+
+    #[tokio::test]
+    async fn test_router_static_falls_back_by_method() {
+        let text = |body: &'static str| {
+            sync(move |_| HttpResponse::ok(body.as_bytes(), ResponseHeaders::new()))
+        };
+        let mut router = Router::new();
+        router.post("/items/new", text("create")).unwrap();
+        router.get("/items/:id", text("show")).unwrap();
+        router.put("/items/*rest", text("replace")).unwrap();
+
+        let resolve = |request: &str| router.resolve(HttpRequest::from_string(request).unwrap());
+        assert_eq!(resolve("POST /items/new HTTP/1.1\r\n\r\n").await.unwrap().body, b"create");
+        assert_eq!(resolve("GET /items/new HTTP/1.1\r\n\r\n").await.unwrap().body, b"show");
+        assert_eq!(resolve("PUT /items/new HTTP/1.1\r\n\r\n").await.unwrap().body, b"replace");
+        let response = resolve("DELETE /items/new HTTP/1.1\r\n\r\n").await.unwrap();
+        assert_eq!(response.status_code, StatusCode::NOT_ALLOWED);
+        assert_eq!(response.headers.get(ALLOW).unwrap(), "GET, POST, PUT");
     }
 
-    #[test]
-    fn test_router_resolve_root() {
+    #[tokio::test]
+    async fn test_router_resolve_root() {
         let router = make_test_router();
         let request = HttpRequest::from_string(
             "GET / HTTP/1.1\r\nHost: localhost:4221\r\nUser-Agent: curl/7.64.1\r\nAccept: \
              */*\r\n\r\n",
         )
         .unwrap();
-        let response = router.resolve(&request).unwrap();
+        let response = router.resolve(request).await.unwrap();
         assert_eq!(response.status_code, StatusCode::OK);
         assert_eq!(response.body, b"");
         assert_eq!(response.to_bytes().unwrap(), b"HTTP/1.1 200 OK\r\n\r\n");
     }
 
-    #[test]
-    fn test_router_resolve_echo() {
+    #[tokio::test]
+    async fn test_router_resolve_echo() {
         let expected_body = "my_test_path";
         let router = make_test_router();
         let request = HttpRequest::from_string(&format!(
@@ -219,7 +1099,7 @@ mod test {
             expected_body
         ))
         .unwrap();
-        let response = router.resolve(&request).unwrap();
+        let response = router.resolve(request).await.unwrap();
         assert_eq!(response.status_code, StatusCode::OK);
         assert_eq!(response.body, expected_body.as_bytes());
         assert_eq!(
@@ -233,40 +1113,40 @@ mod test {
         );
     }
 
-    #[test]
-    fn test_router_resolve_not_found() {
+    #[tokio::test]
+    async fn test_router_resolve_not_found() {
         let router = make_test_router();
         let request = HttpRequest::from_string(
             "GET /not_found HTTP/1.1\r\nHost: localhost:4221\r\nUser-Agent: \
              curl/7.64.1\r\nAccept: */*\r\n\r\n",
         )
         .unwrap();
-        let response = router.resolve(&request).unwrap();
+        let response = router.resolve(request).await.unwrap();
         assert_eq!(response.status_code, StatusCode::NOT_FOUND);
         assert_eq!(response.body, b"");
         assert_eq!(response.to_bytes().unwrap(), b"HTTP/1.1 404 Not Found\r\n\r\n");
     }
 
-    #[test]
-    fn test_example() {
+    #[tokio::test]
+    async fn test_example() {
         let router = make_test_router();
         let request =
             HttpRequest::from_string("GET / HTTP/1.1\r\nHost: localhost:4221\r\n\r\n").unwrap();
-        let response = router.resolve(&request).unwrap();
+        let response = router.resolve(request).await.unwrap();
         assert_eq!(response.status_code, StatusCode::OK);
         assert_eq!(response.body, b"");
         assert_eq!(response.to_bytes().unwrap(), b"HTTP/1.1 200 OK\r\n\r\n");
     }
 
-    #[test]
-    fn test_echo_example() {
+    #[tokio::test]
+    async fn test_echo_example() {
         let router = make_test_router();
         let request = HttpRequest::from_string(
             "GET /echo/abc HTTP/1.1\r\nHost: localhost:4221\r\nUser-Agent: curl/7.64.1\r\nAccept: \
              */*\r\n\r\n",
         )
         .unwrap();
-        let response = router.resolve(&request).unwrap();
+        let response = router.resolve(request).await.unwrap();
         assert_eq!(response.status_code, StatusCode::OK);
         assert_eq!(response.body, b"abc");
         assert_eq!(
@@ -275,8 +1155,8 @@ mod test {
         );
     }
 
-    #[test]
-    fn test_user_agent() {
+    #[tokio::test]
+    async fn test_user_agent() {
         let router = make_test_router();
         let user_agent = "banana/blueberry";
         let request = HttpRequest::from_string(&format!(
@@ -285,7 +1165,7 @@ mod test {
             user_agent
         ))
         .unwrap();
-        let response = router.resolve(&request).unwrap();
+        let response = router.resolve(request).await.unwrap();
         assert_eq!(response.status_code, StatusCode::OK);
         assert_eq!(response.body, user_agent.as_bytes());
         assert_eq!(
@@ -299,72 +1179,68 @@ mod test {
         );
     }
 
-    #[test]
-    fn test_files() {
+    #[tokio::test]
+    async fn test_files() {
         let tmp_dir = TempDir::new("test_files").unwrap();
         let file_path = tmp_dir.path().join("test.txt");
         let contents = "test";
         std::fs::write(file_path, contents).unwrap();
         let tmp_dir = tmp_dir.path().to_str().unwrap();
 
-        let router = make_router(tmp_dir);
+        let router = make_router(tmp_dir).unwrap();
         let request = HttpRequest::from_string(
             "GET /files/test.txt HTTP/1.1\r\nHost: localhost:4221\r\nUser-Agent: \
              curl/7.64.1\r\nAccept: */*\r\n\r\n",
         )
         .unwrap();
-        let response = router.resolve(&request).unwrap();
+        let response = router.resolve(request).await.unwrap();
         assert_eq!(response.status_code, StatusCode::OK);
         assert_eq!(response.body, contents.as_bytes());
-        assert_eq!(
-            response.to_string().unwrap(),
-            format!(
-                "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: \
-                 4\r\n\r\n{}",
-                contents
-            )
-        );
+        assert_eq!(response.headers[CONTENT_TYPE], "text/plain; charset=utf-8");
+        assert!(response.headers[ETAG].starts_with('"'));
+        assert!(response.headers.contains_key(LAST_MODIFIED));
+        assert!(response.to_string().unwrap().ends_with("Content-Length: 4\r\n\r\ntest"));
     }
 
-    #[test]
-    fn test_files_file_not_exists() {
+    #[tokio::test]
+    async fn test_files_file_not_exists() {
         let tmp_dir = TempDir::new("test_files").unwrap();
         let tmp_dir = tmp_dir.path().to_str().unwrap();
 
-        let router = make_router(tmp_dir);
+        let router = make_router(tmp_dir).unwrap();
         let request = HttpRequest::from_string(
             "GET /files/test.txt HTTP/1.1\r\nHost: localhost:4221\r\nUser-Agent: \
              curl/7.64.1\r\nAccept: */*\r\n\r\n",
         )
         .unwrap();
-        let response = router.resolve(&request).unwrap();
+        let response = router.resolve(request).await.unwrap();
         assert_eq!(response.status_code, StatusCode::NOT_FOUND);
         assert_eq!(response.body, b"");
         assert_eq!(response.to_bytes().unwrap(), b"HTTP/1.1 404 Not Found\r\n\r\n");
     }
 
-    #[test]
-    fn test_files_post() {
+    #[tokio::test]
+    async fn test_files_post() {
         let tmp_dir = TempDir::new("test_files").unwrap();
         let file_path = tmp_dir.path().join("test.txt");
         let tmp_dir = tmp_dir.path().to_str().unwrap();
 
-        let router = make_router(tmp_dir);
+        let router = make_router(tmp_dir).unwrap();
         let request = HttpRequest::from_string(
             "POST /files/test.txt HTTP/1.1\r\nHost: localhost:4221\r\nUser-Agent: \
              curl/7.64.1\r\nContent-Length: 4\r\nContent-Type: \
              application/octet-stream\r\nAccept: */*\r\n\r\ntest",
         )
         .unwrap();
-        let response = router.resolve(&request).unwrap();
+        let response = router.resolve(request).await.unwrap();
         assert_eq!(response.status_code, StatusCode::CREATED);
         assert_eq!(response.body, b"");
         assert_eq!(response.to_bytes().unwrap(), b"HTTP/1.1 201 Created\r\n\r\n");
         assert_eq!(std::fs::read_to_string(file_path).unwrap(), "test");
     }
 
-    #[test]
-    fn test_post_file_example() {
+    #[tokio::test]
+    async fn test_post_file_example() {
         let contents = "mango banana mango grape blueberry orange banana grape";
         let filename = "strawberry_blueberry_raspberry_raspberry";
         let tmp_dir = TempDir::new("test_files").unwrap();
@@ -379,11 +1255,96 @@ mod test {
             contents
         );
         let request = HttpRequest::from_string(&request_str).unwrap();
-        let router = make_router(tmp_dir);
-        let response = router.resolve(&request).unwrap();
+        let router = make_router(tmp_dir).unwrap();
+        let response = router.resolve(request).await.unwrap();
         assert_eq!(response.status_code, StatusCode::CREATED);
         assert_eq!(response.body, b"");
         assert_eq!(response.to_bytes().unwrap(), b"HTTP/1.1 201 Created\r\n\r\n");
         assert_eq!(std::fs::read_to_string(file_path).unwrap(), contents);
     }
+
+    #[tokio::test]
+    async fn test_files_post_multipart() {
+        let tmp_dir = TempDir::new("test_files").unwrap();
+        let dir = tmp_dir.path().to_str().unwrap();
+        let body =
+            "--b0undary\r\nContent-Disposition: form-data; \
+             name=\"note\"\r\n\r\nhi\r\n--b0undary\r\nContent-Disposition: form-data; name=\"f\"; \
+             filename=\"../one.txt\"\r\n\r\nfirst\r\nfile\r\n--b0undary\r\nContent-Disposition: \
+             form-data; name=\"f\"; filename=\"two.bin\"\r\nContent-Type: \
+             application/octet-stream\r\n\r\nsecond\r\n--b0undary--\r\n";
+        let request = HttpRequest::from_string(&format!(
+            "POST /files/ HTTP/1.1\r\nHost: localhost:4221\r\nContent-Type: multipart/form-data; \
+             boundary=b0undary\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        ))
+        .unwrap();
+        let response = make_router(dir).unwrap().resolve(request).await.unwrap();
+        assert_eq!(response.status_code, StatusCode::CREATED);
+        assert_eq!(
+            std::fs::read_to_string(tmp_dir.path().join("one.txt")).unwrap(),
+            "first\r\nfile"
+        );
+        assert_eq!(std::fs::read_to_string(tmp_dir.path().join("two.bin")).unwrap(), "second");
+        assert!(!tmp_dir.path().join("note").exists());
+    }
+
+    #[tokio::test]
+    async fn test_files_post_multipart_too_large() {
+        let tmp_dir = TempDir::new("test_files").unwrap();
+        let dir = tmp_dir.path().to_str().unwrap();
+        let contents = "x".repeat(MultipartLimits::default().max_part_size + 1);
+        let body = format!(
+            "--b\r\nContent-Disposition: form-data; name=\"f\"; \
+             filename=\"big\"\r\n\r\n{}\r\n--b--\r\n",
+            contents
+        );
+        let request = HttpRequest::from_string(&format!(
+            "POST /files/ HTTP/1.1\r\nContent-Type: multipart/form-data; \
+             boundary=b\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        ))
+        .unwrap();
+        let response = make_router(dir).unwrap().resolve(request).await.unwrap();
+        assert_eq!(response.status_code, StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(!tmp_dir.path().join("big").exists());
+    }
+
+    #[tokio::test]
+    async fn test_router_method_not_allowed() {
+        let mut router = Router::new();
+        router
+            .get("/items/:id", sync(|_| HttpResponse::ok(b"get", ResponseHeaders::new())))
+            .unwrap();
+        router
+            .delete("/items/:id", sync(|_| HttpResponse::ok(b"delete", ResponseHeaders::new())))
+            .unwrap();
+        router
+            .put("/items/:id", sync(|_| HttpResponse::ok(b"put", ResponseHeaders::new())))
+            .unwrap();
+
+        let request = HttpRequest::from_string("DELETE /items/1 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(router.resolve(request).await.unwrap().body, b"delete");
+
+        let request = HttpRequest::from_string("POST /items/1 HTTP/1.1\r\n\r\n").unwrap();
+        let response = router.resolve(request).await.unwrap();
+        assert_eq!(response.status_code, StatusCode::NOT_ALLOWED);
+        assert_eq!(response.headers.get(ALLOW).unwrap(), "DELETE, GET, PUT");
+
+        let request = HttpRequest::from_string("POST /other HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(router.resolve(request).await.unwrap().status_code, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_files_method_not_allowed() {
+        let request = HttpRequest::from_string("OPTIONS /files/a.txt HTTP/1.1\r\n\r\n").unwrap();
+        let response = make_test_router().resolve(request).await.unwrap();
+        assert_eq!(response.status_code, StatusCode::NOT_ALLOWED);
+        assert_eq!(
+            response.to_bytes().unwrap(),
+            b"HTTP/1.1 405 Method Not Allowed\r\nAllow: DELETE, GET, PATCH, POST, PUT\r\n\r\n"
+        );
+    }
 }
@@ -1,17 +1,29 @@
 use std::sync::Arc;
 
-use bytes::Bytes;
+use bytes::BytesMut;
 use eyre::{Result, WrapErr};
-use futures::{SinkExt, StreamExt};
-use tokio::net::TcpListener;
-use tokio_util::codec::{BytesCodec, FramedRead, FramedWrite};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::oneshot,
+};
 
 use crate::{
-    error::{HttpError::EmptyRequestLine, ServerError},
+    body::{BodyReader, BodyStream},
+    error::{HttpError::HeadTooLarge, ServerError},
+    form::find,
     http::{HttpRequest, KEEP_ALIVE},
     router::Router,
 };
 
+/// The largest request head accepted, i.e. the request line and headers.
+pub const MAX_HEAD_LEN: usize = 64 * 1024;
+
+const HEAD_END: &[u8] = b"\r\n\r\n";
+const EXPECT: &str = "Expect";
+const CONTINUE: &str = "100-continue";
+const CONTINUE_RESPONSE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
 /// A simple HTTP server.
 #[derive(Clone)]
 pub struct Server {
@@ -30,7 +42,7 @@ impl Server {
     /// # Returns
     ///
     /// A `Result` containing the `Server` instance or an error.
-    pub(crate) fn new(addr: &str, router: Router) -> Result<Server> {
+    pub fn new(addr: &str, router: Router) -> Result<Server> {
         Ok(Self { addr: addr.to_string(), router: Arc::new(router) })
     }
 
@@ -39,7 +51,7 @@ impl Server {
     /// # Returns
     ///
     /// A `Result` indicating success or failure.
-    pub(crate) async fn listen(&self) -> Result<()> {
+    pub async fn listen(&self) -> Result<()> {
         let listener = TcpListener::bind(&self.addr).await?;
 
         loop {
@@ -56,6 +68,11 @@ impl Server {
 
     /// Handles an individual connection.
     ///
+    /// Bodies that arrive along with the request head are passed on whole; longer ones are
+    /// passed on as a [`BodyStream`] reading the rest from the connection, which is reused for
+    /// the next request only if the body was read to the end. `Expect: 100-continue` is answered
+    /// before such a body is read.
+    ///
     /// # Arguments
     ///
     /// * `stream` - The TCP stream for the connection.
@@ -64,30 +81,156 @@ impl Server {
     /// # Returns
     ///
     /// A `Result` indicating success or failure.
-    async fn handle_connection(
-        mut stream: tokio::net::TcpStream,
-        router: Arc<Router>,
-    ) -> Result<()> {
-        let (reader, writer) = stream.split();
-        let mut reader = FramedRead::new(reader, BytesCodec::new());
-        let mut writer = FramedWrite::new(writer, BytesCodec::new());
-        loop {
-            let request_bytes = reader
-                .next()
-                .await
-                .ok_or_else(|| ServerError::HttpError(EmptyRequestLine))
-                .wrap_err("Failed to request read bytes")??;
-            let request =
-                HttpRequest::from_bytes(&request_bytes).wrap_err("Failed to parse request")?;
-
-            let response = router.resolve(&request).wrap_err("Failed to resolve request")?;
+    async fn handle_connection(stream: TcpStream, router: Arc<Router>) -> Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader: Option<BodyReader> = Some(Box::new(reader));
+        let mut buffer = BytesMut::new();
+        while let Some(mut connection) = reader.take() {
+            let Some(head_len) = read_head(&mut connection, &mut buffer).await? else {
+                break;
+            };
+            let head = buffer.split_to(head_len);
+            let mut request = HttpRequest::from_head(&head[..head_len - HEAD_END.len()])
+                .wrap_err("Failed to parse request")?;
+            let keep_alive = request.connection == KEEP_ALIVE;
+
+            let len = request.content_length()?;
+            let mut returned = None;
+            if buffer.len() as u64 >= len {
+                request.body = buffer.split_to(len as usize).to_vec();
+                reader = Some(connection);
+            } else {
+                if request.headers.get(EXPECT).is_some_and(|e| e.eq_ignore_ascii_case(CONTINUE)) {
+                    writer.write_all(CONTINUE_RESPONSE).await.wrap_err("Failed to send 100")?;
+                }
+                let (done, connection_back) = oneshot::channel();
+                let prefix = buffer.split().freeze();
+                request.body_stream = Some(BodyStream::new(prefix, connection, len).on_done(done));
+                returned = Some(connection_back);
+            }
+
+            let response = router.resolve(request).await.wrap_err("Failed to resolve request")?;
             let response_bytes = response.to_bytes().wrap_err("Failed to serialize response")?;
-            writer.send(Bytes::from(response_bytes)).await.wrap_err("Failed to send response")?;
+            writer.write_all(&response_bytes).await.wrap_err("Failed to send response")?;
 
-            if request.connection != KEEP_ALIVE {
+            if let Some(connection_back) = returned {
+                reader = connection_back.await.ok().flatten();
+            }
+            if !keep_alive {
                 break;
             }
         }
         Ok(())
     }
 }
+
+/// Reads from a connection until `buffer` holds a whole request head.
+///
+/// # Returns
+///
+/// A `Result` containing the length of the head, including the empty line that ends it, or
+/// `None` if the connection closed before a head was complete.
+async fn read_head(reader: &mut BodyReader, buffer: &mut BytesMut) -> Result<Option<usize>> {
+    loop {
+        if let Some(index) = find(buffer, HEAD_END) {
+            return Ok(Some(index + HEAD_END.len()));
+        }
+        if buffer.len() > MAX_HEAD_LEN {
+            return Err(ServerError::HttpError(HeadTooLarge).into());
+        }
+        if reader.read_buf(buffer).await.wrap_err("Failed to read request")? == 0 {
+            return Ok(None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        body::CHUNK_SIZE,
+        http::{HttpResponse, ResponseHeaders},
+        router::sync,
+    };
+
+    /// Serves one connection with a router that reports the bodies it gets.
+    async fn serve() -> TcpStream {
+        let mut router = Router::new();
+        router
+            .post(
+                "/buffered",
+                sync(|request| {
+                    let len = request.body.len().to_string();
+                    HttpResponse::ok(len.as_bytes(), ResponseHeaders::new())
+                }),
+            )
+            .unwrap();
+        router
+            .post("/streamed", |mut request: HttpRequest| async move {
+                let mut stream = request.body_stream.take().unwrap();
+                let (mut len, mut largest) = (0, 0);
+                while let Some(chunk) = stream.chunk().await? {
+                    len += chunk.len();
+                    largest = largest.max(chunk.len());
+                }
+                let body = format!("{} {}", len, largest);
+                Ok(HttpResponse::ok(body.as_bytes(), ResponseHeaders::new()))
+            })
+            .unwrap()
+            .stream_body();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            Server::handle_connection(stream, Arc::new(router)).await.unwrap();
+        });
+        TcpStream::connect(addr).await.unwrap()
+    }
+
+    /// Reads a response with a `Content-Length` and returns its body.
+    async fn read_body(client: &mut TcpStream) -> String {
+        let mut response = BytesMut::new();
+        let head_len = loop {
+            if let Some(index) = find(&response, HEAD_END) {
+                break index + HEAD_END.len();
+            }
+            assert_ne!(client.read_buf(&mut response).await.unwrap(), 0);
+        };
+        let head = String::from_utf8_lossy(&response[..head_len]).to_string();
+        let len = head
+            .lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .map_or(0, |len| len.parse().unwrap());
+        while response.len() < head_len + len {
+            client.read_buf(&mut response).await.unwrap();
+        }
+        String::from_utf8_lossy(&response[head_len..]).to_string()
+    }
+
+    #[tokio::test]
+    async fn bodies_span_reads() {
+        let mut client = serve().await;
+        let body = vec![b'x'; CHUNK_SIZE * 4];
+        for target in ["/streamed", "/buffered"] {
+            let head = format!(
+                "POST {} HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: {}\r\n\r\n",
+                target,
+                body.len()
+            );
+            client.write_all(head.as_bytes()).await.unwrap();
+            let mut interim = vec![0; CONTINUE_RESPONSE.len()];
+            client.read_exact(&mut interim).await.unwrap();
+            assert_eq!(interim, CONTINUE_RESPONSE);
+            for piece in body.chunks(10_000) {
+                client.write_all(piece).await.unwrap();
+            }
+            let expected = match target {
+                "/streamed" => format!("{} {}", body.len(), CHUNK_SIZE),
+                _ => body.len().to_string(),
+            };
+            assert_eq!(read_body(&mut client).await, expected);
+        }
+        client.write_all(b"POST /buffered HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc").await.unwrap();
+        assert_eq!(read_body(&mut client).await, "3");
+    }
+}
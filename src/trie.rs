@@ -0,0 +1,335 @@
+use std::collections::HashMap;
+
+use eyre::Result;
+
+use crate::{
+    error::{RouterError, ServerError},
+    form::percent_decode,
+    pattern::{split_path, PathParams, RoutePattern, Segment},
+};
+
+/// A node of the route trie, keyed by path segment.
+#[derive(Debug)]
+struct Node<T> {
+    statics:  HashMap<String, Node<T>>,
+    param:    Option<(String, Box<Node<T>>)>,
+    wildcard: Option<(String, Vec<T>)>,
+    values:   Vec<T>,
+}
+
+impl<T> Default for Node<T> {
+    fn default() -> Self {
+        Self { statics: HashMap::new(), param: None, wildcard: None, values: Vec::new() }
+    }
+}
+
+/// A compiled matcher that maps route patterns to values.
+///
+/// Lookup cost depends on the depth of the path rather than the number of registered routes.
+/// At each segment a static child is preferred over a parameter, and a parameter over a
+/// wildcard; a more specific branch that fails further down falls back to the less specific one.
+#[derive(Debug)]
+pub struct RouteTrie<T> {
+    root: Node<T>,
+}
+
+impl<T> Default for RouteTrie<T> {
+    fn default() -> Self { Self { root: Node::default() } }
+}
+
+impl<T: Clone> RouteTrie<T> {
+    /// Creates an empty `RouteTrie`.
+    pub fn new() -> Self { Self::default() }
+
+    /// Inserts a value under a route pattern.
+    ///
+    /// Optional parameters are expanded into one entry per reachable shape, so `/a/:x?` is
+    /// stored as both `/a` and `/a/:x`. Every shape is checked before any is stored, so a
+    /// conflict leaves the trie unchanged.
+    ///
+    /// # Arguments
+    ///
+    /// * `pattern` - The route pattern.
+    /// * `value` - The value to store.
+    /// * `conflicts` - Decides whether an existing value at the same position conflicts with the
+    ///   new one.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success, or a `RouteConflict` error if `conflicts` returned `true`
+    /// or if a parameter or wildcard at the same position is named differently.
+    pub fn insert<F>(&mut self, pattern: &RoutePattern, value: T, conflicts: F) -> Result<()>
+    where F: Fn(&T, &T) -> bool {
+        self.check(pattern, &value, conflicts)?;
+        for shape in expand(pattern.segments()) {
+            Self::insert_at(&mut self.root, &shape, &value);
+        }
+        Ok(())
+    }
+
+    /// Checks whether a value could be inserted under a route pattern, without inserting it.
+    ///
+    /// # Arguments
+    ///
+    /// * `pattern` - The route pattern.
+    /// * `value` - The value that would be stored.
+    /// * `conflicts` - Decides whether an existing value at the same position conflicts with the
+    ///   new one.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success, or the `RouteConflict` error [`insert`](Self::insert)
+    /// would return.
+    pub fn check<F>(&self, pattern: &RoutePattern, value: &T, conflicts: F) -> Result<()>
+    where F: Fn(&T, &T) -> bool {
+        let conflict =
+            || ServerError::RouterError(RouterError::RouteConflict(pattern.as_str().to_string()));
+        // The shapes of one pattern must also agree on the names of their parameters.
+        let mut own = Node::default();
+        for shape in expand(pattern.segments()) {
+            if RouteTrie::conflicts_at(&own, &shape, &(), &|_, _| false)
+                || Self::conflicts_at(&self.root, &shape, value, &conflicts)
+            {
+                return Err(conflict().into());
+            }
+            RouteTrie::insert_at(&mut own, &shape, &());
+        }
+        Ok(())
+    }
+
+    /// Checks whether a shape, made of static segments, parameters and a wildcard, conflicts
+    /// with what is stored below `node`.
+    fn conflicts_at<F>(node: &Node<T>, shape: &[Segment], value: &T, conflicts: &F) -> bool
+    where F: Fn(&T, &T) -> bool {
+        let Some((segment, rest)) = shape.split_first() else {
+            return node.values.iter().any(|existing| conflicts(existing, value));
+        };
+        match segment {
+            Segment::Static(part) => node
+                .statics
+                .get(part)
+                .is_some_and(|child| Self::conflicts_at(child, rest, value, conflicts)),
+            Segment::Param(name) | Segment::OptionalParam(name) => match &node.param {
+                Some((existing, child)) =>
+                    existing != name || Self::conflicts_at(child, rest, value, conflicts),
+                None => false,
+            },
+            Segment::Wildcard(name) => match &node.wildcard {
+                Some((existing, values)) =>
+                    existing != name || values.iter().any(|existing| conflicts(existing, value)),
+                None => false,
+            },
+        }
+    }
+
+    /// Stores a value under a shape that [`conflicts_at`](Self::conflicts_at) accepted.
+    fn insert_at(node: &mut Node<T>, shape: &[Segment], value: &T) {
+        let Some((segment, rest)) = shape.split_first() else {
+            node.values.push(value.clone());
+            return;
+        };
+        match segment {
+            Segment::Static(part) => {
+                let child = node.statics.entry(part.clone()).or_default();
+                Self::insert_at(child, rest, value);
+            },
+            Segment::Param(name) | Segment::OptionalParam(name) => {
+                let (_, child) = node.param.get_or_insert_with(|| (name.clone(), Box::default()));
+                Self::insert_at(child, rest, value);
+            },
+            Segment::Wildcard(name) => {
+                let (_, values) = node.wildcard.get_or_insert_with(|| (name.clone(), Vec::new()));
+                values.push(value.clone());
+            },
+        }
+    }
+
+    /// Looks up the values registered for a request path.
+    ///
+    /// The query string and empty segments are ignored, and captured values are
+    /// percent-decoded.
+    ///
+    /// # Returns
+    ///
+    /// The values of the most specific matching pattern and the captured parameters, or `None`
+    /// if no pattern matches.
+    pub fn lookup(&self, path: &str) -> Option<(&[T], PathParams)> {
+        self.lookup_by(path, |_| true)
+    }
+
+    /// Looks up the values registered for a request path, skipping matches whose values
+    /// `accept` refuses.
+    ///
+    /// Matches are offered to `accept` from the most specific to the least specific, so a
+    /// refused static match falls back to a parameter or wildcard that matches the same path.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The request path.
+    /// * `accept` - Decides whether the values of a matching pattern can handle the request.
+    ///
+    /// # Returns
+    ///
+    /// The values of the most specific accepted match and the captured parameters, or `None`
+    /// if no match was accepted.
+    pub fn lookup_by<F>(&self, path: &str, mut accept: F) -> Option<(&[T], PathParams)>
+    where F: FnMut(&[T]) -> bool {
+        let path = path.split('?').next().unwrap_or_default();
+        let parts = split_path(path).collect::<Vec<_>>();
+        let mut params = Vec::new();
+        let values = Self::lookup_at(&self.root, &parts, &mut params, &mut accept)?;
+        Some((values, params.into_iter().collect()))
+    }
+
+    fn lookup_at<'a, F>(
+        node: &'a Node<T>,
+        parts: &[&str],
+        params: &mut Vec<(String, String)>,
+        accept: &mut F,
+    ) -> Option<&'a [T]>
+    where
+        F: FnMut(&[T]) -> bool,
+    {
+        let Some((part, rest)) = parts.split_first() else {
+            if !node.values.is_empty() && accept(&node.values) {
+                return Some(&node.values);
+            }
+            let (name, values) = node.wildcard.as_ref()?;
+            if !accept(values) {
+                return None;
+            }
+            params.push((name.clone(), String::new()));
+            return Some(values);
+        };
+        if let Some(child) = node.statics.get(*part) {
+            if let Some(values) = Self::lookup_at(child, rest, params, accept) {
+                return Some(values);
+            }
+        }
+        if let Some((name, child)) = &node.param {
+            params.push((name.clone(), percent_decode(part, false)));
+            if let Some(values) = Self::lookup_at(child, rest, params, accept) {
+                return Some(values);
+            }
+            params.pop();
+        }
+        let (name, values) = node.wildcard.as_ref()?;
+        if !accept(values) {
+            return None;
+        }
+        let value = parts.iter().map(|part| percent_decode(part, false)).collect::<Vec<_>>();
+        params.push((name.clone(), value.join("/")));
+        Some(values)
+    }
+}
+
+/// Expands the optional parameters of a pattern into every shape the pattern can match.
+///
+/// Consecutive optional parameters fill from the left, so skipping one also skips those that
+/// immediately follow it.
+fn expand(segments: &[Segment]) -> Vec<Vec<Segment>> {
+    let Some((segment, rest)) = segments.split_first() else {
+        return vec![Vec::new()];
+    };
+    let prefixed =
+        |shape: Vec<Segment>| std::iter::once(segment.clone()).chain(shape).collect::<Vec<_>>();
+    match segment {
+        Segment::OptionalParam(_) => {
+            let skipped = rest
+                .iter()
+                .position(|segment| !matches!(segment, Segment::OptionalParam(_)))
+                .map_or(&[][..], |index| &rest[index..]);
+            let mut shapes = expand(skipped);
+            shapes.extend(expand(rest).into_iter().map(prefixed));
+            shapes
+        },
+        _ => expand(rest).into_iter().map(prefixed).collect(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn trie(patterns: &[&str]) -> RouteTrie<usize> {
+        let mut trie = RouteTrie::new();
+        for (index, pattern) in patterns.iter().enumerate() {
+            trie.insert(&RoutePattern::parse(pattern).unwrap(), index, |_, _| true).unwrap();
+        }
+        trie
+    }
+
+    fn lookup(trie: &RouteTrie<usize>, path: &str) -> Option<(usize, Vec<(String, String)>)> {
+        trie.lookup(path).map(|(values, params)| {
+            let mut params = params.into_iter().collect::<Vec<_>>();
+            params.sort();
+            (values[0], params)
+        })
+    }
+
+    fn params(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn static_beats_param_beats_wildcard() {
+        let trie = trie(&["/users/*rest", "/users/:id", "/users/new"]);
+        assert_eq!(lookup(&trie, "/users/new"), Some((2, params(&[]))));
+        assert_eq!(lookup(&trie, "/users/42"), Some((1, params(&[("id", "42")]))));
+        assert_eq!(lookup(&trie, "/users/42/posts"), Some((0, params(&[("rest", "42/posts")]))));
+        assert_eq!(lookup(&trie, "/users"), Some((0, params(&[("rest", "")]))));
+    }
+
+    #[test]
+    fn backtracks_to_less_specific_branch() {
+        let trie = trie(&["/users/new/edit", "/users/:id/posts"]);
+        assert_eq!(lookup(&trie, "/users/new/posts"), Some((1, params(&[("id", "new")]))));
+        assert_eq!(lookup(&trie, "/users/new/edit"), Some((0, params(&[]))));
+        assert_eq!(lookup(&trie, "/users/new"), None);
+    }
+
+    #[test]
+    fn optional_params_expand() {
+        let trie = trie(&["/archive/:year?/:month?", "/docs/:lang?/index"]);
+        assert_eq!(lookup(&trie, "/archive"), Some((0, params(&[]))));
+        assert_eq!(lookup(&trie, "/archive/2024"), Some((0, params(&[("year", "2024")]))));
+        assert_eq!(
+            lookup(&trie, "/archive/2024/05"),
+            Some((0, params(&[("month", "05"), ("year", "2024")])))
+        );
+        assert_eq!(lookup(&trie, "/docs/index"), Some((1, params(&[]))));
+        assert_eq!(lookup(&trie, "/docs/en/index"), Some((1, params(&[("lang", "en")]))));
+    }
+
+    #[test]
+    fn lookup_skips_refused_matches() {
+        let trie = trie(&["/users/*rest", "/users/:id", "/users/new"]);
+        let lookup = |path, refused: usize| {
+            trie.lookup_by(path, |values| values[0] != refused).map(|(values, _)| values[0])
+        };
+        assert_eq!(lookup("/users/new", 2), Some(1));
+        assert_eq!(lookup("/users/new", 1), Some(2));
+        assert_eq!(lookup("/users/42", 1), Some(0));
+        assert_eq!(lookup("/users", 0), None);
+    }
+
+    #[test]
+    fn detects_conflicts() {
+        let mut trie = trie(&["/users/:id", "/files/*path"]);
+        let mut conflict = |pattern: &str| {
+            trie.insert(&RoutePattern::parse(pattern).unwrap(), 9, |_, _| true).is_err()
+        };
+        assert!(conflict("/users/:id"));
+        assert!(conflict("/users/:name"));
+        assert!(conflict("/files/*rest"));
+        assert!(conflict("/users/:name?"));
+        assert!(conflict("/:a?/:b"));
+        assert!(lookup(&trie, "/users").is_none());
+        assert!(lookup(&trie, "/x").is_none());
+        let mut trie = RouteTrie::new();
+        let pattern = RoutePattern::parse("/users/:id").unwrap();
+        trie.insert(&pattern, 1, |a, b| a == b).unwrap();
+        trie.insert(&pattern, 2, |a, b| a == b).unwrap();
+        assert_eq!(trie.lookup("/users/1").unwrap().0, &[1, 2]);
+    }
+}
@@ -0,0 +1,204 @@
+use std::sync::Arc;
+
+use eyre::Result;
+
+use crate::{
+    error::{RouterError, ServerError},
+    guard::request_host,
+    http::{HttpRequest, HttpResponse},
+    router::Router,
+};
+
+/// A host name a virtual host answers to.
+#[derive(Debug, Clone, PartialEq)]
+enum HostPattern {
+    /// Exactly this host, e.g. `example.com`.
+    Exact(String),
+    /// Any subdomain of this suffix, e.g. `*.example.com`, but not the suffix itself.
+    Subdomains(String),
+}
+
+impl HostPattern {
+    fn parse(pattern: &str) -> Result<Self> {
+        let invalid = || ServerError::RouterError(RouterError::InvalidHostPattern(pattern.into()));
+        let pattern = pattern.trim().to_ascii_lowercase();
+        let (suffix, wildcard) = match pattern.strip_prefix("*.") {
+            Some(suffix) => (suffix, true),
+            None => (pattern.as_str(), false),
+        };
+        let valid_label = |label: &str| {
+            !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        };
+        if !suffix.split('.').all(valid_label) {
+            return Err(invalid().into());
+        }
+        Ok(match wildcard {
+            true => Self::Subdomains(format!(".{}", suffix)),
+            false => Self::Exact(suffix.to_string()),
+        })
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            Self::Exact(name) => host == name,
+            Self::Subdomains(suffix) =>
+                host.len() > suffix.len() && host.ends_with(suffix.as_str()),
+        }
+    }
+
+    /// Orders exact names first, then wildcards from the longest suffix down.
+    fn specificity(&self) -> (bool, usize) {
+        match self {
+            Self::Exact(name) => (true, name.len()),
+            Self::Subdomains(suffix) => (false, suffix.len()),
+        }
+    }
+}
+
+/// Name-based virtual hosting: dispatches each request to a router by its `Host` header.
+///
+/// Exact host names take precedence over wildcard subdomains, and a longer wildcard over a
+/// shorter one. Requests for unknown hosts, or without a `Host` header, go to the default
+/// router; in strict mode, or without a default, unknown hosts get a 421 Misdirected Request.
+#[derive(Default)]
+pub struct VirtualHosts {
+    hosts:   Vec<(HostPattern, Arc<Router>)>,
+    default: Option<Arc<Router>>,
+    strict:  bool,
+}
+
+impl VirtualHosts {
+    /// Creates a `VirtualHosts` without hosts.
+    pub fn new() -> Self { Self::default() }
+
+    /// Adds a virtual host.
+    ///
+    /// # Arguments
+    ///
+    /// * `pattern` - The host name, e.g. `example.com`, or `*.example.com` for its subdomains.
+    /// * `router` - The router serving the host.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing `self`, or an `InvalidHostPattern` error if the pattern is not a
+    /// valid host name.
+    pub fn host(&mut self, pattern: &str, router: Router) -> Result<&mut Self> {
+        let pattern = HostPattern::parse(pattern)?;
+        self.hosts.retain(|(existing, _)| *existing != pattern);
+        self.hosts.push((pattern, Arc::new(router)));
+        self.hosts.sort_by_key(|(pattern, _)| std::cmp::Reverse(pattern.specificity()));
+        Ok(self)
+    }
+
+    /// Sets the router for requests that match no host.
+    pub fn default_host(&mut self, router: Router) -> &mut Self {
+        self.default = Some(Arc::new(router));
+        self
+    }
+
+    /// Answers requests for unknown hosts with 421 Misdirected Request instead of using the
+    /// default router. Requests without a `Host` header still go to the default router.
+    pub fn strict(&mut self, strict: bool) -> &mut Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Returns the router for a request, if any.
+    fn router_for(&self, request: &HttpRequest) -> Option<&Arc<Router>> {
+        let Some(host) = request_host(request) else {
+            return self.default.as_ref();
+        };
+        let matched = self.hosts.iter().find(|(pattern, _)| pattern.matches(&host));
+        match (matched, self.strict) {
+            (Some((_, router)), _) => Some(router),
+            (None, false) => self.default.as_ref(),
+            (None, true) => None,
+        }
+    }
+
+    /// Builds a router that dispatches to the virtual hosts.
+    ///
+    /// Middleware added to the returned router wraps every host.
+    pub fn into_router(self) -> Router {
+        let hosts = Arc::new(self);
+        let mut router = Router::new();
+        router.fallback(move |request: HttpRequest| {
+            let hosts = hosts.clone();
+            async move {
+                match hosts.router_for(&request) {
+                    Some(router) => router.resolve(request).await,
+                    None => Ok(HttpResponse::misdirected_request()),
+                }
+            }
+        });
+        router
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        http::{ResponseHeaders, StatusCode},
+        router::sync,
+    };
+
+    fn site(name: &'static str) -> Router {
+        let mut router = Router::new();
+        router
+            .get("/", sync(move |_| HttpResponse::ok(name.as_bytes(), ResponseHeaders::new())))
+            .unwrap();
+        router
+    }
+
+    async fn resolve(router: &Router, host: Option<&str>) -> HttpResponse {
+        let host = host.map(|host| format!("Host: {}\r\n", host)).unwrap_or_default();
+        let request = HttpRequest::from_string(&format!("GET / HTTP/1.1\r\n{}\r\n", host));
+        router.resolve(request.unwrap()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn dispatches_by_host() {
+        let mut hosts = VirtualHosts::new();
+        hosts
+            .host("*.example.com", site("wildcard"))
+            .unwrap()
+            .host("api.example.com", site("api"))
+            .unwrap()
+            .host("*.eu.example.com", site("eu"))
+            .unwrap()
+            .default_host(site("default"));
+        let router = hosts.into_router();
+
+        assert_eq!(resolve(&router, Some("API.example.com:8080")).await.body, b"api");
+        assert_eq!(resolve(&router, Some("www.example.com")).await.body, b"wildcard");
+        assert_eq!(resolve(&router, Some("shop.eu.example.com")).await.body, b"eu");
+        assert_eq!(resolve(&router, Some("example.com")).await.body, b"default");
+        assert_eq!(resolve(&router, None).await.body, b"default");
+    }
+
+    #[tokio::test]
+    async fn strict_mode() {
+        let mut hosts = VirtualHosts::new();
+        hosts.host("example.com", site("main")).unwrap().default_host(site("default")).strict(true);
+        let router = hosts.into_router();
+
+        assert_eq!(resolve(&router, Some("example.com")).await.body, b"main");
+        let response = resolve(&router, Some("evil.com")).await;
+        assert_eq!(response.status_code, StatusCode::MISDIRECTED_REQUEST);
+        assert_eq!(resolve(&router, None).await.body, b"default");
+
+        let router = VirtualHosts::new().into_router();
+        let response = resolve(&router, Some("example.com")).await;
+        assert_eq!(response.status_code, StatusCode::MISDIRECTED_REQUEST);
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        let mut hosts = VirtualHosts::new();
+        assert!(hosts.host("a.*.com", Router::new()).is_err());
+        assert!(hosts.host("*example.com", Router::new()).is_err());
+        assert!(hosts.host("", Router::new()).is_err());
+        assert!(hosts.host("example.com:80", Router::new()).is_err());
+    }
+}
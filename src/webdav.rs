@@ -0,0 +1,1031 @@
+use std::{
+    collections::{hash_map::RandomState, BTreeSet},
+    fs::{self, Metadata},
+    hash::{BuildHasher, Hasher},
+    io,
+    path::{Component, Path, PathBuf},
+    sync::{LazyLock, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use eyre::{Result, WrapErr};
+use regex::Regex;
+use tokio::task::spawn_blocking;
+
+use crate::{
+    autoindex::{self, encode_segment, escape_html},
+    conditional::{self, Precondition},
+    error::{RouterError, ServerError},
+    extract::{FromRequest, State},
+    files::{
+        file_content_type, file_validators, lock_file, resolve_request_path, send_file,
+        write_upload, PublicDir, SymlinkPolicy,
+    },
+    form::percent_decode,
+    http::{
+        HttpRequest, HttpResponse, ResponseHeaders, StatusCode, ALLOW, CONTENT_TYPE, METHOD_DELETE,
+        METHOD_GET, METHOD_OPTIONS, METHOD_PUT,
+    },
+    middleware::Next,
+    mime,
+    router::Router,
+};
+
+pub const METHOD_PROPFIND: &str = "PROPFIND";
+pub const METHOD_MKCOL: &str = "MKCOL";
+pub const METHOD_COPY: &str = "COPY";
+pub const METHOD_MOVE: &str = "MOVE";
+pub const METHOD_LOCK: &str = "LOCK";
+pub const METHOD_UNLOCK: &str = "UNLOCK";
+
+pub const DAV: &str = "DAV";
+pub const DEPTH: &str = "Depth";
+pub const DESTINATION: &str = "Destination";
+pub const OVERWRITE: &str = "Overwrite";
+pub const IF: &str = "If";
+pub const LOCK_TOKEN: &str = "Lock-Token";
+pub const TIMEOUT: &str = "Timeout";
+pub const CT_APPLICATION_XML: &str = "application/xml";
+
+/// Where [`make_router`](crate::router::make_router) mounts the WebDAV router.
+pub const DAV_PREFIX: &str = "/dav";
+
+/// The longest a lock is granted for, which is also what `Timeout: Infinite` gets.
+pub const MAX_LOCK_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// The methods the WebDAV router answers, as sent in `Allow`.
+const ALLOWED_METHODS: [&str; 10] = [
+    METHOD_OPTIONS,
+    METHOD_GET,
+    METHOD_PUT,
+    METHOD_DELETE,
+    METHOD_PROPFIND,
+    METHOD_MKCOL,
+    METHOD_COPY,
+    METHOD_MOVE,
+    METHOD_LOCK,
+    METHOD_UNLOCK,
+];
+
+/// The `<lockinfo>` element of a `LOCK` body, with any namespace prefix.
+static LOCKINFO_ELEMENT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"<([\w.-]+:)?lockinfo[\s>/]").expect("valid regex"));
+/// The `<shared>` lock scope.
+static SHARED_ELEMENT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"<([\w.-]+:)?shared[\s>/]").expect("valid regex"));
+/// The `<owner>` element, whose content is the second group.
+static OWNER_ELEMENT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?s)<([\w.-]+:)?owner\b[^>]*>(.*?)</([\w.-]+:)?owner\s*>").expect("valid regex")
+});
+/// Any XML tag, to take the text out of an element.
+static TAG: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<[^>]*>").expect("valid regex"));
+
+/// WebDAV access to the public directory at [`DAV_PREFIX`], shared with handlers as router
+/// state; without it the WebDAV router answers every request with 404 Not Found.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WebDav;
+
+/// The path the WebDAV router is mounted at, which `href`s in responses start with.
+#[derive(Debug, Clone)]
+struct Mount(String);
+
+/// An exclusive write lock on a resource.
+#[derive(Debug, Clone)]
+struct Lock {
+    token:     String,
+    /// The resolved path of the locked resource.
+    path:      PathBuf,
+    /// The `href` the lock was taken on.
+    root:      String,
+    /// Whether the lock covers the members of a collection, i.e. was taken with depth infinity.
+    recursive: bool,
+    owner:     Option<String>,
+    timeout:   Duration,
+    expires:   Instant,
+}
+
+impl Lock {
+    /// Checks whether the lock covers a resource.
+    fn covers(&self, path: &Path) -> bool {
+        self.path == path || (self.recursive && path.starts_with(&self.path))
+    }
+}
+
+/// The WebDAV locks held on resources of the public directory, shared with handlers as router
+/// state, so that writes under `/files` honour them as well.
+#[derive(Debug, Default)]
+pub struct LockTable {
+    locks: Mutex<Vec<Lock>>,
+}
+
+impl LockTable {
+    /// Creates an empty `LockTable`.
+    pub fn new() -> Self { Self::default() }
+
+    /// Returns the locks that have not expired, dropping the others.
+    fn active(&self) -> MutexGuard<'_, Vec<Lock>> {
+        let mut locks = self.locks.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        locks.retain(|lock| lock.expires > now);
+        locks
+    }
+
+    /// Checks whether a request may write a resource.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The resolved path of the resource.
+    /// * `members` - Whether locks on members of the resource count as well, e.g. for `DELETE`.
+    /// * `submitted` - The lock tokens the request submitted in its `If` header.
+    fn permits(&self, path: &Path, members: bool, submitted: &[String]) -> bool {
+        self.active()
+            .iter()
+            .filter(|lock| lock.covers(path) || (members && lock.path.starts_with(path)))
+            .all(|lock| submitted.contains(&lock.token))
+    }
+
+    /// Drops the locks on a resource and its members, e.g. once it was deleted.
+    fn release(&self, path: &Path) { self.active().retain(|lock| !lock.path.starts_with(path)) }
+}
+
+/// Creates the WebDAV router, which serves the [`PublicDir`] to WebDAV clients if [`WebDav`]
+/// is registered as state.
+///
+/// It implements class 1 and 2 of RFC 4918 for the public directory: `PROPFIND` with depth 0
+/// or 1, `MKCOL`, `COPY`, `MOVE`, `DELETE`, `PUT` and exclusive write locks with `LOCK` and
+/// `UNLOCK`. `PROPFIND` always answers as for `allprop`, and locks live in memory, so they are
+/// lost on restart. They live in the [`LockTable`] registered as state, which `/files` checks as
+/// well. Paths are resolved as under `/files`, with the same [`SymlinkPolicy`], and `PUT`
+/// writes files as `/files` does.
+///
+/// # Arguments
+///
+/// * `prefix` - The path the router is mounted at, e.g. [`DAV_PREFIX`].
+///
+/// # Returns
+///
+/// A `Result` containing the `Router` instance.
+pub fn webdav_router(prefix: &str) -> Result<Router> {
+    let mut router = Router::new();
+    router.state(Mount(prefix.trim_end_matches('/').to_string())).layer(
+        |request: HttpRequest, next: Next| async move {
+            match request.state.get::<WebDav>() {
+                Some(_) => next.run(request).await,
+                None => HttpResponse::not_found(),
+            }
+        },
+    );
+
+    router.options("/*path", |_request: HttpRequest| async move {
+        let mut headers = ResponseHeaders::new();
+        headers.insert(DAV.to_string(), "1, 2".to_string());
+        headers.insert(ALLOW.to_string(), ALLOWED_METHODS.join(", "));
+        headers.insert("MS-Author-Via".to_string(), DAV.to_string());
+        HttpResponse::ok(b"", headers)
+    })?;
+    router.get("/*path", get)?;
+    router.put("/*path", put)?.stream_body();
+    router.delete("/*path", delete)?;
+    router.route(METHOD_PROPFIND, "/*path", propfind)?;
+    router.route(METHOD_MKCOL, "/*path", mkcol)?;
+    router.route(METHOD_COPY, "/*path", |request| transfer(request, false))?;
+    router.route(METHOD_MOVE, "/*path", |request| transfer(request, true))?;
+    router.route(METHOD_LOCK, "/*path", lock)?;
+    router.route(METHOD_UNLOCK, "/*path", unlock)?;
+
+    Ok(router)
+}
+
+/// The resource a request targets.
+struct Target {
+    /// The public directory.
+    dir:  String,
+    /// The decoded path relative to `dir`.
+    path: String,
+    /// The resolved path.
+    file: PathBuf,
+}
+
+impl Target {
+    /// Resolves the resource a request targets.
+    async fn of(request: &HttpRequest) -> Result<Self> {
+        let State(PublicDir(dir)) = State::from_request(request)?;
+        let path = request.param("path").unwrap_or_default().to_string();
+        let file = resolve_request_path(request, &dir, &path).await?;
+        Ok(Self { dir, path, file })
+    }
+
+    /// Checks whether the target is the public directory itself, which cannot be deleted,
+    /// moved or overwritten.
+    fn is_root(&self) -> bool {
+        !Path::new(&self.path).components().any(|part| matches!(part, Component::Normal(_)))
+    }
+}
+
+async fn get(request: HttpRequest) -> Result<HttpResponse> {
+    let target = Target::of(&request).await?;
+    if tokio::fs::metadata(&target.file).await.is_ok_and(|metadata| metadata.is_dir()) {
+        return autoindex::serve_listing(&request, &target.dir, &target.path).await;
+    }
+    send_file(&request, &target.dir, &target.file).await
+}
+
+async fn put(mut request: HttpRequest) -> Result<HttpResponse> {
+    let target = Target::of(&request).await?;
+    if tokio::fs::metadata(&target.file).await.is_ok_and(|metadata| metadata.is_dir()) {
+        return Ok(HttpResponse::method_not_allowed());
+    }
+    let _guard = lock_file(&request, &target.file).await;
+    let current = file_validators(&request, &target.file).await.map(|(validators, ..)| validators);
+    if conditional::evaluate(&request, current.as_ref()) != Precondition::Proceed {
+        return Ok(HttpResponse::precondition_failed());
+    }
+    write_upload(&mut request, &target.file).await?;
+    Ok(match current {
+        Some(_) => HttpResponse::no_content(),
+        None => HttpResponse::created(),
+    })
+}
+
+/// Deletes a file, or a collection with all its members.
+async fn delete(request: HttpRequest) -> Result<HttpResponse> {
+    let target = Target::of(&request).await?;
+    let _guard = lock_file(&request, &target.file).await;
+    if tokio::fs::symlink_metadata(&target.file).await.is_err() {
+        return Ok(HttpResponse::not_found());
+    }
+    if target.is_root() {
+        return Ok(HttpResponse::from_status_code(StatusCode::FORBIDDEN));
+    }
+    let locks = lock_table(&request)?;
+    if !locks.permits(&target.file, true, &submitted_tokens(&request)) {
+        return Ok(HttpResponse::locked());
+    }
+    let file = target.file.clone();
+    spawn_blocking(move || remove(&file))
+        .await
+        .wrap_err("Delete task failed")?
+        .wrap_err_with(|| format!("Failed to delete {}", target.file.display()))?;
+    locks.release(&target.file);
+    Ok(HttpResponse::no_content())
+}
+
+/// Lists the properties of a resource, and with `Depth: 1` of a collection's members.
+///
+/// `Depth: infinity`, which is also what a missing header means, is refused with 403
+/// Forbidden as RFC 4918 allows, since it would walk the whole tree.
+async fn propfind(request: HttpRequest) -> Result<HttpResponse> {
+    let target = Target::of(&request).await?;
+    let members = match request.headers.get(DEPTH).map(|depth| depth.trim()) {
+        Some("0") => false,
+        Some("1") => true,
+        Some("infinity") | None =>
+            return Ok(HttpResponse::from_status_code(StatusCode::FORBIDDEN)),
+        Some(_) => return Ok(HttpResponse::bad_request()),
+    };
+    let Ok(metadata) = tokio::fs::metadata(&target.file).await else {
+        return Ok(HttpResponse::not_found());
+    };
+    let State(Mount(mount)) = State::from_request(&request)?;
+    let mut responses = vec![properties(&request, &target, &mount, &metadata).await?];
+    if members && metadata.is_dir() {
+        let symlinks = request.state.get::<SymlinkPolicy>().copied().unwrap_or_default();
+        let (root, dir) = (PathBuf::from(&target.dir), PathBuf::from(&target.path));
+        let mut entries = spawn_blocking(move || autoindex::read_entries(&root, &dir, symlinks))
+            .await
+            .wrap_err("Listing task failed")??;
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        for entry in entries {
+            let member = Target {
+                dir:  target.dir.clone(),
+                path: Path::new(&target.path).join(&entry.name).to_string_lossy().into_owned(),
+                file: target.file.join(&entry.name),
+            };
+            let Ok(metadata) = tokio::fs::metadata(&member.file).await else {
+                continue;
+            };
+            responses.push(properties(&request, &member, &mount, &metadata).await?);
+        }
+    }
+    Ok(multistatus(&responses))
+}
+
+/// Creates a collection.
+async fn mkcol(request: HttpRequest) -> Result<HttpResponse> {
+    let target = Target::of(&request).await?;
+    if !request.body.is_empty() {
+        return Ok(HttpResponse::unsupported_media_type());
+    }
+    if tokio::fs::symlink_metadata(&target.file).await.is_ok() {
+        return Ok(HttpResponse::method_not_allowed());
+    }
+    if !has_parent_dir(&target.file).await {
+        return Ok(HttpResponse::conflict());
+    }
+    if !lock_table(&request)?.permits(&target.file, false, &submitted_tokens(&request)) {
+        return Ok(HttpResponse::locked());
+    }
+    tokio::fs::create_dir(&target.file)
+        .await
+        .wrap_err_with(|| format!("Failed to create {}", target.file.display()))?;
+    Ok(HttpResponse::created())
+}
+
+/// Copies or moves a resource to the one named by the `Destination` header.
+///
+/// `Overwrite: F` refuses to replace an existing destination with 412 Precondition Failed.
+/// Collections are copied with all their members unless `Depth: 0` asks for the collection
+/// alone; symbolic links among the members are skipped. Locks are not copied or moved. The
+/// write locks of both resources are held from checking the destination until it is written.
+///
+/// # Arguments
+///
+/// * `request` - The HTTP request.
+/// * `remove_source` - Whether the source goes away, i.e. whether this is a `MOVE`.
+async fn transfer(request: HttpRequest, remove_source: bool) -> Result<HttpResponse> {
+    let source = Target::of(&request).await?;
+    let recursive = match (request.headers.get(DEPTH).map(|depth| depth.trim()), remove_source) {
+        (Some("infinity") | None, _) => true,
+        (Some("0"), false) => false,
+        _ => return Ok(HttpResponse::bad_request()),
+    };
+    let State(Mount(mount)) = State::from_request(&request)?;
+    let Some(destination) = request.headers.get(DESTINATION) else {
+        return Ok(HttpResponse::bad_request());
+    };
+    let Some(path) = destination_path(destination, &mount) else {
+        return Ok(HttpResponse::from_status_code(StatusCode::BAD_GATEWAY));
+    };
+    let destination = Target {
+        file: resolve_request_path(&request, &source.dir, &path).await?,
+        dir: source.dir.clone(),
+        path,
+    };
+    let mut guards = Vec::new();
+    for file in BTreeSet::from([&source.file, &destination.file]) {
+        guards.extend(lock_file(&request, file).await);
+    }
+    let Ok(metadata) = tokio::fs::metadata(&source.file).await else {
+        return Ok(HttpResponse::not_found());
+    };
+    if source.is_root()
+        || destination.is_root()
+        || destination.file.starts_with(&source.file)
+        || (remove_source && source.file.starts_with(&destination.file))
+    {
+        return Ok(HttpResponse::from_status_code(StatusCode::FORBIDDEN));
+    }
+    let replaced = tokio::fs::symlink_metadata(&destination.file).await.is_ok();
+    if replaced && request.headers.get(OVERWRITE).is_some_and(|overwrite| overwrite.trim() == "F") {
+        return Ok(HttpResponse::precondition_failed());
+    }
+    if !has_parent_dir(&destination.file).await {
+        return Ok(HttpResponse::conflict());
+    }
+    let locks = lock_table(&request)?;
+    let submitted = submitted_tokens(&request);
+    if !locks.permits(&destination.file, true, &submitted)
+        || (remove_source && !locks.permits(&source.file, true, &submitted))
+    {
+        return Ok(HttpResponse::locked());
+    }
+    let (from, to) = (source.file.clone(), destination.file.clone());
+    spawn_blocking(move || -> io::Result<()> {
+        if replaced {
+            remove(&to)?;
+        }
+        match remove_source {
+            true => fs::rename(&from, &to).and_then(|()| mime::rename_content_type(&from, &to)),
+            false => copy(&from, &to, metadata.is_dir() && recursive),
+        }
+    })
+    .await
+    .wrap_err("Copy task failed")?
+    .wrap_err_with(|| format!("Failed to copy {}", source.file.display()))?;
+    locks.release(&destination.file);
+    if remove_source {
+        locks.release(&source.file);
+    }
+    Ok(match replaced {
+        true => HttpResponse::no_content(),
+        false => HttpResponse::created(),
+    })
+}
+
+/// Takes or refreshes an exclusive write lock.
+///
+/// A request with a `lockinfo` body takes a new lock, creating an empty file if nothing exists
+/// at the path yet; one without a body refreshes the lock whose token it submits in `If`.
+/// Shared locks are not supported and are refused with 412 Precondition Failed.
+async fn lock(request: HttpRequest) -> Result<HttpResponse> {
+    let target = Target::of(&request).await?;
+    let State(Mount(mount)) = State::from_request(&request)?;
+    let locks = lock_table(&request)?;
+    let timeout = lock_timeout(request.headers.get(TIMEOUT).map(String::as_str));
+    if request.body.is_empty() {
+        let submitted = submitted_tokens(&request);
+        let mut active = locks.active();
+        let Some(lock) = active
+            .iter_mut()
+            .find(|lock| lock.covers(&target.file) && submitted.contains(&lock.token))
+        else {
+            return Ok(HttpResponse::precondition_failed());
+        };
+        lock.timeout = timeout;
+        lock.expires = Instant::now() + timeout;
+        return Ok(lock_discovery(StatusCode::OK, lock));
+    }
+    let body = String::from_utf8_lossy(&request.body);
+    let Some((shared, owner)) = parse_lockinfo(&body) else {
+        return Ok(HttpResponse::bad_request());
+    };
+    if shared {
+        return Ok(HttpResponse::precondition_failed());
+    }
+    let recursive = match request.headers.get(DEPTH).map(|depth| depth.trim()) {
+        Some("infinity") | None => true,
+        Some("0") => false,
+        _ => return Ok(HttpResponse::bad_request()),
+    };
+    let metadata = tokio::fs::metadata(&target.file).await.ok();
+    if metadata.is_none() && !has_parent_dir(&target.file).await {
+        return Ok(HttpResponse::conflict());
+    }
+    let is_dir = metadata.as_ref().is_some_and(|metadata| metadata.is_dir());
+    let lock = Lock {
+        token: lock_token(),
+        path: target.file.clone(),
+        root: href(&mount, Path::new(&target.path), is_dir),
+        recursive,
+        owner,
+        timeout,
+        expires: Instant::now() + timeout,
+    };
+    {
+        let mut active = locks.active();
+        if active.iter().any(|held| held.covers(&lock.path) || lock.covers(&held.path)) {
+            return Ok(HttpResponse::locked());
+        }
+        active.push(lock.clone());
+    }
+    let status_code = match metadata {
+        Some(_) => StatusCode::OK,
+        None => {
+            if let Err(e) = tokio::fs::write(&target.file, b"").await {
+                locks.release(&target.file);
+                return Err(e)
+                    .wrap_err_with(|| format!("Failed to create {}", target.file.display()));
+            }
+            StatusCode::CREATED
+        },
+    };
+    let mut response = lock_discovery(status_code, &lock);
+    response.headers.insert(LOCK_TOKEN.to_string(), format!("<{}>", lock.token));
+    Ok(response)
+}
+
+/// Releases the lock named by the `Lock-Token` header.
+async fn unlock(request: HttpRequest) -> Result<HttpResponse> {
+    let target = Target::of(&request).await?;
+    let Some(token) = request.headers.get(LOCK_TOKEN) else {
+        return Ok(HttpResponse::bad_request());
+    };
+    let token = token.trim().trim_start_matches('<').trim_end_matches('>');
+    let mut active = lock_table(&request)?.active();
+    let Some(index) =
+        active.iter().position(|lock| lock.token == token && lock.covers(&target.file))
+    else {
+        return Ok(HttpResponse::conflict());
+    };
+    active.remove(index);
+    Ok(HttpResponse::no_content())
+}
+
+/// Checks whether the [`LockTable`] registered as state lets a request write a resource, e.g.
+/// because the request submits the token of the lock on it, or there is no `LockTable`.
+///
+/// # Arguments
+///
+/// * `request` - The HTTP request.
+/// * `path` - The resolved path of the resource.
+/// * `members` - Whether locks on members of the resource count as well.
+pub(crate) fn write_permitted(request: &HttpRequest, path: &Path, members: bool) -> bool {
+    request
+        .state
+        .get::<LockTable>()
+        .is_none_or(|locks| locks.permits(path, members, &submitted_tokens(request)))
+}
+
+/// Returns the [`LockTable`] registered as state.
+fn lock_table(request: &HttpRequest) -> Result<&LockTable> {
+    request
+        .state
+        .get::<LockTable>()
+        .ok_or_else(|| ServerError::RouterError(RouterError::MissingState("LockTable")).into())
+}
+
+/// Returns the lock tokens a request submits in its `If` header.
+///
+/// Every `<...>` in the header is taken, which may include resource tags; those match no lock.
+fn submitted_tokens(request: &HttpRequest) -> Vec<String> {
+    let Some(header) = request.headers.get(IF) else {
+        return Vec::new();
+    };
+    header
+        .split('<')
+        .skip(1)
+        .filter_map(|part| part.split_once('>'))
+        .map(|(token, _)| token.to_string())
+        .collect()
+}
+
+/// Parses a `Timeout` header, e.g. `Second-600, Infinite`, capped at [`MAX_LOCK_TIMEOUT`].
+fn lock_timeout(header: Option<&str>) -> Duration {
+    header
+        .and_then(|header| {
+            header.split(',').find_map(|value| value.trim().strip_prefix("Second-")?.parse().ok())
+        })
+        .map_or(MAX_LOCK_TIMEOUT, |secs| Duration::from_secs(secs).min(MAX_LOCK_TIMEOUT))
+}
+
+/// Parses a `lockinfo` body.
+///
+/// # Returns
+///
+/// Whether a shared lock is asked for and the text of the owner, or `None` if the body is not
+/// a `lockinfo` element.
+fn parse_lockinfo(body: &str) -> Option<(bool, Option<String>)> {
+    if !LOCKINFO_ELEMENT.is_match(body) {
+        return None;
+    }
+    let shared = SHARED_ELEMENT.is_match(body);
+    let owner = OWNER_ELEMENT
+        .captures(body)
+        .map(|captures| TAG.replace_all(&captures[2], "").trim().to_string())
+        .filter(|owner| !owner.is_empty());
+    Some((shared, owner))
+}
+
+/// Creates a new lock token, an `opaquelocktoken` URI with a random UUID.
+fn lock_token() -> String {
+    let random = || RandomState::new().build_hasher().finish();
+    let (high, low) = (random(), random());
+    format!(
+        "opaquelocktoken:{:08x}-{:04x}-4{:03x}-{:04x}-{:012x}",
+        high >> 32,
+        (high >> 16) & 0xffff,
+        high & 0xfff,
+        (low >> 48) & 0x3fff | 0x8000,
+        low & 0xffff_ffff_ffff
+    )
+}
+
+/// Returns the path a `Destination` header names, relative to the mount and decoded.
+///
+/// # Returns
+///
+/// The path, or `None` if the destination is outside the mount.
+fn destination_path(destination: &str, mount: &str) -> Option<String> {
+    let path = match destination.trim().split_once("://") {
+        Some((_, rest)) => &rest[rest.find('/')?..],
+        None => destination.trim(),
+    };
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    let relative = path.strip_prefix(mount)?;
+    (relative.is_empty() || relative.starts_with('/'))
+        .then(|| percent_decode(relative.trim_start_matches('/'), false))
+}
+
+/// Returns the `href` of a resource: the mount followed by the encoded path, with a trailing
+/// slash for collections.
+fn href(mount: &str, path: &Path, is_dir: bool) -> String {
+    let mut href = mount.to_string();
+    for component in path.components() {
+        if let Component::Normal(part) = component {
+            href.push('/');
+            href.push_str(&encode_segment(&part.to_string_lossy()));
+        }
+    }
+    if is_dir || href.is_empty() {
+        href.push('/');
+    }
+    href
+}
+
+/// Returns a `response` element with the properties of a resource.
+async fn properties(
+    request: &HttpRequest,
+    target: &Target,
+    mount: &str,
+    metadata: &Metadata,
+) -> Result<String> {
+    let path = Path::new(&target.path);
+    let name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    let mut props = format!("<D:displayname>{}</D:displayname>", escape_html(&name));
+    if let Ok(modified) = metadata.modified() {
+        props.push_str(&format!(
+            "<D:getlastmodified>{}</D:getlastmodified>",
+            httpdate::fmt_http_date(modified)
+        ));
+    }
+    if metadata.is_dir() {
+        props.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+    } else {
+        props.push_str("<D:resourcetype/>");
+        props.push_str(&format!("<D:getcontentlength>{}</D:getcontentlength>", metadata.len()));
+        if let Some((validators, ..)) = file_validators(request, &target.file).await {
+            let etag = escape_html(&validators.etag.to_string());
+            props.push_str(&format!("<D:getetag>{}</D:getetag>", etag));
+        }
+        let content_type = file_content_type(request, &target.dir, &target.file, None).await?;
+        props.push_str(&format!(
+            "<D:getcontenttype>{}</D:getcontenttype>",
+            escape_html(&content_type)
+        ));
+    }
+    props.push_str(
+        "<D:supportedlock><D:lockentry><D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:\
+         write/></D:locktype></D:lockentry></D:supportedlock>",
+    );
+    let locks = lock_table(request)?.active();
+    props.push_str("<D:lockdiscovery>");
+    for lock in locks.iter().filter(|lock| lock.covers(&target.file)) {
+        props.push_str(&active_lock(lock));
+    }
+    props.push_str("</D:lockdiscovery>");
+    Ok(format!(
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 \
+         {}</D:status></D:propstat></D:response>\n",
+        href(mount, path, metadata.is_dir()),
+        props,
+        StatusCode::OK.as_str()
+    ))
+}
+
+/// Returns the `activelock` element describing a lock.
+fn active_lock(lock: &Lock) -> String {
+    let owner = lock
+        .owner
+        .as_ref()
+        .map(|owner| format!("<D:owner>{}</D:owner>", escape_html(owner)))
+        .unwrap_or_default();
+    format!(
+        "<D:activelock><D:locktype><D:write/></D:locktype><D:lockscope><D:exclusive/></D:\
+         lockscope><D:depth>{}</D:depth>{}<D:timeout>Second-{}</D:timeout><D:locktoken><D:\
+         href>{}</D:href></D:locktoken><D:lockroot><D:href>{}</D:href></D:lockroot></D:activelock>",
+        match lock.recursive {
+            true => "infinity",
+            false => "0",
+        },
+        owner,
+        lock.timeout.as_secs(),
+        lock.token,
+        lock.root
+    )
+}
+
+/// Creates the response to a `LOCK`, a `prop` element with the lock's `lockdiscovery`.
+fn lock_discovery(status_code: StatusCode, lock: &Lock) -> HttpResponse {
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:prop \
+         xmlns:D=\"DAV:\"><D:lockdiscovery>{}</D:lockdiscovery></D:prop>\n",
+        active_lock(lock)
+    );
+    xml_response(status_code, &body)
+}
+
+/// Creates a 207 Multi-Status response from `response` elements.
+fn multistatus(responses: &[String]) -> HttpResponse {
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus \
+         xmlns:D=\"DAV:\">\n{}</D:multistatus>\n",
+        responses.concat()
+    );
+    xml_response(StatusCode::MULTI_STATUS, &body)
+}
+
+/// Creates a response with an XML body.
+fn xml_response(status_code: StatusCode, body: &str) -> HttpResponse {
+    let mut headers = ResponseHeaders::new();
+    headers.insert(CONTENT_TYPE.to_string(), format!("{}; charset=utf-8", CT_APPLICATION_XML));
+    HttpResponse::new(status_code, body.as_bytes(), headers)
+}
+
+/// Checks whether the parent of a path is an existing directory.
+async fn has_parent_dir(path: &Path) -> bool {
+    match path.parent() {
+        Some(parent) => tokio::fs::metadata(parent).await.is_ok_and(|metadata| metadata.is_dir()),
+        None => false,
+    }
+}
+
+/// Removes a file with its stored content type, or a directory with everything in it.
+fn remove(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path)?.is_dir() {
+        true => fs::remove_dir_all(path),
+        false => {
+            fs::remove_file(path)?;
+            mime::remove_content_type(path)
+        },
+    }
+}
+
+/// Copies a file with its stored content type, or a directory with or without its members.
+fn copy(from: &Path, to: &Path, recursive: bool) -> io::Result<()> {
+    if !fs::metadata(from)?.is_dir() {
+        fs::copy(from, to)?;
+        match mime::stored_content_type(from) {
+            Some(content_type) => mime::store_content_type(to, &content_type),
+            None => mime::remove_content_type(to)?,
+        }
+        return Ok(());
+    }
+    fs::create_dir(to)?;
+    if recursive {
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_symlink() {
+                copy(&entry.path(), &to.join(entry.file_name()), true)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::{
+        http::{ETAG, METHOD_POST},
+        router::make_router,
+    };
+
+    /// Creates `public/` with a file and a collection holding another file.
+    fn fixture() -> (TempDir, PathBuf, Router) {
+        let base = TempDir::new("webdav").unwrap();
+        let public = base.path().join("public");
+        fs::create_dir_all(public.join("docs")).unwrap();
+        fs::write(public.join("a.txt"), b"alpha").unwrap();
+        fs::write(public.join("docs/b.txt"), b"beta").unwrap();
+        let mut router = make_router(public.to_str().unwrap()).unwrap();
+        router.state(WebDav);
+        (base, public, router)
+    }
+
+    async fn dav(
+        router: &Router,
+        method: &str,
+        target: &str,
+        headers: &[(&str, &str)],
+        body: &str,
+    ) -> HttpResponse {
+        let mut raw = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n", method, target);
+        for (key, value) in headers {
+            raw.push_str(&format!("{}: {}\r\n", key, value));
+        }
+        if !body.is_empty() {
+            raw.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        raw.push_str("\r\n");
+        raw.push_str(body);
+        router.resolve(HttpRequest::from_string(&raw).unwrap()).await.unwrap()
+    }
+
+    fn body(response: &HttpResponse) -> String { String::from_utf8_lossy(&response.body).into() }
+
+    const LOCKINFO: &str = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<lockinfo \
+                            xmlns='DAV:'>\n<lockscope><exclusive/></lockscope>\n<locktype><write/>\
+                            </locktype><owner><href>http://example.org/~designer</href></owner>\n\
+                            </lockinfo>\n";
+
+    #[test]
+    fn destinations() {
+        let path = |destination| destination_path(destination, "/dav");
+        assert_eq!(path("http://localhost:4221/dav/b%20c.txt"), Some("b c.txt".to_string()));
+        assert_eq!(path("/dav/docs/"), Some("docs/".to_string()));
+        assert_eq!(path("https://host/dav"), Some(String::new()));
+        assert_eq!(path("http://localhost:4221/files/a.txt"), None);
+        assert_eq!(path("/davx/a.txt"), None);
+        assert_eq!(path("http://localhost:4221"), None);
+    }
+
+    #[test]
+    fn headers() {
+        assert_eq!(lock_timeout(Some("Second-600")), Duration::from_secs(600));
+        assert_eq!(lock_timeout(Some("Infinite, Second-4100000000")), MAX_LOCK_TIMEOUT);
+        assert_eq!(lock_timeout(None), MAX_LOCK_TIMEOUT);
+        assert_eq!(
+            parse_lockinfo(LOCKINFO),
+            Some((false, Some("http://example.org/~designer".to_string())))
+        );
+        let shared =
+            "<D:lockinfo xmlns:D=\"DAV:\"><D:lockscope><D:shared/></D:lockscope></D:lockinfo>";
+        assert_eq!(parse_lockinfo(shared), Some((true, None)));
+        assert_eq!(parse_lockinfo("<propfind/>"), None);
+        assert_eq!(href("/dav", Path::new("docs/a b"), true), "/dav/docs/a%20b/");
+        assert_eq!(href("", Path::new(""), true), "/");
+    }
+
+    #[tokio::test]
+    async fn disabled_without_state() {
+        let (_base, public, _) = fixture();
+        let router = make_router(public.to_str().unwrap()).unwrap();
+        let response = dav(&router, METHOD_PROPFIND, "/dav/", &[(DEPTH, "0")], "").await;
+        assert_eq!(response.status_code, StatusCode::NOT_FOUND);
+        let response = dav(&router, METHOD_DELETE, "/dav/a.txt", &[], "").await;
+        assert_eq!(response.status_code, StatusCode::NOT_FOUND);
+        assert!(public.join("a.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn propfind() {
+        let (_base, _public, router) = fixture();
+        let response = dav(&router, METHOD_OPTIONS, "/dav/", &[], "").await;
+        assert_eq!(response.headers[DAV], "1, 2");
+        assert!(response.headers[ALLOW].contains(METHOD_PROPFIND));
+
+        let response = dav(&router, METHOD_PROPFIND, "/dav/", &[(DEPTH, "1")], "").await;
+        assert_eq!(response.status_code, StatusCode::MULTI_STATUS);
+        assert_eq!(response.headers[CONTENT_TYPE], "application/xml; charset=utf-8");
+        let xml = body(&response);
+        assert_eq!(xml.matches("<D:response>").count(), 3, "{}", xml);
+        assert!(xml.contains("<D:href>/dav/</D:href>"));
+        assert!(xml.contains("<D:href>/dav/docs/</D:href>"));
+        assert!(xml.contains(
+            "<D:href>/dav/a.txt</D:href><D:propstat><D:prop><D:displayname>a.txt</D:displayname>"
+        ));
+        assert!(xml.contains("<D:getcontentlength>5</D:getcontentlength>"));
+        assert!(xml.contains("<D:getcontenttype>text/plain; charset=utf-8</D:getcontenttype>"));
+        assert!(xml.contains("<D:resourcetype><D:collection/></D:resourcetype>"));
+        assert!(xml.contains("<D:status>HTTP/1.1 200 OK</D:status>"));
+
+        let etag = dav(&router, METHOD_GET, "/dav/a.txt", &[], "").await.headers[ETAG].clone();
+        let response = dav(&router, METHOD_PROPFIND, "/dav/a.txt", &[(DEPTH, "0")], "").await;
+        let xml = body(&response);
+        assert_eq!(xml.matches("<D:response>").count(), 1);
+        assert!(xml.contains(&format!("<D:getetag>{}</D:getetag>", escape_html(&etag))));
+
+        let status = |response: HttpResponse| response.status_code;
+        let infinity = dav(&router, METHOD_PROPFIND, "/dav/", &[(DEPTH, "infinity")], "").await;
+        assert_eq!(status(infinity), StatusCode::FORBIDDEN);
+        let missing = dav(&router, METHOD_PROPFIND, "/dav/nope", &[(DEPTH, "0")], "").await;
+        assert_eq!(status(missing), StatusCode::NOT_FOUND);
+        let escape = dav(&router, METHOD_PROPFIND, "/dav/..%2f", &[(DEPTH, "1")], "").await;
+        assert_eq!(status(escape), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn collections_copy_and_move() {
+        let (_base, public, router) = fixture();
+        let status = |response: HttpResponse| response.status_code;
+        assert_eq!(
+            status(dav(&router, METHOD_MKCOL, "/dav/new", &[], "").await),
+            StatusCode::CREATED
+        );
+        assert!(public.join("new").is_dir());
+        let again = dav(&router, METHOD_MKCOL, "/dav/new", &[], "").await;
+        assert_eq!(status(again), StatusCode::NOT_ALLOWED);
+        let orphan = dav(&router, METHOD_MKCOL, "/dav/x/y", &[], "").await;
+        assert_eq!(status(orphan), StatusCode::CONFLICT);
+        let with_body = dav(&router, METHOD_MKCOL, "/dav/z", &[], "<x/>").await;
+        assert_eq!(status(with_body), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let to = |path: &str| format!("http://localhost:4221/dav/{}", path);
+        let copy = dav(&router, METHOD_COPY, "/dav/docs", &[(DESTINATION, &to("copy"))], "").await;
+        assert_eq!(status(copy), StatusCode::CREATED);
+        assert_eq!(fs::read(public.join("copy/b.txt")).unwrap(), b"beta");
+        let shallow = [(DESTINATION, to("shallow")), (DEPTH, "0".to_string())];
+        let shallow = shallow.iter().map(|(k, v)| (*k, v.as_str())).collect::<Vec<_>>();
+        assert_eq!(
+            status(dav(&router, METHOD_COPY, "/dav/docs", &shallow, "").await),
+            StatusCode::CREATED
+        );
+        assert_eq!(fs::read_dir(public.join("shallow")).unwrap().count(), 0);
+
+        let keep = [(DESTINATION, "/dav/docs/b.txt"), (OVERWRITE, "F")];
+        let refused = dav(&router, METHOD_COPY, "/dav/a.txt", &keep, "").await;
+        assert_eq!(status(refused), StatusCode::PRECONDITION_FAILED);
+        let replace =
+            dav(&router, METHOD_COPY, "/dav/a.txt", &[(DESTINATION, "/dav/docs/b.txt")], "").await;
+        assert_eq!(status(replace), StatusCode::NO_CONTENT);
+        assert_eq!(fs::read(public.join("docs/b.txt")).unwrap(), b"alpha");
+
+        let moved =
+            dav(&router, METHOD_MOVE, "/dav/copy", &[(DESTINATION, &to("new/moved"))], "").await;
+        assert_eq!(status(moved), StatusCode::CREATED);
+        assert!(!public.join("copy").exists());
+        assert_eq!(fs::read(public.join("new/moved/b.txt")).unwrap(), b"beta");
+
+        let into_itself =
+            dav(&router, METHOD_COPY, "/dav/new", &[(DESTINATION, &to("new/in"))], "").await;
+        assert_eq!(status(into_itself), StatusCode::FORBIDDEN);
+        let elsewhere = [(DESTINATION, "http://localhost:4221/files/a.txt")];
+        let elsewhere = dav(&router, METHOD_MOVE, "/dav/a.txt", &elsewhere, "").await;
+        assert_eq!(status(elsewhere), StatusCode::BAD_GATEWAY);
+        let no_parent =
+            dav(&router, METHOD_MOVE, "/dav/a.txt", &[(DESTINATION, &to("x/a.txt"))], "").await;
+        assert_eq!(status(no_parent), StatusCode::CONFLICT);
+        // The sidecar files holding content types are out of reach, as under /files.
+        let sidecar = dav(&router, METHOD_PUT, "/dav/.a.txt.content-type", &[], "text/html").await;
+        assert_eq!(status(sidecar), StatusCode::FORBIDDEN);
+        let sidecar = [(DESTINATION, "/dav/docs/.b.txt.content-type")];
+        let sidecar = dav(&router, METHOD_COPY, "/dav/a.txt", &sidecar, "").await;
+        assert_eq!(status(sidecar), StatusCode::FORBIDDEN);
+        assert!(!public.join("docs/.b.txt.content-type").exists());
+
+        assert_eq!(
+            status(dav(&router, METHOD_DELETE, "/dav/new", &[], "").await),
+            StatusCode::NO_CONTENT
+        );
+        assert!(!public.join("new").exists());
+        assert_eq!(
+            status(dav(&router, METHOD_DELETE, "/dav/", &[], "").await),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(dav(&router, METHOD_DELETE, "/dav/new", &[], "").await),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    /// Replays what `cadaver` sends for `lock`, `put`, `mv` and `unlock` on a file.
+    #[tokio::test]
+    async fn locking_session() {
+        let (_base, public, router) = fixture();
+        let status = |response: HttpResponse| response.status_code;
+        let lock = dav(
+            &router,
+            METHOD_LOCK,
+            "/dav/a.txt",
+            &[(DEPTH, "0"), (TIMEOUT, "Second-600")],
+            LOCKINFO,
+        )
+        .await;
+        assert_eq!(lock.status_code, StatusCode::OK);
+        let token = lock.headers[LOCK_TOKEN].trim_matches(['<', '>']).to_string();
+        assert!(token.starts_with("opaquelocktoken:"));
+        let xml = body(&lock);
+        assert!(xml.contains("<D:owner>http://example.org/~designer</D:owner>"));
+        assert!(xml.contains("<D:timeout>Second-600</D:timeout>"));
+        assert!(xml.contains(&format!("<D:locktoken><D:href>{}</D:href>", token)));
+
+        let again = dav(&router, METHOD_LOCK, "/dav/a.txt", &[], LOCKINFO).await;
+        assert_eq!(status(again), StatusCode::LOCKED);
+        let parent = dav(&router, METHOD_LOCK, "/dav/", &[], LOCKINFO).await;
+        assert_eq!(status(parent), StatusCode::LOCKED);
+        assert_eq!(
+            status(dav(&router, METHOD_PUT, "/dav/a.txt", &[], "x").await),
+            StatusCode::LOCKED
+        );
+        assert_eq!(
+            status(dav(&router, METHOD_DELETE, "/dav/a.txt", &[], "").await),
+            StatusCode::LOCKED
+        );
+        let moved =
+            dav(&router, METHOD_MOVE, "/dav/a.txt", &[(DESTINATION, "/dav/c.txt")], "").await;
+        assert_eq!(status(moved), StatusCode::LOCKED);
+        // Writes under /files honour the locks as well.
+        let put = dav(&router, METHOD_PUT, "/files/a.txt", &[], "x").await;
+        assert_eq!(status(put), StatusCode::LOCKED);
+        let post = dav(&router, METHOD_POST, "/files/a.txt", &[], "x").await;
+        assert_eq!(status(post), StatusCode::LOCKED);
+        let delete = dav(&router, METHOD_DELETE, "/files/a.txt", &[], "").await;
+        assert_eq!(status(delete), StatusCode::LOCKED);
+        assert_eq!(fs::read(public.join("a.txt")).unwrap(), b"alpha");
+
+        let submitted = format!("(<{}>)", token);
+        let put = dav(&router, METHOD_PUT, "/files/a.txt", &[(IF, &submitted)], "beta").await;
+        assert_eq!(status(put), StatusCode::NO_CONTENT);
+        let put = dav(&router, METHOD_PUT, "/dav/a.txt", &[(IF, &submitted)], "gamma").await;
+        assert_eq!(status(put), StatusCode::NO_CONTENT);
+        assert_eq!(fs::read(public.join("a.txt")).unwrap(), b"gamma");
+        let refresh = dav(&router, METHOD_LOCK, "/dav/a.txt", &[(IF, &submitted)], "").await;
+        assert_eq!(refresh.status_code, StatusCode::OK);
+        assert!(body(&refresh).contains("<D:timeout>Second-3600</D:timeout>"));
+        let propfind = dav(&router, METHOD_PROPFIND, "/dav/a.txt", &[(DEPTH, "0")], "").await;
+        assert!(body(&propfind).contains(&token));
+
+        let wrong =
+            dav(&router, METHOD_UNLOCK, "/dav/a.txt", &[(LOCK_TOKEN, "<opaquelocktoken:x>")], "")
+                .await;
+        assert_eq!(status(wrong), StatusCode::CONFLICT);
+        let unlock =
+            dav(&router, METHOD_UNLOCK, "/dav/a.txt", &[(LOCK_TOKEN, &format!("<{}>", token))], "")
+                .await;
+        assert_eq!(status(unlock), StatusCode::NO_CONTENT);
+        let moved =
+            dav(&router, METHOD_MOVE, "/dav/a.txt", &[(DESTINATION, "/dav/c.txt")], "").await;
+        assert_eq!(status(moved), StatusCode::CREATED);
+        assert_eq!(fs::read(public.join("c.txt")).unwrap(), b"gamma");
+
+        let create = dav(&router, METHOD_LOCK, "/dav/docs/new.txt", &[], LOCKINFO).await;
+        assert_eq!(create.status_code, StatusCode::CREATED);
+        assert_eq!(fs::read(public.join("docs/new.txt")).unwrap(), b"");
+        let collection = dav(&router, METHOD_PUT, "/dav/docs", &[], "x").await;
+        assert_eq!(status(collection), StatusCode::NOT_ALLOWED);
+        let deleted = dav(&router, METHOD_DELETE, "/dav/docs", &[], "").await;
+        assert_eq!(status(deleted), StatusCode::LOCKED);
+    }
+}